@@ -1,9 +1,21 @@
 //! Wrapper for Redis cache connections.
 //!
+use errors::GnapError;
 use redis::aio::ConnectionManager;
 use redis::Client;
 use std::env;
 
+/// Cache path for key proofs that have been seen.
+const PROOFS_PATH: &str = "gnap:proofs";
+/// Cache path for the user code index.
+const USER_CODES_PATH: &str = "gnap:user_codes";
+/// Cache path for app interaction handles that have been used.
+const APP_HANDLES_PATH: &str = "gnap:app_handles";
+/// Cache path for locks shared by AS instances.
+const LOCKS_PATH: &str = "gnap:locks";
+/// Cache path for counted attempts, such as sign ins.
+const ATTEMPTS_PATH: &str = "gnap:attempts";
+
 #[derive(Clone)]
 pub struct GnapCache {
     pub client: Client,
@@ -26,6 +38,147 @@ impl GnapCache {
             connection_manager,
         }
     }
-}
 
+    /// Record a verified key proof, by its signature identifier and its
+    /// nonce, for `ttl` seconds.  The creation time is kept as the value.
+    /// Nonces are chosen by the signer, so they are only unique per key, and
+    /// are recorded under the `key_id` of the key that made the proof.
+    ///
+    /// Returns false if the signature or the nonce was already recorded,
+    /// meaning the proof is a replay.  Both are checked and recorded in one
+    /// script, so a replay leaves nothing recorded.
+    pub async fn record_proof(
+        &self,
+        key_id: &str,
+        signature_id: &str,
+        nonce: Option<&str>,
+        created: u64,
+        ttl: u64,
+    ) -> Result<bool, GnapError> {
+        let mut con = self.client.get_async_connection().await?;
+        let mut keys = vec![format!("{}:signatures:{}", PROOFS_PATH, signature_id)];
+        if let Some(nonce) = nonce {
+            keys.push(format!("{}:nonces:{}:{}", PROOFS_PATH, key_id, nonce));
+        }
+        let fresh: u32 = redis::Script::new(
+            r#"for _, key in ipairs(KEYS) do
+                if redis.call("EXISTS", key) == 1 then return 0 end
+            end
+            for _, key in ipairs(KEYS) do
+                redis.call("SET", key, ARGV[1], "EX", ARGV[2])
+            end
+            return 1"#,
+        )
+        .key(keys)
+        .arg(created)
+        .arg(ttl)
+        .invoke_async(&mut con)
+        .await?;
+        Ok(fresh == 1)
+    }
+
+    /// Count an attempt by `subject` at something limited, such as signing
+    /// in.  The count is kept for `window` seconds from the first attempt.
+    ///
+    /// The count and its expiry are set in one script, so a count can never
+    /// be left without an expiry.  Returns the count including this attempt.
+    pub async fn count_attempt(&self, scope: &str, subject: &str, window: u64) -> Result<u64, GnapError> {
+        let mut con = self.client.get_async_connection().await?;
+        let attempts: u64 = redis::Script::new(
+            r#"local attempts = redis.call("INCR", KEYS[1])
+            if attempts == 1 then redis.call("EXPIRE", KEYS[1], ARGV[1]) end
+            return attempts"#,
+        )
+        .key(format!("{}:{}:{}", ATTEMPTS_PATH, scope, subject))
+        .arg(window)
+        .invoke_async(&mut con)
+        .await?;
+        Ok(attempts)
+    }
+
+    /// Forget the attempts counted for `subject`.
+    pub async fn clear_attempts(&self, scope: &str, subject: &str) -> Result<(), GnapError> {
+        let mut con = self.client.get_async_connection().await?;
+        let _: () = redis::cmd("DEL")
+            .arg(format!("{}:{}:{}", ATTEMPTS_PATH, scope, subject))
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    /// Index a transaction by a user code, for `ttl` seconds.
+    ///
+    /// Returns false if the code is already in use.
+    pub async fn add_user_code(&self, code: &str, tx_id: &str, ttl: u64) -> Result<bool, GnapError> {
+        let mut con = self.client.get_async_connection().await?;
+        let set: Option<String> = redis::cmd("SET")
+            .arg(format!("{}:{}", USER_CODES_PATH, code))
+            .arg(tx_id)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut con)
+            .await?;
+        Ok(set.is_some())
+    }
+
+    /// Look up the transaction for a user code.  Codes can only be used
+    /// once, so the code is removed.
+    pub async fn take_user_code(&self, code: &str) -> Result<Option<String>, GnapError> {
+        let mut con = self.client.get_async_connection().await?;
+        let key = format!("{}:{}", USER_CODES_PATH, code);
+        let (tx_id, _): (Option<String>, u32) = redis::pipe()
+            .atomic()
+            .get(&key)
+            .del(&key)
+            .query_async(&mut con)
+            .await?;
+        Ok(tx_id)
+    }
+
+    /// Record that an app interaction handle has been used, for `ttl`
+    /// seconds.
+    ///
+    /// Returns false if the handle was already used.
+    pub async fn use_app_handle(&self, handle_id: &str, ttl: u64) -> Result<bool, GnapError> {
+        let mut con = self.client.get_async_connection().await?;
+        let set: Option<String> = redis::cmd("SET")
+            .arg(format!("{}:{}", APP_HANDLES_PATH, handle_id))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl.max(1))
+            .query_async(&mut con)
+            .await?;
+        Ok(set.is_some())
+    }
 
+    /// Take a lock for `ttl` seconds on behalf of `holder`.
+    ///
+    /// Returns false if the lock is held by someone else.
+    pub async fn acquire_lock(&self, name: &str, holder: &str, ttl: u64) -> Result<bool, GnapError> {
+        let mut con = self.client.get_async_connection().await?;
+        let set: Option<String> = redis::cmd("SET")
+            .arg(format!("{}:{}", LOCKS_PATH, name))
+            .arg(holder)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut con)
+            .await?;
+        Ok(set.is_some())
+    }
+
+    /// Release a lock, unless it expired and was taken by someone else.
+    pub async fn release_lock(&self, name: &str, holder: &str) -> Result<(), GnapError> {
+        let mut con = self.client.get_async_connection().await?;
+        let _: u32 = redis::Script::new(
+            r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("DEL", KEYS[1]) else return 0 end"#,
+        )
+        .key(format!("{}:{}", LOCKS_PATH, name))
+        .arg(holder)
+        .invoke_async(&mut con)
+        .await?;
+        Ok(())
+    }
+}
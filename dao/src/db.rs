@@ -5,11 +5,19 @@ use futures::stream::TryStreamExt;
 use log::{debug, trace};
 use model::transaction::TransactionOptions;
 use model::{
-    account::{Account, AccountRequest},
+    account::Account,
     client::{GnapClient, GnapClientRequest},
     gnap::GnapOptions,
+    grant::ClientInstance,
+    key::{KeyAlgorithm, SigningKey},
+    resource::ResourceServer,
+};
+use mongodb::{
+    bson::doc,
+    error::{ErrorKind, WriteFailure},
+    options::ClientOptions,
+    Client, Database,
 };
-use mongodb::{bson::doc, options::ClientOptions, Client, Database};
 use std::env;
 use uuid::Uuid;
 
@@ -138,8 +146,59 @@ impl GnapDB {
     }
 
     pub async fn add_client(&self, request: GnapClientRequest) -> Result<GnapClient, GnapError> {
+        let mut client = GnapClient::new(request.redirect_uris, request.client_name);
+        client.key = request.key;
+        client.jwks = request.jwks;
+        client.jwks_uri = request.jwks_uri;
+        client.pre_authorized = request.pre_authorized;
+        self.insert_client(client).await
+    }
+
+    /// Save a client instance sent by value in a grant request.
+    ///
+    /// An instance that was saved before with the same key is not saved
+    /// again.  A redirect URI it has not used before is added to it.
+    pub async fn add_client_instance(
+        &self,
+        instance: ClientInstance,
+        redirect_uri: Option<&str>,
+    ) -> Result<GnapClient, GnapError> {
+        let existing = match instance.key.thumbprint() {
+            Some(thumbprint) => self.fetch_client_by_thumbprint(&thumbprint).await?,
+            None => None,
+        };
+        let mut client = match existing {
+            Some(client) => client,
+            None => return self.insert_client(GnapClient::from_instance(instance, redirect_uri)).await,
+        };
+        if let Some(uri) = redirect_uri.filter(|uri| !client.is_redirect_uri(uri)) {
+            self.database
+                .collection::<GnapClient>("clients")
+                .update_one(
+                    doc! {"client_id": client.client_id.to_string()},
+                    doc! {"$addToSet": {"redirect_uris": uri}},
+                    None,
+                )
+                .await
+                .map_err(GnapError::DatabaseError)?;
+            client.redirect_uris.push(uri.to_owned());
+            debug!("Added redirect URI to client: {}", &client.client_id);
+        }
+        Ok(client)
+    }
+
+    /// Fetch the client saved for a client instance key sent by value.
+    pub async fn fetch_client_by_thumbprint(&self, thumbprint: &str) -> Result<Option<GnapClient>, GnapError> {
+        trace!("Fetching client by key thumbprint: {}", thumbprint);
+        self.database
+            .collection::<GnapClient>("clients")
+            .find_one(doc! {"key_thumbprint": thumbprint}, None)
+            .await
+            .map_err(GnapError::DatabaseError)
+    }
+
+    async fn insert_client(&self, client: GnapClient) -> Result<GnapClient, GnapError> {
         let collection = self.database.collection::<GnapClient>("clients");
-        let client = GnapClient::new(request.redirect_uris, request.client_name);
         match collection.insert_one(client.clone(), None).await {
             Ok(_) => {
                 debug!("Added client: {:?}", &client);
@@ -179,13 +238,26 @@ impl GnapDB {
         }
     }
 
-    pub async fn add_account(&self, request: AccountRequest) -> Result<Account, GnapError> {
+    /// Fetch the account a resource owner signs in to.
+    pub async fn fetch_account_by_username(&self, username: &str) -> Result<Option<Account>, GnapError> {
+        trace!("Fetching account by username: {}", username);
+        self.database
+            .collection::<Account>("accounts")
+            .find_one(doc! {"preferred_username": username}, None)
+            .await
+            .map_err(GnapError::DatabaseError)
+    }
+
+    pub async fn add_account(&self, account: &Account) -> Result<(), GnapError> {
         let collection = self.database.collection::<Account>("accounts");
-        let account = Account::from(request);
-        match collection.insert_one(&account, None).await {
+        match collection.insert_one(account, None).await {
             Ok(_) => {
-                debug!("Added account: {:?}", &account);
-                Ok(account)
+                debug!("Added account: {}", account.account_id());
+                Ok(())
+            }
+            Err(err) if is_duplicate_key(&err) => {
+                debug!("Account already exists: {:?}", &err);
+                Err(GnapError::BadData)
             }
             Err(err) => {
                 debug!("Error saving account: {:?}", &err);
@@ -193,6 +265,90 @@ impl GnapDB {
             }
         }
     }
+
+    // Signing key methods
+    /// Fetch the active signing key for an algorithm.
+    ///
+    /// Keys saved without a state are active.
+    pub async fn fetch_signing_key(&self, alg: KeyAlgorithm) -> Result<Option<SigningKey>, GnapError> {
+        trace!("Fetching signing key for {}", alg);
+        self.database
+            .collection::<SigningKey>("signing_keys")
+            .find_one(
+                doc! {"alg": alg.to_string(), "state": {"$nin": ["retiring", "retired"]}},
+                None,
+            )
+            .await
+            .map_err(GnapError::DatabaseError)
+    }
+
+    /// Fetch every signing key that has not been retired.
+    pub async fn fetch_signing_keys(&self) -> Result<Vec<SigningKey>, GnapError> {
+        trace!("Fetching signing keys");
+        let cursor = self
+            .database
+            .collection::<SigningKey>("signing_keys")
+            .find(doc! {"state": {"$ne": "retired"}}, None)
+            .await
+            .map_err(GnapError::DatabaseError)?;
+        cursor.try_collect().await.map_err(GnapError::DatabaseError)
+    }
+
+    pub async fn update_signing_key(&self, key: &SigningKey) -> Result<(), GnapError> {
+        self.database
+            .collection::<SigningKey>("signing_keys")
+            .replace_one(doc! {"kid": &key.kid}, key, None)
+            .await
+            .map_err(GnapError::DatabaseError)?;
+        debug!("Updated signing key: {}", &key.kid);
+        Ok(())
+    }
+
+    pub async fn add_signing_key(&self, key: &SigningKey) -> Result<(), GnapError> {
+        let collection = self.database.collection::<SigningKey>("signing_keys");
+        match collection.insert_one(key, None).await {
+            Ok(_) => {
+                debug!("Added signing key: {}", &key.kid);
+                Ok(())
+            }
+            Err(err) => {
+                debug!("Error saving signing key: {:?}", &err);
+                Err(GnapError::DatabaseError(err))
+            }
+        }
+    }
+
+    // Resource server methods
+    pub async fn fetch_resource_server(&self, id: &Uuid) -> Result<Option<ResourceServer>, GnapError> {
+        trace!("Fetching resource server by ID: {}", id.to_string());
+        self.database
+            .collection::<ResourceServer>("resource_servers")
+            .find_one(doc! {"rs_id": &id.to_string()}, None)
+            .await
+            .map_err(GnapError::DatabaseError)
+    }
+
+    pub async fn add_resource_server(&self, rs: &ResourceServer) -> Result<(), GnapError> {
+        let collection = self.database.collection::<ResourceServer>("resource_servers");
+        match collection.insert_one(rs, None).await {
+            Ok(_) => {
+                debug!("Added resource server: {}", &rs.rs_id);
+                Ok(())
+            }
+            Err(err) => {
+                debug!("Error saving resource server: {:?}", &err);
+                Err(GnapError::DatabaseError(err))
+            }
+        }
+    }
+}
+
+/// Did a write fail because it broke a unique index?
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(
+        &*err.kind,
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
 }
 
 #[cfg(test)]
@@ -11,7 +11,10 @@ use model::{
     account::Account,
     client::{GnapClient, GnapClientRequest},
     gnap::GnapOptions,
-    grant::GrantRequest,
+    grant::{ClientInstance, GrantRequest},
+    key::{ClientKey, Jwks, KeyAlgorithm, KeyState, SigningKey},
+    resource::ResourceServer,
+    token::GnapAccessToken,
     transaction::{GnapTransaction, TransactionOptions},
     CachePath,
 };
@@ -111,6 +114,67 @@ impl Service {
     /// Dynamically create a client
     pub async fn add_client(&self, request: GnapClientRequest) -> Result<GnapClient, GnapError> {
         let client = self.db_client.add_client(request).await?;
+        self.cache_new_client(client).await
+    }
+
+    /// Get the cached key set fetched from a client's `jwks_uri`.
+    pub async fn get_client_jwks(&self, id: &Uuid) -> Result<Option<Jwks>, GnapError> {
+        trace!("Service - get_client_jwks");
+        let cache_key = format!("{}:{}:jwks", GnapClient::cache_path(), id);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        match con.get(&cache_key).await? {
+            Value::Nil => Ok(None),
+            Value::Data(val) => Ok(serde_json::from_slice(&val)?),
+            _ => {
+                debug!("Did not successfully get a cache response");
+                Err(GnapError::GeneralError)
+            }
+        }
+    }
+
+    /// Cache the key set fetched from a client's `jwks_uri`.
+    pub async fn set_client_jwks(&self, id: &Uuid, jwks: &Jwks) -> Result<(), GnapError> {
+        let cache_key = format!("{}:{}:jwks", GnapClient::cache_path(), id);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&cache_key, jwks)
+            .expire(&cache_key, 3600)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    /// Can a client's key set be fetched from its `jwks_uri` again?
+    ///
+    /// Returns true at most once every `interval` seconds per client.
+    pub async fn allow_jwks_refetch(&self, id: &Uuid, interval: u64) -> Result<bool, GnapError> {
+        let cache_key = format!("{}:{}:jwks:refetch", GnapClient::cache_path(), id);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let set: Option<String> = redis::cmd("SET")
+            .arg(&cache_key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(interval)
+            .query_async(&mut con)
+            .await?;
+        Ok(set.is_some())
+    }
+
+    /// Save a client instance sent by value, so that it can be referenced
+    /// by its `client_id` afterwards.  An instance sent again with the same
+    /// key keeps its `client_id`.
+    pub async fn add_client_instance(
+        &self,
+        instance: ClientInstance,
+        redirect_uri: Option<&str>,
+    ) -> Result<GnapClient, GnapError> {
+        let client = self.db_client.add_client_instance(instance, redirect_uri).await?;
+        self.cache_new_client(client).await
+    }
+
+    async fn cache_new_client(&self, client: GnapClient) -> Result<GnapClient, GnapError> {
         let mut con = self.cache_client.client.get_async_connection().await?;
         let cache_key = format!(
             "{}:{}",
@@ -198,16 +262,33 @@ impl Service {
         }
     }
 
+    /// Save a new account.
+    pub async fn add_account(&self, account: &Account) -> Result<(), GnapError> {
+        self.db_client.add_account(account).await
+    }
+
+    /// Fetch an account by the username the resource owner signs in with.
+    ///
+    /// Accounts are always read from the database here, so that a changed
+    /// password takes effect right away.
+    pub async fn get_account_by_username(&self, username: &str) -> Result<Option<Account>, GnapError> {
+        trace!("Service - get_account_by_username");
+        self.db_client.fetch_account_by_username(username).await
+    }
+
     /// Start a GNAP transaction.
     ///
     /// This is called from the grant request handler.  The request is cached
     /// with the transaction. Ownership of the request passes to the transaction.
+    /// The key is the client instance key the request was verified with.
     pub async fn start_transaction(
         &self,
         request: GrantRequest,
+        key: Option<ClientKey>,
     ) -> Result<GnapTransaction, GnapError> {
         let mut con = self.cache_client.client.get_async_connection().await?;
-        let tx = GnapTransaction::new(Some(request));
+        let mut tx = GnapTransaction::new(Some(request));
+        tx.key = key;
         let cache_key = format!("{}:{}", GnapTransaction::cache_path(), &tx.tx_id.clone());
         let _: () = redis::pipe()
             .atomic()
@@ -217,4 +298,422 @@ impl Service {
             .await?;
         Ok(tx)
     }
+
+    /// Fetch a cached GNAP transaction.
+    ///
+    /// Transactions only live in the cache, so an expired transaction is
+    /// simply not found.
+    pub async fn get_transaction(&self, tx_id: &str) -> Result<Option<GnapTransaction>, GnapError> {
+        trace!("Service - get_transaction");
+
+        let cache_key = format!("{}:{}", GnapTransaction::cache_path(), tx_id);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_response = con.get(&cache_key).await?;
+
+        match cache_response {
+            Value::Nil => {
+                trace!("Transaction not found: {}", tx_id);
+                Ok(None)
+            }
+            Value::Data(val) => {
+                trace!("Use cache to retrieve GnapTransaction");
+                Ok(Some(serde_json::from_slice(&val)?))
+            }
+            _ => {
+                debug!("Did not successfully get a cache response");
+                Err(GnapError::GeneralError)
+            }
+        }
+    }
+
+    /// Save the current state of a GNAP transaction.
+    ///
+    /// Each update extends the life of the transaction in the cache.
+    pub async fn update_transaction(&self, tx: &GnapTransaction) -> Result<(), GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", GnapTransaction::cache_path(), &tx.tx_id);
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&cache_key, tx)
+            .expire(&cache_key, 3600)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    /// Change a cached GNAP transaction in place.
+    ///
+    /// Unlike [Service::update_transaction], the change is applied to the
+    /// transaction as it is in the cache, and retried if the transaction is
+    /// saved by someone else in the meantime.  Use this for changes made
+    /// outside of the client instance's requests, which must not undo the
+    /// client instance's progress, and for changes that depend on the
+    /// transaction's current state.  If `modify` fails, the transaction is
+    /// left as it is and the error is returned.  Returns the changed
+    /// transaction, or `None` if it has expired.
+    pub async fn modify_transaction<F>(&self, tx_id: &str, mut modify: F) -> Result<Option<GnapTransaction>, GnapError>
+    where
+        F: FnMut(&mut GnapTransaction) -> Result<(), GnapError>,
+    {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", GnapTransaction::cache_path(), tx_id);
+        loop {
+            let _: () = redis::cmd("WATCH").arg(&cache_key).query_async(&mut con).await?;
+            let cache_response = con.get(&cache_key).await?;
+            let mut tx: GnapTransaction = match cache_response {
+                Value::Data(val) => serde_json::from_slice(&val)?,
+                _ => {
+                    let _: () = redis::cmd("UNWATCH").query_async(&mut con).await?;
+                    trace!("Transaction not found: {}", tx_id);
+                    return Ok(None);
+                }
+            };
+            if let Err(err) = modify(&mut tx) {
+                let _: () = redis::cmd("UNWATCH").query_async(&mut con).await?;
+                return Err(err);
+            }
+            // The transaction is not saved if it changed since WATCH.
+            let saved: Option<()> = redis::pipe()
+                .atomic()
+                .set(&cache_key, &tx)
+                .expire(&cache_key, 3600)
+                .query_async(&mut con)
+                .await?;
+            if saved.is_some() {
+                return Ok(Some(tx));
+            }
+            trace!("Transaction {} changed while it was modified, retrying", tx_id);
+        }
+    }
+
+    /// Index a transaction by its interaction ID.
+    ///
+    /// The resource owner's browser only knows the interaction ID, so the
+    /// interaction pages find the transaction through this index.  The index
+    /// expires with the transaction.
+    pub async fn add_interaction(&self, tx: &GnapTransaction) -> Result<(), GnapError> {
+        let interaction = match &tx.interaction {
+            Some(interaction) => interaction,
+            None => return Ok(()),
+        };
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:interactions:{}", GnapTransaction::cache_path(), &interaction.id);
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&cache_key, &tx.tx_id)
+            .expire(&cache_key, 3600)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    /// Fetch the transaction for an interaction ID.
+    pub async fn get_interaction(&self, interaction_id: &str) -> Result<Option<GnapTransaction>, GnapError> {
+        trace!("Service - get_interaction");
+        let cache_key = format!("{}:interactions:{}", GnapTransaction::cache_path(), interaction_id);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let tx_id: Option<String> = con.get(&cache_key).await?;
+        match tx_id {
+            Some(tx_id) => self.get_transaction(&tx_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Save an issued access token.
+    ///
+    /// Tokens are cached by ID, with an index from the token value to the
+    /// ID.  Both expire with the token.
+    pub async fn add_access_token(&self, token: &GnapAccessToken) -> Result<(), GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", GnapAccessToken::cache_path(), &token.token_id);
+        let value_key = format!("{}:values:{}", GnapAccessToken::cache_path(), &token.value);
+        let ttl = token.expires_in().max(1) as usize;
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&cache_key, token)
+            .expire(&cache_key, ttl)
+            .set(&value_key, &token.token_id)
+            .expire(&value_key, ttl)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    /// Save a rotated access token, but only if the token still has the
+    /// value `current`, so that a value can only be rotated once.  The
+    /// index entry of `revoked_value` is removed along with it.  Returns
+    /// false if the token was rotated or revoked meanwhile.
+    pub async fn rotate_access_token(
+        &self,
+        token: &GnapAccessToken,
+        current: &str,
+        revoked_value: Option<&str>,
+    ) -> Result<bool, GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", GnapAccessToken::cache_path(), &token.token_id);
+        let value_key = format!("{}:values:{}", GnapAccessToken::cache_path(), &token.value);
+        let _: () = redis::cmd("WATCH").arg(&cache_key).query_async(&mut con).await?;
+        let is_current = match con.get(&cache_key).await? {
+            Value::Data(val) => serde_json::from_slice::<GnapAccessToken>(&val)?.value == current,
+            _ => false,
+        };
+        if !is_current {
+            let _: () = redis::cmd("UNWATCH").query_async(&mut con).await?;
+            trace!("Access token {} changed before it was rotated", &token.token_id);
+            return Ok(false);
+        }
+        let ttl = token.expires_in().max(1) as usize;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .set(&cache_key, token)
+            .expire(&cache_key, ttl)
+            .set(&value_key, &token.token_id)
+            .expire(&value_key, ttl);
+        if let Some(value) = revoked_value {
+            pipe.del(format!("{}:values:{}", GnapAccessToken::cache_path(), value));
+        }
+        // Nothing is saved if the token changed since WATCH.
+        let saved: Option<()> = pipe.query_async(&mut con).await?;
+        Ok(saved.is_some())
+    }
+
+    /// Revoke an issued access token.  The record and the index entries
+    /// for the current and any retired values are removed, so the token can
+    /// no longer be looked up.
+    pub async fn revoke_access_token(&self, token: &GnapAccessToken) -> Result<(), GnapError> {
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", GnapAccessToken::cache_path(), &token.token_id);
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&cache_key);
+        for value in std::iter::once(&token.value).chain(token.retired_values.iter().map(|retired| &retired.value)) {
+            pipe.del(format!("{}:values:{}", GnapAccessToken::cache_path(), value));
+        }
+        let _: () = pipe.query_async(&mut con).await?;
+        Ok(())
+    }
+
+    pub async fn get_access_token(&self, token_id: &str) -> Result<Option<GnapAccessToken>, GnapError> {
+        trace!("Service - get_access_token");
+
+        let cache_key = format!("{}:{}", GnapAccessToken::cache_path(), token_id);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_response = con.get(&cache_key).await?;
+
+        match cache_response {
+            Value::Nil => {
+                trace!("Access token not found: {}", token_id);
+                Ok(None)
+            }
+            Value::Data(val) => {
+                trace!("Use cache to retrieve GnapAccessToken");
+                Ok(Some(serde_json::from_slice(&val)?))
+            }
+            _ => {
+                debug!("Did not successfully get a cache response");
+                Err(GnapError::GeneralError)
+            }
+        }
+    }
+
+    /// Look up an issued access token by the value the client presented.
+    pub async fn get_access_token_by_value(&self, value: &str) -> Result<Option<GnapAccessToken>, GnapError> {
+        trace!("Service - get_access_token_by_value");
+
+        let value_key = format!("{}:values:{}", GnapAccessToken::cache_path(), value);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let token_id: Option<String> = con.get(&value_key).await?;
+
+        match token_id {
+            Some(token_id) => self.get_access_token(&token_id).await,
+            None => {
+                trace!("No access token found for value");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Get the AS signing key for an algorithm.
+    pub async fn get_signing_key(&self, alg: KeyAlgorithm) -> Result<Option<SigningKey>, GnapError> {
+        trace!("Service - get_signing_key");
+
+        let cache_key = format!("{}:{}", SigningKey::cache_path(), alg);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_response = con.get(&cache_key).await?;
+
+        match cache_response {
+            Value::Nil => {
+                trace!("Use database to retrieve SigningKey");
+                let result = self.db_client.fetch_signing_key(alg).await?;
+                if let Some(data) = result {
+                    let _: () = redis::pipe()
+                        .atomic()
+                        .set(&cache_key, &data)
+                        .expire(&cache_key, 3600)
+                        .query_async(&mut con)
+                        .await?;
+                    Ok(Some(data))
+                } else {
+                    Ok(None)
+                }
+            }
+            Value::Data(val) => {
+                trace!("Use cache to retrieve SigningKey");
+                Ok(serde_json::from_slice(&val)?)
+            }
+            _ => {
+                debug!("Did not successfully get a cache response");
+                Err(GnapError::GeneralError)
+            }
+        }
+    }
+
+    /// Save a new AS signing key.
+    pub async fn add_signing_key(&self, key: &SigningKey) -> Result<(), GnapError> {
+        self.db_client.add_signing_key(key).await?;
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", SigningKey::cache_path(), key.alg);
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&cache_key, key)
+            .expire(&cache_key, 3600)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    /// Record a verified key proof made with the key `key_id` for `ttl`
+    /// seconds.  Returns false if the proof is a replay.
+    pub async fn record_proof(
+        &self,
+        key_id: &str,
+        signature_id: &str,
+        nonce: Option<&str>,
+        created: u64,
+        ttl: u64,
+    ) -> Result<bool, GnapError> {
+        self.cache_client.record_proof(key_id, signature_id, nonce, created, ttl).await
+    }
+
+    /// Count an attempt by `subject` at something limited, over a `window`
+    /// of seconds.  Returns the count including this attempt.
+    pub async fn count_attempt(&self, scope: &str, subject: &str, window: u64) -> Result<u64, GnapError> {
+        self.cache_client.count_attempt(scope, subject, window).await
+    }
+
+    /// Forget the attempts counted for `subject`.
+    pub async fn clear_attempts(&self, scope: &str, subject: &str) -> Result<(), GnapError> {
+        self.cache_client.clear_attempts(scope, subject).await
+    }
+
+    /// Index a transaction by a user code for `ttl` seconds.  Returns false
+    /// if the code is already in use.
+    pub async fn add_user_code(&self, code: &str, tx_id: &str, ttl: u64) -> Result<bool, GnapError> {
+        self.cache_client.add_user_code(code, tx_id, ttl).await
+    }
+
+    /// Fetch the pending transaction for a user code, using up the code.
+    pub async fn take_user_code(&self, code: &str) -> Result<Option<GnapTransaction>, GnapError> {
+        match self.cache_client.take_user_code(code).await? {
+            Some(tx_id) => self.get_transaction(&tx_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Record that an app interaction handle has been used, until it
+    /// expires in `ttl` seconds.  Returns false if it was already used.
+    pub async fn use_app_handle(&self, handle_id: &str, ttl: u64) -> Result<bool, GnapError> {
+        self.cache_client.use_app_handle(handle_id, ttl).await
+    }
+
+    /// Take a lock shared by AS instances for `ttl` seconds.  Returns false
+    /// if someone else holds it.
+    pub async fn acquire_lock(&self, name: &str, holder: &str, ttl: u64) -> Result<bool, GnapError> {
+        self.cache_client.acquire_lock(name, holder, ttl).await
+    }
+
+    /// Release a lock taken with [acquire_lock](Service::acquire_lock).
+    pub async fn release_lock(&self, name: &str, holder: &str) -> Result<(), GnapError> {
+        self.cache_client.release_lock(name, holder).await
+    }
+
+    /// Get every signing key that has not been retired.
+    pub async fn get_signing_keys(&self) -> Result<Vec<SigningKey>, GnapError> {
+        self.db_client.fetch_signing_keys().await
+    }
+
+    /// Save a change to a signing key.
+    ///
+    /// Keys that are no longer active are dropped from the cache, so the
+    /// next [get_signing_key](Service::get_signing_key) finds the new active
+    /// key.
+    pub async fn update_signing_key(&self, key: &SigningKey) -> Result<(), GnapError> {
+        self.db_client.update_signing_key(key).await?;
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", SigningKey::cache_path(), key.alg);
+        if key.state == KeyState::Active {
+            let _: () = redis::pipe()
+                .atomic()
+                .set(&cache_key, key)
+                .expire(&cache_key, 3600)
+                .query_async(&mut con)
+                .await?;
+        } else {
+            let cached: Option<SigningKey> = match con.get(&cache_key).await? {
+                Value::Data(val) => serde_json::from_slice(&val).ok(),
+                _ => None,
+            };
+            if cached.is_some_and(|cached| cached.kid == key.kid) {
+                let _: () = con.del(&cache_key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register a resource server.
+    pub async fn add_resource_server(&self, rs: &ResourceServer) -> Result<(), GnapError> {
+        self.db_client.add_resource_server(rs).await?;
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_key = format!("{}:{}", ResourceServer::cache_path(), rs.rs_id);
+        let _: () = redis::pipe()
+            .atomic()
+            .set(&cache_key, rs)
+            .expire(&cache_key, 3600)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_resource_server(&self, id: &Uuid) -> Result<Option<ResourceServer>, GnapError> {
+        trace!("Service - get_resource_server");
+
+        let cache_key = format!("{}:{}", ResourceServer::cache_path(), id);
+        let mut con = self.cache_client.client.get_async_connection().await?;
+        let cache_response = con.get(&cache_key).await?;
+
+        match cache_response {
+            Value::Nil => {
+                trace!("Use database to retrieve ResourceServer");
+                let result = self.db_client.fetch_resource_server(id).await?;
+                if let Some(data) = result {
+                    let _: () = redis::pipe()
+                        .atomic()
+                        .set(&cache_key, &data)
+                        .expire(&cache_key, 3600)
+                        .query_async(&mut con)
+                        .await?;
+                    Ok(Some(data))
+                } else {
+                    Ok(None)
+                }
+            }
+            Value::Data(val) => {
+                trace!("Use cache to retrieve ResourceServer");
+                Ok(serde_json::from_slice(&val)?)
+            }
+            _ => {
+                debug!("Did not successfully get a cache response");
+                Err(GnapError::GeneralError)
+            }
+        }
+    }
 }
@@ -1,5 +1,6 @@
 use thiserror::Error;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Error, Debug)]
 pub enum GnapError {
@@ -9,6 +10,10 @@ pub enum GnapError {
     MongoDataError(#[from] mongodb::bson::document::ValueAccessError),
     #[error("Cache error: {0}")]
     CacheError(#[from] redis::RedisError),
+    #[error("GNAP error: {0}")]
+    ProtocolError(GnapErrorCode),
+    #[error("Crypto error: {0}")]
+    CryptoError(String),
     #[error("Not found error")]
     NotFound,
     #[error("Bad data error")]
@@ -23,15 +28,63 @@ impl From<serde_json::Error> for GnapError {
     }
 }
 
+/// Error codes the AS returns to the client instance, as defined by the
+/// GNAP spec.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GnapErrorCode {
+    /// The request is missing a required parameter or is otherwise malformed.
+    InvalidRequest,
+    /// The client instance could not be identified or authenticated.
+    InvalidClient,
+    /// The interaction reference is missing or does not match the grant.
+    InvalidInteraction,
+    /// The continuation request refers to an unknown or finished grant.
+    InvalidContinuation,
+    /// The AS denied a token rotation request.
+    InvalidRotation,
+    /// The resource owner denied the request.
+    UserDenied,
+    /// The AS denied the request.
+    RequestDenied,
+    /// The client instance did not wait long enough before continuing.
+    TooFast,
+}
+
+impl fmt::Display for GnapErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", code.as_str().unwrap_or_default())
+    }
+}
+
 #[derive(Serialize)]
 pub struct ErrorResponse {
     pub message: String,
 }
 
+/// Body of a GNAP error response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GnapErrorResponse {
+    pub error: GnapErrorCode,
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn error_code_display() {
+        assert_eq!(GnapErrorCode::InvalidContinuation.to_string(), "invalid_continuation");
+        let response = GnapErrorResponse { error: GnapErrorCode::UserDenied };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"error":"user_denied"}"#
+        );
+    }
 }
@@ -0,0 +1,157 @@
+//! Resource owner accounts.
+//!
+//! Resource owners sign in to the interaction pages with the preferred
+//! username and password of their account.  Sign in attempts are counted,
+//! both for the account and for the address they come from, and either one
+//! that makes too many is turned away for a while.
+//!
+use crate::keys::crypto_error;
+use dao::service::Service;
+use errors::GnapError;
+use log::{error, trace};
+use model::account::{Account, AccountRequest};
+use openssl::{hash::MessageDigest, memcmp, pkcs5::pbkdf2_hmac, rand::rand_bytes};
+
+/// PBKDF2 iterations for new password hashes.
+const PASSWORD_ITERATIONS: usize = 100_000;
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+/// Number of sign in attempts an account or an address can make in the
+/// attempt window.
+const MAX_SIGN_IN_ATTEMPTS: u64 = 10;
+/// How long sign in attempts are counted for, in seconds.
+const SIGN_IN_WINDOW: u64 = 300;
+const ACCOUNT_ATTEMPTS: &str = "sign_in:accounts";
+const ADDRESS_ATTEMPTS: &str = "sign_in:addresses";
+
+/// The outcome of a sign in attempt.
+#[derive(Debug, Clone)]
+pub enum Authentication {
+    Authenticated(Box<Account>),
+    /// The username is unknown or the password is wrong.
+    Failed,
+    /// The account, or the address, has made too many attempts.
+    Throttled,
+}
+
+/// Register a new account.
+///
+/// Resource owners sign in with their preferred username, so no two
+/// accounts may share one.
+pub async fn register(service: &Service, mut request: AccountRequest) -> Result<Account, GnapError> {
+    let password = request.password.take();
+    let mut account = Account::from(request);
+    if let Some(username) = account.preferred_username() {
+        if service.get_account_by_username(username).await?.is_some() {
+            error!("Username {} is already taken", username);
+            return Err(GnapError::BadData);
+        }
+    }
+    if let Some(password) = password {
+        account.password_hash = Some(hash_password(&password)?);
+    }
+    service.add_account(&account).await?;
+    trace!("Registered account {}", account.account_id());
+    Ok(account)
+}
+
+/// Authenticate a resource owner signing in from `address`.
+///
+/// Attempts are counted before the password is checked, so that parallel
+/// guesses are counted too.  A successful sign in clears the count for the
+/// account.  Unknown usernames take as long to check as wrong passwords, so
+/// they do not reveal which accounts exist.
+pub async fn authenticate(
+    service: &Service,
+    address: &str,
+    username: &str,
+    password: &str,
+) -> Result<Authentication, GnapError> {
+    let by_address = service.count_attempt(ADDRESS_ATTEMPTS, address, SIGN_IN_WINDOW).await?;
+    let by_account = service.count_attempt(ACCOUNT_ATTEMPTS, username, SIGN_IN_WINDOW).await?;
+    if by_address > MAX_SIGN_IN_ATTEMPTS || by_account > MAX_SIGN_IN_ATTEMPTS {
+        error!("Too many sign in attempts for {} from {}", username, address);
+        return Ok(Authentication::Throttled);
+    }
+
+    let account = service.get_account_by_username(username).await?;
+    let password_hash = account.as_ref().and_then(|account| account.password_hash.as_deref());
+    let verified = match password_hash {
+        Some(password_hash) => verify_password(password, password_hash)?,
+        None => {
+            derive_key(password, &[0u8; 16], PASSWORD_ITERATIONS)?;
+            false
+        }
+    };
+    match account {
+        Some(account) if verified => {
+            service.clear_attempts(ACCOUNT_ATTEMPTS, username).await?;
+            Ok(Authentication::Authenticated(Box::new(account)))
+        }
+        Some(account) => {
+            error!("Account {} failed to authenticate", account.account_id());
+            Ok(Authentication::Failed)
+        }
+        None => {
+            error!("Unknown account: {}", username);
+            Ok(Authentication::Failed)
+        }
+    }
+}
+
+/// Hash a password for storage.
+///
+/// Passwords are chosen by people, so they are stretched with PBKDF2 and a
+/// random salt.  The hash is saved as `pbkdf2-sha256$<iterations>$<salt>$<hash>`.
+pub fn hash_password(password: &str) -> Result<String, GnapError> {
+    let mut salt = [0u8; 16];
+    rand_bytes(&mut salt).map_err(crypto_error)?;
+    let hash = derive_key(password, &salt, PASSWORD_ITERATIONS)?;
+    Ok(format!(
+        "{}${}${}${}",
+        PASSWORD_SCHEME,
+        PASSWORD_ITERATIONS,
+        base64::encode_config(salt, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Check a password against a hash made with [hash_password].
+pub fn verify_password(password: &str, password_hash: &str) -> Result<bool, GnapError> {
+    let parts: Vec<&str> = password_hash.split('$').collect();
+    let (iterations, salt, expected) = match parts.as_slice() {
+        [PASSWORD_SCHEME, iterations, salt, hash] => (iterations, salt, hash),
+        _ => {
+            error!("Unsupported password hash");
+            return Ok(false);
+        }
+    };
+    let iterations = iterations.parse().map_err(|_| GnapError::BadData)?;
+    let salt = base64::decode_config(salt, base64::URL_SAFE_NO_PAD).map_err(|_| GnapError::BadData)?;
+    let expected = base64::decode_config(expected, base64::URL_SAFE_NO_PAD).map_err(|_| GnapError::BadData)?;
+    let hash = derive_key(password, &salt, iterations)?;
+    Ok(hash.len() == expected.len() && memcmp::eq(&hash, &expected))
+}
+
+fn derive_key(password: &str, salt: &[u8], iterations: usize) -> Result<Vec<u8>, GnapError> {
+    let mut key = vec![0u8; 32];
+    pbkdf2_hmac(password.as_bytes(), salt, iterations, MessageDigest::sha256(), &mut key)
+        .map_err(crypto_error)?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_verification() {
+        let password_hash = hash_password("correct horse").unwrap();
+        assert!(password_hash.starts_with("pbkdf2-sha256$100000$"));
+        assert!(verify_password("correct horse", &password_hash).unwrap());
+        assert!(!verify_password("battery staple", &password_hash).unwrap());
+
+        // Each hash has its own salt.
+        assert_ne!(hash_password("correct horse").unwrap(), password_hash);
+        assert!(!verify_password("correct horse", "plain").unwrap());
+    }
+}
@@ -0,0 +1,68 @@
+//! Administrative API authentication.
+//!
+//! Registering clients and resource servers is limited to the AS operator.
+//! Those requests carry the `GNAP_ADMIN_SECRET` with the `Bearer`
+//! authorization scheme.  Without the secret set, the admin API is closed.
+//!
+use crate::keys::{hash_secret, verify_secret};
+use actix_web::{http::header, HttpRequest};
+use errors::{GnapError, GnapErrorCode};
+use log::error;
+use std::env;
+
+/// Get the admin secret from ENV.
+fn admin_secret() -> Option<String> {
+    env::var("GNAP_ADMIN_SECRET").ok().filter(|secret| !secret.is_empty())
+}
+
+/// Check that a request was made by the AS operator.
+pub fn authenticate(req: &HttpRequest) -> Result<(), GnapError> {
+    let unauthorized = GnapError::ProtocolError(GnapErrorCode::InvalidClient);
+    let secret = match admin_secret() {
+        Some(secret) => secret,
+        None => {
+            error!("GNAP_ADMIN_SECRET is not set, the admin API is closed");
+            return Err(unauthorized);
+        }
+    };
+    match bearer_token(req) {
+        Some(presented) if verify_secret(&presented, &hash_secret(&secret)) => Ok(()),
+        _ => {
+            error!("Admin request without the admin secret");
+            Err(unauthorized)
+        }
+    }
+}
+
+/// Get the token from a Bearer authorization header.
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme != "Bearer" || token.trim().is_empty() {
+        return None;
+    }
+    Some(token.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn admin_secret_required() {
+        let request = |value: &str| {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, value))
+                .to_http_request()
+        };
+        env::set_var("GNAP_ADMIN_SECRET", "s3cret");
+        assert!(authenticate(&request("Bearer s3cret")).is_ok());
+        assert!(authenticate(&request("Bearer guess")).is_err());
+        assert!(authenticate(&request("GNAP s3cret")).is_err());
+        assert!(authenticate(&TestRequest::default().to_http_request()).is_err());
+
+        env::remove_var("GNAP_ADMIN_SECRET");
+        assert!(authenticate(&request("Bearer s3cret")).is_err());
+    }
+}
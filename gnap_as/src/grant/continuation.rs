@@ -0,0 +1,111 @@
+use super::{continuation_for, finalize_grant, CONTINUATION_WAIT};
+use crate::proof::ProofRequest;
+use dao::service::Service;
+use errors::{GnapError, GnapErrorCode};
+use log::{error, trace};
+use model::{
+    grant::{ContinueRequest, GrantResponse},
+    transaction::{GnapTransaction, GnapTransactionState},
+};
+
+/// Continue a grant transaction.
+///
+/// Loads the transaction cached by [Service::start_transaction] and moves it
+/// forward based on its current state.  The client instance must present the
+/// transaction's current continuation access token, which is rotated on each
+/// successful continuation.  The token is bound to the key the transaction
+/// was started with, so the request must be signed with that key.  Once
+/// interaction has finished, the request must carry the interaction
+/// reference sent to the client instance.  Otherwise, the client instance is
+/// polling, and must wait as long as it was told to between requests.
+///
+/// The token is checked again when it is rotated or the grant is finalized,
+/// and the change is only saved if the transaction has not changed in the
+/// meantime.  Of several requests presenting the same token, only one
+/// succeeds.
+pub async fn process_continuation(
+    service: &Service,
+    tx_id: &str,
+    continuation_token: &str,
+    request: &ContinueRequest,
+    proof: &ProofRequest<'_>,
+) -> Result<GrantResponse, GnapError> {
+    let tx = match service.get_transaction(tx_id).await? {
+        Some(tx) => tx,
+        None => {
+            error!("No transaction found for continuation: {}", tx_id);
+            return Err(GnapError::ProtocolError(GnapErrorCode::InvalidContinuation));
+        }
+    };
+
+    if !tx.is_continuation_token(continuation_token) {
+        error!("Continuation access token does not match transaction {}", tx_id);
+        return Err(GnapError::ProtocolError(GnapErrorCode::InvalidContinuation));
+    }
+    if tx.key.is_none() {
+        error!("Transaction {} has no key to bind its continuation to", tx_id);
+        return Err(GnapError::ProtocolError(GnapErrorCode::InvalidClient));
+    }
+    proof.verify(service, tx.key.as_ref(), Some(continuation_token)).await?;
+    if !tx.is_interact_ref(request.interact_ref.as_deref()) {
+        error!("Interaction reference does not match transaction {}", tx_id);
+        return Err(GnapError::ProtocolError(GnapErrorCode::InvalidInteraction));
+    }
+    if request.interact_ref.is_none() && tx.is_too_fast(CONTINUATION_WAIT) {
+        error!("Transaction {} was continued before the wait was over", tx_id);
+        return Err(GnapError::ProtocolError(GnapErrorCode::TooFast));
+    }
+
+    match tx.state {
+        GnapTransactionState::Start
+        | GnapTransactionState::Received
+        | GnapTransactionState::ClientVerified
+        | GnapTransactionState::ResourceOwnerVerified => {
+            // Still waiting on the resource owner.  Hand back a fresh
+            // continuation.
+            trace!("Transaction {} is pending", &tx.tx_id);
+            let tx = rotate_continuation(service, tx_id, continuation_token).await?;
+            Ok(pending_response(&tx))
+        }
+        GnapTransactionState::Approved => {
+            trace!("Transaction {} is approved", &tx.tx_id);
+            finalize_grant(service, tx_id, continuation_token).await
+        }
+        GnapTransactionState::Denied => {
+            trace!("Transaction {} was denied", &tx.tx_id);
+            Err(GnapError::ProtocolError(GnapErrorCode::UserDenied))
+        }
+        GnapTransactionState::Finalized => {
+            trace!("Transaction {} is already finalized", &tx.tx_id);
+            Err(GnapError::ProtocolError(GnapErrorCode::InvalidContinuation))
+        }
+    }
+}
+
+/// Replace the continuation access token, if it is still the one presented.
+async fn rotate_continuation(
+    service: &Service,
+    tx_id: &str,
+    continuation_token: &str,
+) -> Result<GnapTransaction, GnapError> {
+    service
+        .modify_transaction(tx_id, |tx| {
+            if !tx.is_continuation_token(continuation_token) {
+                error!("Continuation access token for {} was already used", tx_id);
+                return Err(GnapError::ProtocolError(GnapErrorCode::InvalidContinuation));
+            }
+            tx.rotate_continuation_token();
+            Ok(())
+        })
+        .await?
+        .ok_or(GnapError::ProtocolError(GnapErrorCode::InvalidContinuation))
+}
+
+fn pending_response(tx: &GnapTransaction) -> GrantResponse {
+    GrantResponse {
+        instance_id: tx.instance_id(),
+        access_token: None,
+        tx_continue: Some(continuation_for(tx)),
+        interact: None,
+    }
+}
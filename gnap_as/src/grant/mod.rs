@@ -1 +1,80 @@
-pub mod request;
\ No newline at end of file
+use crate::token::issue_tokens;
+use dao::service::Service;
+use errors::{GnapError, GnapErrorCode};
+use gnap_as::get_as_host;
+use log::error;
+use model::{
+    grant::{GrantResponse, RequestContinuation},
+    transaction::{GnapTransaction, GnapTransactionState},
+};
+
+pub mod continuation;
+pub mod request;
+
+/// Number of seconds a client instance should wait between continuation calls.
+pub const CONTINUATION_WAIT: u32 = 5;
+
+/// The grant endpoint URI, where client instances start grant requests.
+pub fn grant_endpoint() -> String {
+    format!("{}/gnap/tx", get_as_host())
+}
+
+/// Build the continuation section of a grant response for a transaction.
+///
+/// The transaction's current continuation access token is handed to the
+/// client instance with the URI.
+pub fn continuation_for(tx: &GnapTransaction) -> RequestContinuation {
+    let uri = format!("{}/{}", grant_endpoint(), &tx.tx_id);
+    let mut rc = RequestContinuation::with_wait(&uri, CONTINUATION_WAIT);
+    rc.access_token = tx.continuation_token.clone();
+    rc
+}
+
+/// Finish an approved transaction.
+///
+/// The transaction is finalized first, and only if it is still approved and
+/// the presented continuation access token is still current, so the grant
+/// is only ever issued once.  Access tokens are then issued for the grant,
+/// and the transaction can no longer be continued.
+pub async fn finalize_grant(
+    service: &Service,
+    tx_id: &str,
+    continuation_token: &str,
+) -> Result<GrantResponse, GnapError> {
+    let tx = service
+        .modify_transaction(tx_id, |tx| {
+            if !tx.is_continuation_token(continuation_token)
+                || !matches!(tx.state, GnapTransactionState::Approved)
+            {
+                error!("Transaction {} is no longer approved for this continuation", tx_id);
+                return Err(GnapError::ProtocolError(GnapErrorCode::InvalidContinuation));
+            }
+            tx.state = GnapTransactionState::Finalized;
+            tx.continuation_token = None;
+            Ok(())
+        })
+        .await?
+        .ok_or(GnapError::ProtocolError(GnapErrorCode::InvalidContinuation))?;
+    let access_token = issue_tokens(service, &tx).await?;
+
+    Ok(GrantResponse {
+        instance_id: tx.instance_id(),
+        access_token,
+        tx_continue: None,
+        interact: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuation_uri() {
+        let tx = GnapTransaction::new(None);
+        let rc = continuation_for(&tx);
+        assert!(rc.uri.ends_with(&format!("/gnap/tx/{}", &tx.tx_id)));
+        assert_eq!(rc.wait, Some(CONTINUATION_WAIT));
+        assert_eq!(rc.access_token, tx.continuation_token);
+    }
+}
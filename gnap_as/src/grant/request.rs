@@ -1,61 +1,239 @@
-use model::{GnapID, grant::*};
-use errors::GnapError;
+use model::{GnapID, client::GnapClient, grant::*, transaction::GnapTransactionState};
+use errors::{GnapError, GnapErrorCode};
+use super::{continuation_for, finalize_grant};
+use crate::{
+    interact::{app, interaction_uri, user_code}, keys::client::client_key, outbound::is_public_uri,
+    proof::ProofRequest,
+    token::validate_token_requests,
+};
 use dao::service::Service;
 use log::{trace, error};
+use url::Url;
 
-pub async fn process_request(service: &Service, request: GrantRequest) -> Result<GrantResponse, GnapError> {
+pub async fn process_request(
+    service: &Service,
+    mut request: GrantRequest,
+    proof: &ProofRequest<'_>,
+) -> Result<GrantResponse, GnapError> {
 
-    // A valid request?
-    if request.client.is_none() {
-        // No client identifier
-        error!("No client id in grant request");
-        return Err(GnapError::BadData);
-    }
-    // This will fail if either there is no client_id in the request, or the
-    // client_id is not a valid uuid.
-    trace!("getting id from reqeust...");
-    let client_id = request.parse_id()?;
-    trace!("parsed id from request: {}", client_id.to_string());
-    // This will fail if the client_id provided in the request is not found.
-    let _client = service.get_client(&client_id).await?.unwrap();
-
-    // At this point, we have determined that the request contains a valid client_id
+    let (client, key) = match request.client.clone() {
+        None => {
+            // No client identifier
+            error!("No client id in grant request");
+            return Err(GnapError::BadData);
+        }
+        Some(GnapClientInstance::Value(instance)) => {
+            // The request must be signed with the key it presents, before
+            // the instance is saved.
+            trace!("Request client is sent by value");
+            proof.verify(service, Some(&instance.key), None).await?;
+            // Nothing is registered for the instance ahead of time, so the
+            // redirect finish URI it presents is registered for it.
+            let redirect_uri = request
+                .interact
+                .as_ref()
+                .and_then(|interact| interact.finish.as_ref())
+                .filter(|finish| matches!(finish.method, InteractFinishMethodType::Redirect))
+                .map(|finish| finish.uri.clone());
+            let client = service.add_client_instance(*instance, redirect_uri.as_deref()).await?;
+            trace!("Saved client instance: {}", client.client_id.to_string());
+            // From here on, the instance is handled as a reference.
+            request.client = Some(GnapClientInstance::Ref(client.client_id.to_string()));
+            let key = client.key.clone();
+            (client, key)
+        }
+        Some(GnapClientInstance::Ref(_)) => {
+            // This will fail if the client_id is not a valid uuid.
+            trace!("getting id from reqeust...");
+            let client_id = request.parse_id()?;
+            trace!("parsed id from request: {}", client_id.to_string());
+            // This will fail if the client_id provided in the request is not found.
+            let client = service
+                .get_client(&client_id)
+                .await?
+                .ok_or(GnapError::ProtocolError(GnapErrorCode::InvalidClient))?;
+
+            // The request must be signed with the client instance key.
+            let key = client_key(service, &client, proof).await?;
+            proof.verify(service, key.as_ref(), None).await?;
+            (client, key)
+        }
+    };
+
+    // At this point, we have determined that the request contains a valid client
     // and the client data was found.  Now we can compare request data against
     // the authorized client.
 
     // Verify the request data against client config, etc.
+    validate_token_requests(&request.access_token)?;
+    if let Some(finish) = request.interact.as_ref().and_then(|interact| interact.finish.as_ref()) {
+        validate_finish(&client, finish)?;
+    }
 
-    // Start a transaction
-    let tx = service.start_transaction(request.clone()).await?;
+    // Start a transaction, with tokens bound to the key the request was
+    // proven with.
+    let mut tx = service.start_transaction(request.clone(), proof.proven_key(key.as_ref())).await?;
 
-    let uri = format!("http://locahost:8000/tx/{}", &tx.tx_id);
-    let rc = RequestContinuation::as_uri(&uri.clone());
+    // Requests that do not need the resource owner can be approved right
+    // away.
+    let interact = match request.interact {
+        Some(interact) => interact,
+        None => {
+            if !can_approve_without_interaction(&client, &request) {
+                error!("GrantRequest requires interaction, but the client cannot interact");
+                return Err(GnapError::ProtocolError(GnapErrorCode::RequestDenied));
+            }
+            trace!("GrantRequest approved without interaction");
+            tx.state = GnapTransactionState::Approved;
+            service.update_transaction(&tx).await?;
+            let continuation_token = tx.continuation_token.as_ref().map(|token| token.value.clone());
+            return finalize_grant(service, &tx.tx_id, &continuation_token.unwrap_or_default()).await;
+        }
+    };
+
+    // The AS nonce for the interaction finish hash is handed out with the
+    // interaction modes.
+    let interaction = tx.start_interaction().clone();
     let mut interact_response = InteractResponse {
-        tx_continue: rc,
-        redirect: None
+        redirect: None,
+        app: None,
+        user_code: None,
+        finish: interaction.finish_nonce.clone(),
     };
 
     // What are the interaction methods?
-    for method in  request.interact.unwrap().start.iter() {
+    for method in interact.start.iter() {
         match method {
             InteractStartMode::Redirect => {
                 trace!("GrantRequest interaction contains Redirect");
-                interact_response.redirect = Some(uri.clone());
+                interact_response.redirect = Some(interaction_uri(&interaction.id));
             },
             InteractStartMode:: App => {
                 trace!("GrantRequest interaction contains App");
+                interact_response.app = Some(app::app_uri(service, &interaction.id).await?);
             },
             InteractStartMode::UserCode => {
                 trace!("GrantRequest interaction contains UserCode");
+                interact_response.user_code = Some(user_code::issue(service, &mut tx).await?);
             }
         }
     }
+    // Without the redirect mode, the interaction URI is only reached by
+    // redeeming an app URI or user code, so it is bound to that browser.
+    if !interact.start.iter().any(|mode| matches!(mode, InteractStartMode::Redirect)) {
+        if let Some(interaction) = tx.interaction.as_mut() {
+            interaction.bind_browser = true;
+        }
+    }
+    service.update_transaction(&tx).await?;
+    service.add_interaction(&tx).await?;
+
+    let rc = continuation_for(&tx);
 
     let response = GrantResponse{
-        instance_id: tx.tx_id.clone(),
+        instance_id: tx.instance_id(),
+        access_token: None,
+        tx_continue: Some(rc),
         interact: Some(interact_response)
     };
 
     Ok(response)
 
-}
\ No newline at end of file
+}
+
+/// Check the interaction finish method against the client registration.
+///
+/// The browser is only ever sent back to a registered redirect URI.  Client
+/// instances sent by value register the redirect URI they present.  Push
+/// finish URIs must be https URIs on a public host, since the AS makes the
+/// request itself.
+fn validate_finish(client: &GnapClient, finish: &InteractFinishRequest) -> Result<(), GnapError> {
+    match finish.method {
+        InteractFinishMethodType::Redirect if !client.is_redirect_uri(&finish.uri) => {
+            error!("Finish URI {} is not registered for client {}", &finish.uri, &client.client_id);
+            Err(GnapError::ProtocolError(GnapErrorCode::InvalidRequest))
+        }
+        InteractFinishMethodType::Push if !is_push_uri(&finish.uri) => {
+            error!("Push finish URI {} is not a public https URI", &finish.uri);
+            Err(GnapError::ProtocolError(GnapErrorCode::InvalidRequest))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn is_push_uri(uri: &str) -> bool {
+    matches!(Url::parse(uri), Ok(uri) if is_public_uri(&uri))
+}
+
+/// Can the AS approve the request without the resource owner?
+///
+/// Only access the client was registered with is granted this way.
+/// Requests for subject information always need the resource owner.
+fn can_approve_without_interaction(client: &GnapClient, request: &GrantRequest) -> bool {
+    request.subject.is_none()
+        && !request.access_token.is_empty()
+        && request.access_token.iter().all(|token_request| {
+            !token_request.access.is_empty()
+                && token_request.access.iter().all(|access| client.is_pre_authorized(access))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finish_uri_registration() {
+        let client = GnapClient::new(vec!["https://client.example.net/return".to_owned()], "client".to_owned());
+        let mut finish = InteractFinishRequest {
+            method: InteractFinishMethodType::Redirect,
+            uri: "https://client.example.net/return".to_owned(),
+            nonce: "abc".to_owned(),
+            hash_method: None,
+        };
+        assert!(validate_finish(&client, &finish).is_ok());
+
+        finish.uri = "https://client.example.net/return/other".to_owned();
+        assert!(validate_finish(&client, &finish).is_err());
+
+        finish.method = InteractFinishMethodType::Push;
+        assert!(validate_finish(&client, &finish).is_ok());
+        finish.uri = "client.example.net/push".to_owned();
+        assert!(validate_finish(&client, &finish).is_err());
+        finish.uri = "http://client.example.net/push".to_owned();
+        assert!(validate_finish(&client, &finish).is_err());
+        finish.uri = "https://169.254.169.254/latest/meta-data".to_owned();
+        assert!(validate_finish(&client, &finish).is_err());
+    }
+
+    fn grant_request(access: Vec<AccessRequest>) -> GrantRequest {
+        let mut token_request = AccessTokenRequest::new();
+        token_request.access = access;
+        GrantRequest {
+            access_token: vec![token_request],
+            subject: None,
+            client: None,
+            user: None,
+            interact: None,
+        }
+    }
+
+    #[test]
+    fn pre_authorized_access() {
+        let mut client = GnapClient::new(Vec::new(), "client".to_owned());
+        let listed = AccessRequest::Value {
+            resource_type: "photo-api".to_owned(),
+            actions: Some(vec!["read".to_owned()]),
+            locations: None,
+            data_types: None,
+        };
+        let unlisted = AccessRequest::Reference("calendar".to_owned());
+        assert!(!can_approve_without_interaction(&client, &grant_request(vec![listed.clone()])));
+
+        client.pre_authorized = Some(vec!["photo-api".to_owned()]);
+        assert!(can_approve_without_interaction(&client, &grant_request(vec![listed.clone()])));
+        assert!(!can_approve_without_interaction(&client, &grant_request(vec![unlisted.clone()])));
+        assert!(!can_approve_without_interaction(&client, &grant_request(vec![listed, unlisted])));
+        assert!(!can_approve_without_interaction(&client, &grant_request(Vec::new())));
+    }
+}
@@ -1,8 +1,13 @@
 use dao::service::Service;
 use uuid::Uuid;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
+use model::account::AccountRequest;
 use model::client::GnapClientRequest;
+use model::resource::ResourceServerRequest;
+use super::error_response;
+use crate::{account, admin};
+use crate::resource_server::register;
 use log::{trace, error};
 
 pub async fn get_client(
@@ -29,15 +34,56 @@ pub async fn get_client(
     }
 }
 
+/// Register a client.  Only the AS operator can register clients.
 pub async fn add_client(
+    req: HttpRequest,
     service: web::Data<Service>,
     client: web::Json<GnapClientRequest>
 ) -> HttpResponse {
-
+    if let Err(err) = admin::authenticate(&req) {
+        return error_response(err);
+    }
     match service.add_client(client.into_inner()).await
     {
         Ok(data) => HttpResponse::Ok().json(data),
         Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
     }
 
-}
\ No newline at end of file
+}
+
+/// Register a resource server.  The response holds the RS secret, which
+/// cannot be retrieved again.  Only the AS operator can register RSs.
+pub async fn add_resource_server(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    request: web::Json<ResourceServerRequest>
+) -> HttpResponse {
+    if let Err(err) = admin::authenticate(&req) {
+        return error_response(err);
+    }
+    match register(&service, request.into_inner()).await
+    {
+        Ok(data) => HttpResponse::Ok().json(data),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Register a resource owner account.  The password hash is not returned.
+/// Only the AS operator can register accounts.
+pub async fn add_account(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    request: web::Json<AccountRequest>
+) -> HttpResponse {
+    if let Err(err) = admin::authenticate(&req) {
+        return error_response(err);
+    }
+    match account::register(&service, request.into_inner()).await
+    {
+        Ok(mut data) => {
+            data.password_hash = None;
+            HttpResponse::Ok().json(data)
+        },
+        Err(err) => error_response(err),
+    }
+}
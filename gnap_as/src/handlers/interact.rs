@@ -0,0 +1,249 @@
+//! Resource owner interaction handlers
+use crate::interact::{
+    app, bind_browser,
+    complete_interaction, consent_page, finish_push, finish_redirect, has_session,
+    interaction_path, is_bound_browser, login, pages, pending_transaction, Login,
+    user_code::{guess_address, redeem, trusted_proxies, Redemption},
+    BROWSER_COOKIE, SESSION_COOKIE,
+};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    http::header::{self, ContentType},
+    web, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use dao::service::Service;
+use errors::GnapError;
+use gnap_as::get_as_host;
+use log::{error, trace};
+use model::interact::{AppHandleQuery, ConsentForm, LoginForm, UserCodeForm};
+use model::transaction::GnapTransaction;
+
+/// HTTP GET <as>/gnap/interact/{interaction_id}
+///
+/// Shows the sign in page, or the consent page once the resource owner has
+/// signed in.
+pub async fn interaction_page(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    interaction_id: web::Path<String>,
+) -> HttpResponse {
+    trace!("interaction_page: {}", &interaction_id);
+    let tx = match bound_transaction(&req, &service, &interaction_id).await {
+        Ok(tx) => tx,
+        Err(err) => return page_error(err),
+    };
+    if !has_session(&tx, session(&req).as_deref()) {
+        return html(HttpResponse::Ok(), pages::login_page(&interaction_id, None));
+    }
+    match consent_page(&service, &tx).await {
+        Ok(page) => html(HttpResponse::Ok(), page),
+        Err(err) => page_error(err),
+    }
+}
+
+/// HTTP POST <as>/gnap/interact/{interaction_id}/login
+pub async fn interaction_login(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    interaction_id: web::Path<String>,
+    form: web::Form<LoginForm>,
+) -> HttpResponse {
+    trace!("interaction_login: {}", &interaction_id);
+    let mut tx = match bound_transaction(&req, &service, &interaction_id).await {
+        Ok(tx) => tx,
+        Err(err) => return page_error(err),
+    };
+    match login(&service, &mut tx, &client_address(&req), &form).await {
+        Ok(Login::Session(secret)) => {
+            let path = interaction_path(&interaction_id);
+            HttpResponse::SeeOther()
+                .cookie(interaction_cookie(SESSION_COOKIE, secret, &path))
+                .insert_header((header::LOCATION, path))
+                .finish()
+        }
+        Ok(Login::Failed) => html(
+            HttpResponse::Unauthorized(),
+            pages::login_page(&interaction_id, Some("The username or password is not correct.")),
+        ),
+        Ok(Login::Throttled) => html(
+            HttpResponse::TooManyRequests(),
+            pages::login_page(&interaction_id, Some("Too many attempts.  Wait a few minutes and try again.")),
+        ),
+        Err(err) => page_error(err),
+    }
+}
+
+/// HTTP POST <as>/gnap/interact/{interaction_id}/consent
+pub async fn interaction_consent(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    interaction_id: web::Path<String>,
+    form: web::Form<ConsentForm>,
+) -> HttpResponse {
+    trace!("interaction_consent: {}", &interaction_id);
+    let mut tx = match bound_transaction(&req, &service, &interaction_id).await {
+        Ok(tx) => tx,
+        Err(err) => return page_error(err),
+    };
+    let session = session(&req);
+    if !has_session(&tx, session.as_deref()) {
+        error!("Consent for transaction {} without a session", &tx.tx_id);
+        return html(HttpResponse::Unauthorized(), pages::login_page(&interaction_id, None));
+    }
+    let finish = match complete_interaction(&service, &mut tx, session.as_deref(), form.decision).await {
+        Ok(finish) => finish,
+        Err(err) => return page_error(err),
+    };
+
+    // Tell the client instance interaction finished, the way it asked to
+    // be told.
+    let redirect = match &finish {
+        Some(finish) => {
+            finish_push(&service, &tx, finish);
+            finish_redirect(&service, &tx, finish).await
+        }
+        None => Ok(None),
+    };
+    match redirect {
+        Ok(Some(uri)) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, uri))
+            .finish(),
+        Ok(None) => html(HttpResponse::Ok(), pages::done_page(form.decision)),
+        Err(err) => page_error(err),
+    }
+}
+
+/// HTTP GET <as>/gnap/device
+pub async fn user_code_page() -> HttpResponse {
+    html(HttpResponse::Ok(), pages::user_code_page(None))
+}
+
+/// HTTP POST <as>/gnap/device
+///
+/// Takes the resource owner on to the interaction for the user code.
+pub async fn user_code_entry(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    form: web::Form<UserCodeForm>,
+) -> HttpResponse {
+    // Guesses are throttled by the address they come from.
+    match redeem(&service, &client_address(&req), &form.code).await {
+        Ok(Redemption::Interaction(interaction_id)) => enter_interaction(&service, &interaction_id).await,
+        Ok(Redemption::Unknown) => html(
+            HttpResponse::BadRequest(),
+            pages::user_code_page(Some("That code is not valid.  Check the code and try again.")),
+        ),
+        Ok(Redemption::Throttled) => html(
+            HttpResponse::TooManyRequests(),
+            pages::user_code_page(Some("Too many wrong codes.  Wait a few minutes and try again.")),
+        ),
+        Err(err) => page_error(err),
+    }
+}
+
+/// HTTP GET <as>/gnap/app?handle=...
+///
+/// Redeems the interaction handle of an app URI, and takes the resource
+/// owner on to the interaction.
+pub async fn app_interaction(
+    service: web::Data<Service>,
+    query: web::Query<AppHandleQuery>,
+) -> HttpResponse {
+    trace!("app_interaction");
+    match app::redeem(&service, &query.handle).await {
+        Ok(interaction_id) => enter_interaction(&service, &interaction_id).await,
+        Err(err) => page_error(err),
+    }
+}
+
+/// Bind the interaction to the browser that redeemed it, and take the
+/// browser on to the interaction.
+async fn enter_interaction(service: &Service, interaction_id: &str) -> HttpResponse {
+    let secret = match bind_browser(service, interaction_id).await {
+        Ok(secret) => secret,
+        Err(err) => return page_error(err),
+    };
+    let path = interaction_path(interaction_id);
+    HttpResponse::SeeOther()
+        .cookie(interaction_cookie(BROWSER_COOKIE, secret, &path))
+        .insert_header((header::LOCATION, path))
+        .finish()
+}
+
+/// Find the pending transaction for an interaction the browser may open.
+async fn bound_transaction(
+    req: &HttpRequest,
+    service: &Service,
+    interaction_id: &str,
+) -> Result<GnapTransaction, GnapError> {
+    let tx = pending_transaction(service, interaction_id).await?;
+    let browser = req.cookie(BROWSER_COOKIE).map(|cookie| cookie.value().to_owned());
+    if !is_bound_browser(&tx, browser.as_deref()) {
+        error!("Interaction for transaction {} opened by another browser", &tx.tx_id);
+        return Err(GnapError::NotFound);
+    }
+    Ok(tx)
+}
+
+fn interaction_cookie(name: &'static str, secret: String, path: &str) -> Cookie<'static> {
+    Cookie::build(name, secret)
+        .path(path.to_owned())
+        .http_only(true)
+        .secure(get_as_host().starts_with("https"))
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+/// The address a request comes from, for throttling attempts.
+fn client_address(req: &HttpRequest) -> String {
+    let forwarded_for = req
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok());
+    guess_address(req.peer_addr().map(|addr| addr.ip()), forwarded_for, trusted_proxies())
+}
+
+fn session(req: &HttpRequest) -> Option<String> {
+    req.cookie(SESSION_COOKIE).map(|cookie| cookie.value().to_owned())
+}
+
+/// Interaction pages may not be framed by other sites, so that the resource
+/// owner cannot be tricked into clicking their buttons.
+fn html(mut builder: HttpResponseBuilder, page: String) -> HttpResponse {
+    builder
+        .content_type(ContentType::html())
+        .insert_header((header::X_FRAME_OPTIONS, "DENY"))
+        .insert_header((header::CONTENT_SECURITY_POLICY, "frame-ancestors 'none'"))
+        .body(page)
+}
+
+fn page_error(err: GnapError) -> HttpResponse {
+    match err {
+        GnapError::NotFound => html(
+            HttpResponse::NotFound(),
+            pages::error_page("This request is unknown, or has already been decided."),
+        ),
+        err => {
+            error!("{:?}", err);
+            html(
+                HttpResponse::InternalServerError(),
+                pages::error_page("The request could not be processed."),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_cannot_be_framed() {
+        let response = html(HttpResponse::Ok(), pages::login_page("abc", None));
+        assert_eq!(response.headers().get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(
+            response.headers().get(header::CONTENT_SECURITY_POLICY).unwrap(),
+            "frame-ancestors 'none'"
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! Token introspection API handlers
+use super::error_response;
+use crate::{resource_server::authenticate, token::introspect::introspect};
+use actix_web::{web, HttpRequest, HttpResponse};
+use dao::service::Service;
+use log::{error, trace};
+use model::token::IntrospectionRequest;
+
+/// Introspect an access token on behalf of an authenticated RS
+pub async fn introspect_token(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    request: web::Json<IntrospectionRequest>,
+) -> HttpResponse {
+    let rs = match authenticate(&service, &req).await {
+        Ok(rs) => rs,
+        Err(err) => return error_response(err),
+    };
+    trace!("introspect_token for {}", &rs.rs_id);
+    match introspect(&service, &rs, &request).await {
+        Ok(data) => HttpResponse::Ok().json(data),
+        Err(err) => {
+            error!("{:?}", err);
+            error_response(err)
+        }
+    }
+}
@@ -0,0 +1,18 @@
+//! AS key API handlers
+use super::error_response;
+use crate::keys::published_keys;
+use actix_web::{web, HttpResponse};
+use dao::service::Service;
+use log::{error, trace};
+
+/// Publish the AS public keys as a JWK Set
+pub async fn jwks(service: web::Data<Service>) -> HttpResponse {
+    trace!("jwks");
+    match published_keys(&service).await {
+        Ok(data) => HttpResponse::Ok().json(data),
+        Err(err) => {
+            error!("{:?}", err);
+            error_response(err)
+        }
+    }
+}
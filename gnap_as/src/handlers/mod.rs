@@ -1,3 +1,65 @@
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use errors::{GnapError, GnapErrorCode, GnapErrorResponse};
+
 pub mod transaction;
 pub mod well_known;
 pub mod db;
+pub mod introspection;
+pub mod token;
+pub mod keys;
+pub mod interact;
+
+/// Convert a GnapError into an HTTP response.
+///
+/// Protocol errors are returned to the client instance as a GNAP error
+/// object.  Anything else is an internal error.
+pub fn error_response(err: GnapError) -> HttpResponse {
+    match err {
+        GnapError::ProtocolError(code) => {
+            let body = GnapErrorResponse { error: code };
+            match code {
+                GnapErrorCode::InvalidClient | GnapErrorCode::InvalidContinuation => {
+                    HttpResponse::Unauthorized().json(body)
+                }
+                _ => HttpResponse::BadRequest().json(body),
+            }
+        }
+        GnapError::BadData => HttpResponse::BadRequest().body(err.to_string()),
+        _ => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Get the access token presented with the `GNAP` authorization scheme.
+///
+/// GNAP tokens handed out by this AS are never bearer tokens, so a token
+/// presented with any other scheme, such as `Bearer`, is not accepted.
+pub fn gnap_access_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme != "GNAP" || token.trim().is_empty() {
+        return None;
+    }
+    Some(token.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn gnap_scheme_only() {
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "GNAP abc123"))
+            .to_http_request();
+        assert_eq!(gnap_access_token(&req), Some("abc123".to_owned()));
+
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer abc123"))
+            .to_http_request();
+        assert_eq!(gnap_access_token(&req), None);
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(gnap_access_token(&req), None);
+    }
+}
@@ -0,0 +1,59 @@
+//! Access token management API handlers
+use super::{error_response, gnap_access_token};
+use crate::proof::ProofRequest;
+use crate::token::manage::{revoke_token, rotate_token};
+use actix_web::{web, HttpRequest, HttpResponse};
+use dao::service::Service;
+use errors::{GnapError, GnapErrorCode};
+use log::{error, trace};
+use model::token::TokenManagementResponse;
+
+/// Rotate an access token at its management URI
+pub async fn rotate(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    token_id: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let token_id = token_id.into_inner();
+    trace!("rotate: {}", &token_id);
+    let presented = match gnap_access_token(&req) {
+        Some(token) => token,
+        None => {
+            error!("Token management request without a GNAP access token");
+            return error_response(GnapError::ProtocolError(GnapErrorCode::InvalidClient));
+        }
+    };
+    match rotate_token(&service, &token_id, &presented, &ProofRequest::new(&req, &body)).await {
+        Ok(access_token) => HttpResponse::Ok().json(TokenManagementResponse { access_token }),
+        Err(err) => {
+            error!("{:?}", err);
+            error_response(err)
+        }
+    }
+}
+
+/// Revoke an access token at its management URI
+pub async fn revoke(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    token_id: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let token_id = token_id.into_inner();
+    trace!("revoke: {}", &token_id);
+    let presented = match gnap_access_token(&req) {
+        Some(token) => token,
+        None => {
+            error!("Token management request without a GNAP access token");
+            return error_response(GnapError::ProtocolError(GnapErrorCode::InvalidClient));
+        }
+    };
+    match revoke_token(&service, &token_id, &presented, &ProofRequest::new(&req, &body)).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            error!("{:?}", err);
+            error_response(err)
+        }
+    }
+}
@@ -1,9 +1,12 @@
 //! Transaction API Handlers
-use crate::grant::request::process_request;
-use actix_web::{web, HttpResponse};
+use super::{error_response, gnap_access_token};
+use crate::grant::{continuation::process_continuation, request::process_request};
+use crate::proof::{jws::SignedJson, ProofRequest};
+use actix_web::{web, HttpRequest, HttpResponse};
 use dao::service::Service;
+use errors::{GnapError, GnapErrorCode};
 use log::{error, trace};
-use model::grant::GrantRequest;
+use model::grant::{ContinueRequest, GrantRequest};
 
 /// HTTP OPTIONS <as>/gnap/tx
 pub async fn grant_options(service: web::Data<Service>) -> HttpResponse {
@@ -23,11 +26,13 @@ pub async fn grant_options(service: web::Data<Service>) -> HttpResponse {
 
 /// Initiate a grant transaction
 pub async fn grant_request(
+    req: HttpRequest,
     service: web::Data<Service>,
-    request: web::Json<GrantRequest>,
+    request: SignedJson<GrantRequest>,
 ) -> HttpResponse {
     // Create a response from the request
-    let result = process_request(&service, request.into_inner()).await;
+    let proof = ProofRequest::new(&req, request.body());
+    let result = process_request(&service, (*request).clone(), &proof).await;
     match result {
         Ok(data) => {
             trace!("processed grant request: {:?}", data);
@@ -35,7 +40,37 @@ pub async fn grant_request(
         }
         Err(err) => {
             error!("{:?}", err);
-            HttpResponse::InternalServerError().body(err.to_string())
+            error_response(err)
+        }
+    }
+}
+
+/// Continue a grant transaction at its continuation URI
+pub async fn continue_request(
+    req: HttpRequest,
+    service: web::Data<Service>,
+    tx_id: web::Path<String>,
+    request: SignedJson<Option<ContinueRequest>>,
+) -> HttpResponse {
+    let tx_id = tx_id.into_inner();
+    trace!("continue_request: {}", &tx_id);
+    let token = match gnap_access_token(&req) {
+        Some(token) => token,
+        None => {
+            error!("Continuation request without a GNAP access token");
+            return error_response(GnapError::ProtocolError(GnapErrorCode::InvalidContinuation));
+        }
+    };
+    let continue_request = request.clone().unwrap_or_default();
+    let proof = ProofRequest::new(&req, request.body());
+    match process_continuation(&service, &tx_id, &token, &continue_request, &proof).await {
+        Ok(data) => {
+            trace!("processed continuation: {:?}", data);
+            HttpResponse::Ok().json(data)
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            error_response(err)
         }
     }
 }
@@ -0,0 +1,111 @@
+//! App interaction.
+//!
+//! Client instances that can launch an application on the resource owner's
+//! device are given an app URI on the AS.  The URI carries an interaction
+//! handle: a short lived JWT, signed by the AS, that names the interaction.
+//! Each handle can only be redeemed once, after which the resource owner
+//! goes through the same pages as with the redirect mode, in the browser
+//! that redeemed it.
+//!
+use super::pending_transaction;
+use crate::{
+    keys::{get_signing_key, signing_alg},
+    token::jwt::{decode_jwt, encode_jwt},
+};
+use dao::service::Service;
+use errors::GnapError;
+use gnap_as::get_as_host;
+use log::error;
+use model::{key::SigningKey, unix_time};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// JWT `typ` header for interaction handles.
+const HANDLE_TYP: &str = "gnap-interaction+jwt";
+/// How long an interaction handle can be redeemed for, in seconds.
+const HANDLE_LIFETIME: u64 = 600;
+/// Where interaction handles are redeemed.
+pub const APP_PATH: &str = "/gnap/app";
+
+/// Claims of an interaction handle.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HandleClaims {
+    iss: String,
+    /// The interaction ID.
+    sub: String,
+    /// Identifies the handle, so it can only be used once.
+    jti: String,
+    iat: u64,
+    exp: u64,
+}
+
+/// Create the app URI for an interaction.
+pub async fn app_uri(service: &Service, interaction_id: &str) -> Result<String, GnapError> {
+    let key = get_signing_key(service, signing_alg()).await?;
+    let handle = encode_handle(interaction_id, &get_as_host(), unix_time() + HANDLE_LIFETIME, &key)?;
+    Ok(format!("{}{}?handle={}", get_as_host(), APP_PATH, handle))
+}
+
+/// Redeem an interaction handle, and get the interaction ID it names.
+///
+/// Handles that are forged, expired, already used, or name an interaction
+/// that has finished are not found.
+pub async fn redeem(service: &Service, handle: &str) -> Result<String, GnapError> {
+    let keys = service.get_signing_keys().await?;
+    let claims = decode_handle(handle, &get_as_host(), &keys).map_err(|err| {
+        error!("Invalid interaction handle: {:?}", err);
+        GnapError::NotFound
+    })?;
+    if !service.use_app_handle(&claims.jti, claims.exp.saturating_sub(unix_time())).await? {
+        error!("Interaction handle {} was already used", &claims.jti);
+        return Err(GnapError::NotFound);
+    }
+    pending_transaction(service, &claims.sub).await?;
+    Ok(claims.sub)
+}
+
+fn encode_handle(interaction_id: &str, issuer: &str, expires_at: u64, key: &SigningKey) -> Result<String, GnapError> {
+    let claims = HandleClaims {
+        iss: issuer.to_owned(),
+        sub: interaction_id.to_owned(),
+        jti: Uuid::new_v4().to_string(),
+        iat: unix_time(),
+        exp: expires_at,
+    };
+    encode_jwt(&claims, HANDLE_TYP, key)
+}
+
+fn decode_handle(handle: &str, issuer: &str, keys: &[SigningKey]) -> Result<HandleClaims, GnapError> {
+    decode_jwt(handle, HANDLE_TYP, issuer, keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::generate_key;
+    use model::key::KeyAlgorithm;
+
+    const ISSUER: &str = "https://as.example";
+
+    #[test]
+    fn interaction_handle() {
+        let key = generate_key(KeyAlgorithm::ES256).unwrap();
+        let keys = vec![key.clone()];
+        let handle = encode_handle("abc", ISSUER, unix_time() + 60, &key).unwrap();
+        let claims = decode_handle(&handle, ISSUER, &keys).expect("handle not decoded");
+        assert_eq!(claims.sub, "abc");
+
+        // Every handle is distinct, so each can be used once.
+        let other = encode_handle("abc", ISSUER, unix_time() + 60, &key).unwrap();
+        assert_ne!(decode_handle(&other, ISSUER, &keys).unwrap().jti, claims.jti);
+
+        assert!(decode_handle(&handle, "https://other.example", &keys).is_err());
+        assert!(decode_handle(&handle, ISSUER, &[generate_key(KeyAlgorithm::ES256).unwrap()]).is_err());
+
+        let (signed, _) = handle.rsplit_once('.').unwrap();
+        assert!(decode_handle(&format!("{}.AAAA", signed), ISSUER, &keys).is_err());
+
+        let expired = encode_handle("abc", ISSUER, unix_time() - 60, &key).unwrap();
+        assert!(decode_handle(&expired, ISSUER, &keys).is_err());
+    }
+}
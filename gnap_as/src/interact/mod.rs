@@ -0,0 +1,366 @@
+//! Resource owner interaction.
+//!
+//! Grant requests that need the resource owner are given an interaction URI.
+//! The resource owner signs in there, reviews the request, and approves or
+//! denies it.  Signing in gives the browser a session cookie, so that only
+//! the resource owner who signed in can decide.
+//!
+//! Interactions reached by redeeming an app URI or user code are bound to
+//! the browser that redeemed it with another cookie, so the interaction URI
+//! cannot be reused by anyone else.
+//!
+use crate::{
+    account::{authenticate, Authentication},
+    grant::grant_endpoint,
+    keys::{create_secret, hash_secret, verify_secret},
+};
+use dao::service::Service;
+use url::Url;
+use errors::GnapError;
+use gnap_as::get_as_host;
+use log::{error, trace};
+use model::{
+    grant::{InteractFinish, InteractFinishMethodType},
+    interact::{ConsentDecision, LoginForm},
+    transaction::{GnapTransaction, GnapTransactionState},
+};
+
+pub mod app;
+pub mod pages;
+pub mod push;
+pub mod user_code;
+
+/// Cookie holding the resource owner's session secret.
+pub const SESSION_COOKIE: &str = "gnap_interaction";
+/// Cookie binding an interaction to the browser that redeemed it.
+pub const BROWSER_COOKIE: &str = "gnap_browser";
+
+/// The interaction page for an interaction ID.
+pub fn interaction_path(interaction_id: &str) -> String {
+    format!("/gnap/interact/{}", interaction_id)
+}
+
+/// The interaction page URI handed to the client instance.
+pub fn interaction_uri(interaction_id: &str) -> String {
+    format!("{}{}", get_as_host(), interaction_path(interaction_id))
+}
+
+/// Find the transaction the resource owner is interacting with.
+///
+/// Interactions that are unknown, expired or already decided are not found.
+pub async fn pending_transaction(service: &Service, interaction_id: &str) -> Result<GnapTransaction, GnapError> {
+    match service.get_interaction(interaction_id).await? {
+        Some(tx) if tx.is_pending() => Ok(tx),
+        Some(tx) => {
+            error!("Interaction for transaction {} has already finished", &tx.tx_id);
+            Err(GnapError::NotFound)
+        }
+        None => {
+            error!("Unknown interaction: {}", interaction_id);
+            Err(GnapError::NotFound)
+        }
+    }
+}
+
+/// The outcome of signing in to an interaction.
+#[derive(Debug, Clone)]
+pub enum Login {
+    /// Holds the session secret for the browser.
+    Session(String),
+    Failed,
+    Throttled,
+}
+
+/// Sign the resource owner in to an interaction from `address`.
+///
+/// The interaction must still be pending when the session is saved, so a
+/// decision made meanwhile is not undone.
+pub async fn login(
+    service: &Service,
+    tx: &mut GnapTransaction,
+    address: &str,
+    form: &LoginForm,
+) -> Result<Login, GnapError> {
+    let account = match authenticate(service, address, &form.username, &form.password).await? {
+        Authentication::Authenticated(account) => account,
+        Authentication::Failed => return Ok(Login::Failed),
+        Authentication::Throttled => return Ok(Login::Throttled),
+    };
+    let secret = create_secret()?;
+    let session = hash_secret(&secret);
+    let interaction_id = tx.interaction.as_ref().ok_or(GnapError::NotFound)?.id.clone();
+    let updated = service
+        .modify_transaction(&tx.tx_id, |tx| {
+            check_pending(tx, &interaction_id)?;
+            if let Some(interaction) = tx.interaction.as_mut() {
+                interaction.account_id = Some(account.account_id());
+                interaction.session = Some(session.clone());
+            }
+            tx.state = GnapTransactionState::ResourceOwnerVerified;
+            Ok(())
+        })
+        .await?;
+    *tx = updated.ok_or(GnapError::NotFound)?;
+    trace!("Resource owner {} signed in to transaction {}", account.account_id(), &tx.tx_id);
+    Ok(Login::Session(secret))
+}
+
+/// Bind the interaction to the browser that redeemed an app URI or user
+/// code for it.
+///
+/// Returns the secret for the browser.  Each redemption binds a new browser,
+/// since app URIs and user codes can only be redeemed once.
+pub async fn bind_browser(service: &Service, interaction_id: &str) -> Result<String, GnapError> {
+    let tx = pending_transaction(service, interaction_id).await?;
+    let secret = create_secret()?;
+    let browser = hash_secret(&secret);
+    service
+        .modify_transaction(&tx.tx_id, |tx| {
+            check_pending(tx, interaction_id)?;
+            if let Some(interaction) = tx.interaction.as_mut() {
+                interaction.browser = Some(browser.clone());
+            }
+            Ok(())
+        })
+        .await?
+        .ok_or(GnapError::NotFound)?;
+    trace!("Bound interaction for transaction {} to a browser", &tx.tx_id);
+    Ok(secret)
+}
+
+/// Can the browser open the interaction pages?
+///
+/// Interactions that are not bound to a browser can be opened by anyone
+/// with the interaction URI.
+pub fn is_bound_browser(tx: &GnapTransaction, browser: Option<&str>) -> bool {
+    let interaction = match tx.interaction.as_ref() {
+        Some(interaction) => interaction,
+        None => return false,
+    };
+    if !interaction.bind_browser {
+        return true;
+    }
+    match (browser, interaction.browser.as_deref()) {
+        (Some(browser), Some(browser_hash)) => verify_secret(browser, browser_hash),
+        _ => false,
+    }
+}
+
+/// Has the browser signed in to the interaction?
+pub fn has_session(tx: &GnapTransaction, session: Option<&str>) -> bool {
+    let session_hash = tx
+        .interaction
+        .as_ref()
+        .and_then(|interaction| interaction.session.as_deref());
+    match (session, session_hash) {
+        (Some(session), Some(session_hash)) => verify_secret(session, session_hash),
+        _ => false,
+    }
+}
+
+/// Render the consent page for a signed in resource owner.
+pub async fn consent_page(service: &Service, tx: &GnapTransaction) -> Result<String, GnapError> {
+    let interaction = tx.interaction.as_ref().ok_or(GnapError::NotFound)?;
+    let client = match &tx.client_id {
+        Some(client_id) => service.get_client(client_id).await?,
+        None => None,
+    };
+    let account = match &interaction.account_id {
+        Some(account_id) => service.get_account(account_id).await?,
+        None => None,
+    };
+    let access: Vec<_> = tx
+        .request
+        .iter()
+        .flat_map(|request| request.access_token.iter())
+        .flat_map(|token| token.access.iter())
+        .cloned()
+        .collect();
+    Ok(pages::consent_page(&interaction.id, client.as_ref(), account.as_ref(), &access))
+}
+
+/// Record the resource owner's decision on the transaction.
+///
+/// The decision is only saved if the interaction is still pending and the
+/// browser still holds its session, so concurrent decisions cannot both
+/// win.  The session ends with the decision.  Returns the interaction finish
+/// for the client instance, if it asked to be told when interaction
+/// finishes.
+pub async fn complete_interaction(
+    service: &Service,
+    tx: &mut GnapTransaction,
+    session: Option<&str>,
+    decision: ConsentDecision,
+) -> Result<Option<InteractFinish>, GnapError> {
+    let interaction_id = tx.interaction.as_ref().ok_or(GnapError::NotFound)?.id.clone();
+    let mut finish = None;
+    let updated = service
+        .modify_transaction(&tx.tx_id, |tx| {
+            check_pending(tx, &interaction_id)?;
+            if !has_session(tx, session) {
+                error!("Session for transaction {} has ended", &tx.tx_id);
+                return Err(GnapError::NotFound);
+            }
+            tx.state = match decision {
+                ConsentDecision::Approve => GnapTransactionState::Approved,
+                ConsentDecision::Deny => GnapTransactionState::Denied,
+            };
+            finish = tx.finish_interaction(&grant_endpoint());
+            if let Some(interaction) = tx.interaction.as_mut() {
+                interaction.session = None;
+            }
+            Ok(())
+        })
+        .await?;
+    *tx = updated.ok_or(GnapError::NotFound)?;
+    trace!("Resource owner decided {:?} for transaction {}", decision, &tx.tx_id);
+    Ok(finish)
+}
+
+/// Check, against the latest copy of the transaction, that the interaction
+/// is still pending.
+fn check_pending(tx: &GnapTransaction, interaction_id: &str) -> Result<(), GnapError> {
+    let current = tx.interaction.as_ref().map(|interaction| interaction.id.as_str());
+    if tx.is_pending() && current == Some(interaction_id) {
+        Ok(())
+    } else {
+        error!("Interaction for transaction {} has already finished", &tx.tx_id);
+        Err(GnapError::NotFound)
+    }
+}
+
+/// Where to send the browser when interaction finishes.
+///
+/// Only client instances that asked for the `redirect` finish method are
+/// sent back, and only to a redirect URI registered for the client.
+pub async fn finish_redirect(
+    service: &Service,
+    tx: &GnapTransaction,
+    finish: &InteractFinish,
+) -> Result<Option<String>, GnapError> {
+    let finish_request = match tx.finish_request() {
+        Some(finish_request) => finish_request,
+        None => return Ok(None),
+    };
+    if !matches!(finish_request.method, InteractFinishMethodType::Redirect) {
+        return Ok(None);
+    }
+    let client = match &tx.client_id {
+        Some(client_id) => service.get_client(client_id).await?,
+        None => None,
+    };
+    match client {
+        Some(client) if client.is_redirect_uri(&finish_request.uri) => {
+            finish_redirect_uri(&finish_request.uri, finish).map(Some)
+        }
+        _ => {
+            error!("Finish URI {} is not registered for transaction {}", &finish_request.uri, &tx.tx_id);
+            Ok(None)
+        }
+    }
+}
+
+/// Push the interaction finish to client instances that asked for the
+/// `push` finish method.
+///
+/// Delivery runs in the background, so the resource owner is not kept
+/// waiting on the client instance.
+pub fn finish_push(service: &Service, tx: &GnapTransaction, finish: &InteractFinish) {
+    let finish_request = match tx.finish_request() {
+        Some(finish_request) if matches!(finish_request.method, InteractFinishMethodType::Push) => {
+            finish_request
+        }
+        _ => return,
+    };
+    trace!("Pushing interaction finish for transaction {}", &tx.tx_id);
+    actix_web::rt::spawn(push::deliver(
+        service.clone(),
+        tx.tx_id.clone(),
+        finish_request.uri.clone(),
+        finish.clone(),
+        push::PushPolicy::from_env(),
+    ));
+}
+
+/// Add the interaction finish to the client instance's finish URI.
+pub fn finish_redirect_uri(uri: &str, finish: &InteractFinish) -> Result<String, GnapError> {
+    let mut uri = Url::parse(uri).map_err(|err| {
+        error!("Malformed finish URI {}: {}", uri, err);
+        GnapError::BadData
+    })?;
+    uri.query_pairs_mut()
+        .append_pair("hash", &finish.hash)
+        .append_pair("interact_ref", &finish.interact_ref);
+    Ok(uri.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_uri() {
+        let finish = InteractFinish {
+            hash: "p28jsq0Y2KK3WS__a42tavNC64ldGTBroywsWxT4md_jZQ1R2HZT8BOWYHcLmObM7XHPAdJzTZMtKBsaraJ64A".to_owned(),
+            interact_ref: "4IFWWIKYBC2PQ6U56NL1".to_owned(),
+        };
+        let uri = finish_redirect_uri("https://client.example.net/return/123455", &finish).unwrap();
+        assert_eq!(
+            uri,
+            format!(
+                "https://client.example.net/return/123455?hash={}&interact_ref={}",
+                finish.hash, finish.interact_ref
+            )
+        );
+
+        let uri = finish_redirect_uri("https://client.example.net/return?state=a%20b", &finish).unwrap();
+        assert!(uri.starts_with("https://client.example.net/return?state=a%20b&hash="));
+        assert!(finish_redirect_uri("/return", &finish).is_err());
+    }
+
+    #[test]
+    fn session() {
+        let mut tx = GnapTransaction::new(None);
+        tx.start_interaction();
+        assert!(!has_session(&tx, None));
+
+        let secret = create_secret().unwrap();
+        assert!(!has_session(&tx, Some(&secret)));
+
+        tx.interaction.as_mut().unwrap().session = Some(hash_secret(&secret));
+        assert!(has_session(&tx, Some(&secret)));
+        assert!(!has_session(&tx, Some("guess")));
+        assert!(!has_session(&tx, None));
+    }
+
+    #[test]
+    fn browser_binding() {
+        let mut tx = GnapTransaction::new(None);
+        tx.start_interaction();
+        assert!(is_bound_browser(&tx, None));
+
+        tx.interaction.as_mut().unwrap().bind_browser = true;
+        assert!(!is_bound_browser(&tx, None));
+        assert!(!is_bound_browser(&tx, Some("guess")));
+
+        let secret = create_secret().unwrap();
+        tx.interaction.as_mut().unwrap().browser = Some(hash_secret(&secret));
+        assert!(is_bound_browser(&tx, Some(&secret)));
+        assert!(!is_bound_browser(&tx, Some("guess")));
+        assert!(!is_bound_browser(&tx, None));
+    }
+
+    #[test]
+    fn pending_interaction() {
+        let mut tx = GnapTransaction::new(None);
+        let interaction_id = tx.start_interaction().id.clone();
+        assert!(check_pending(&tx, &interaction_id).is_ok());
+        assert!(check_pending(&tx, "another").is_err());
+
+        tx.state = GnapTransactionState::ResourceOwnerVerified;
+        assert!(check_pending(&tx, &interaction_id).is_ok());
+
+        tx.state = GnapTransactionState::Denied;
+        assert!(check_pending(&tx, &interaction_id).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! HTML pages shown to the resource owner.
+//!
+use super::{interaction_path, user_code::USER_CODE_PATH};
+use model::{
+    account::Account,
+    client::{is_web_uri, GnapClient},
+    grant::AccessRequest,
+    interact::ConsentDecision,
+};
+
+/// Ask the resource owner to sign in.
+pub fn login_page(interaction_id: &str, message: Option<&str>) -> String {
+    let message = match message {
+        Some(message) => format!("<p class=\"error\">{}</p>", escape(message)),
+        None => String::new(),
+    };
+    let body = format!(
+        r#"<h1>Sign in</h1>
+    {}
+    <form method="post" action="{}/login">
+        <label>Username <input name="username" autocomplete="username" required /></label>
+        <label>Password <input name="password" type="password" autocomplete="current-password" required /></label>
+        <button type="submit">Sign in</button>
+    </form>"#,
+        message,
+        interaction_path(interaction_id)
+    );
+    page("Sign in", &body)
+}
+
+/// Ask the resource owner for the user code the client instance showed them.
+pub fn user_code_page(message: Option<&str>) -> String {
+    let message = match message {
+        Some(message) => format!("<p class=\"error\">{}</p>", escape(message)),
+        None => String::new(),
+    };
+    let body = format!(
+        r#"<h1>Connect a device</h1>
+    {}
+    <p>Enter the code shown by the application.</p>
+    <form method="post" action="{}">
+        <label>Code <input name="code" autocomplete="off" autocapitalize="characters" spellcheck="false" required /></label>
+        <button type="submit">Continue</button>
+    </form>"#,
+        message, USER_CODE_PATH
+    );
+    page("Enter code", &body)
+}
+
+/// Ask the resource owner to approve or deny the access a client instance
+/// requested.
+///
+/// The client logo and URI are only shown if they are http or https URIs.
+pub fn consent_page(
+    interaction_id: &str,
+    client: Option<&GnapClient>,
+    account: Option<&Account>,
+    access: &[AccessRequest],
+) -> String {
+    let name = escape(&client_name(client));
+    let logo = client
+        .and_then(|client| client.logo_uri.as_ref())
+        .filter(|logo_uri| is_web_uri(logo_uri))
+        .map(|logo_uri| format!("<img src=\"{}\" alt=\"\" height=\"64\" />", escape(logo_uri)))
+        .unwrap_or_default();
+    let uri = client
+        .and_then(|client| client.client_uri.as_ref())
+        .filter(|uri| is_web_uri(uri))
+        .map(|uri| format!("<p><a href=\"{0}\">{0}</a></p>", escape(uri)))
+        .unwrap_or_default();
+    let account = account
+        .map(|account| format!("<p>Signed in as {}</p>", escape(account.name())))
+        .unwrap_or_default();
+    let items: String = access
+        .iter()
+        .map(|access| format!("\n        <li>{}</li>", escape(&describe_access(access))))
+        .collect();
+    let body = format!(
+        r#"{}
+    <h1>Authorize {}</h1>
+    {}
+    {}
+    <p>{} is requesting access to:</p>
+    <ul>{}
+    </ul>
+    <form method="post" action="{}/consent">
+        <button type="submit" name="decision" value="approve">Approve</button>
+        <button type="submit" name="decision" value="deny">Deny</button>
+    </form>"#,
+        logo,
+        name,
+        uri,
+        account,
+        name,
+        items,
+        interaction_path(interaction_id)
+    );
+    page("Authorize", &body)
+}
+
+/// Tell the resource owner the decision was recorded.
+pub fn done_page(decision: ConsentDecision) -> String {
+    let message = match decision {
+        ConsentDecision::Approve => "You approved the request.",
+        ConsentDecision::Deny => "You denied the request.",
+    };
+    let body = format!("<h1>Done</h1>\n    <p>{} You can return to the application.</p>", message);
+    page("Done", &body)
+}
+
+pub fn error_page(message: &str) -> String {
+    let body = format!("<h1>Something went wrong</h1>\n    <p>{}</p>", escape(message));
+    page("Error", &body)
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+
+<head>
+    <meta charset="utf-8" />
+    <meta name="viewport" content="width=device-width, initial-scale=1" />
+    <meta http-equiv="Cache-Control" content="no-cache, no-store, must-revalidate" />
+    <title>GNAP - {}</title>
+</head>
+
+<body>
+    {}
+</body>
+
+</html>
+"#,
+        escape(title),
+        body
+    )
+}
+
+fn client_name(client: Option<&GnapClient>) -> String {
+    match client {
+        Some(client) if !client.client_name.is_empty() => client.client_name.clone(),
+        Some(GnapClient { class_id: Some(class_id), .. }) => class_id.clone(),
+        _ => "An application".to_owned(),
+    }
+}
+
+/// Describe a requested access right.
+fn describe_access(access: &AccessRequest) -> String {
+    match access {
+        AccessRequest::Reference(reference) => reference.clone(),
+        AccessRequest::Value {
+            resource_type,
+            actions,
+            locations,
+            data_types,
+        } => {
+            let mut description = resource_type.clone();
+            if let Some(actions) = actions {
+                description.push_str(&format!(": {}", actions.join(", ")));
+            }
+            if let Some(locations) = locations {
+                description.push_str(&format!(" at {}", locations.join(", ")));
+            }
+            if let Some(data_types) = data_types {
+                description.push_str(&format!(" ({})", data_types.join(", ")));
+            }
+            description
+        }
+    }
+}
+
+/// Escape text for HTML content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consent_lists_access() {
+        let mut client = GnapClient::new(Vec::new(), "<My App>".to_owned());
+        client.logo_uri = Some("https://app.example/logo.png".to_owned());
+        let access = vec![
+            AccessRequest::Reference("foo".to_owned()),
+            AccessRequest::Value {
+                resource_type: "photos".to_owned(),
+                actions: Some(vec!["read".to_owned(), "write".to_owned()]),
+                locations: Some(vec!["https://rs.example/photos".to_owned()]),
+                data_types: None,
+            },
+        ];
+        let page = consent_page("abc", Some(&client), None, &access);
+        assert!(page.contains("Authorize &lt;My App&gt;"));
+        assert!(!page.contains("<My App>"));
+        assert!(page.contains("<img src=\"https://app.example/logo.png\""));
+        assert!(page.contains("<li>foo</li>"));
+        assert!(page.contains("<li>photos: read, write at https://rs.example/photos</li>"));
+        assert!(page.contains("action=\"/gnap/interact/abc/consent\""));
+
+        let page = consent_page("abc", None, None, &access);
+        assert!(page.contains("Authorize An application"));
+    }
+
+    #[test]
+    fn consent_links_web_uris_only() {
+        let mut client = GnapClient::new(Vec::new(), "app".to_owned());
+        client.client_uri = Some("javascript:alert(document.cookie)".to_owned());
+        client.logo_uri = Some("data:image/svg+xml,<svg/>".to_owned());
+        let page = consent_page("abc", Some(&client), None, &[]);
+        assert!(!page.contains("javascript:"));
+        assert!(!page.contains("data:"));
+
+        client.client_uri = Some("https://app.example".to_owned());
+        let page = consent_page("abc", Some(&client), None, &[]);
+        assert!(page.contains("<a href=\"https://app.example\">"));
+    }
+}
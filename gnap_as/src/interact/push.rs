@@ -0,0 +1,245 @@
+//! Push interaction finish.
+//!
+//! Client instances that ask for the `push` finish method are sent the
+//! interaction finish by the AS, as a JSON POST to their finish URI.  Failed
+//! deliveries are retried with exponential backoff, and the outcome is
+//! recorded on the transaction.  Deliveries only go to https URIs on public
+//! addresses, and redirects are not followed.
+//!
+use crate::outbound::public_target;
+use actix_web::rt::time::sleep;
+use dao::service::Service;
+use errors::GnapError;
+use log::{error, trace};
+use model::{grant::InteractFinish, transaction::PushOutcome, unix_time};
+use std::env;
+use std::time::Duration;
+
+/// Default number of delivery attempts.
+const DEFAULT_PUSH_ATTEMPTS: u32 = 4;
+/// Default wait before the first retry, in milliseconds.  The wait doubles
+/// on each retry.
+const DEFAULT_PUSH_BACKOFF: u64 = 1000;
+/// How long to wait for the client instance to respond to a delivery.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How hard to try to deliver an interaction finish.
+#[derive(Debug, Clone)]
+pub struct PushPolicy {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+impl PushPolicy {
+    /// Get the push policy from ENV.
+    pub fn from_env() -> Self {
+        let attempts = env::var("GNAP_PUSH_ATTEMPTS")
+            .ok()
+            .and_then(|attempts| attempts.parse().ok())
+            .unwrap_or(DEFAULT_PUSH_ATTEMPTS);
+        let backoff = env::var("GNAP_PUSH_BACKOFF")
+            .ok()
+            .and_then(|backoff| backoff.parse().ok())
+            .unwrap_or(DEFAULT_PUSH_BACKOFF);
+        Self {
+            attempts: attempts.max(1),
+            backoff: Duration::from_millis(backoff),
+        }
+    }
+}
+
+/// Push the interaction finish to the client instance, and record the
+/// outcome on the transaction.
+pub async fn deliver(service: Service, tx_id: String, uri: String, finish: InteractFinish, policy: PushPolicy) {
+    let outcome = push(&uri, &finish, &policy).await;
+    if let Err(err) = record(&service, &tx_id, outcome).await {
+        error!("Could not record push outcome for transaction {}: {:?}", &tx_id, err);
+    }
+}
+
+/// Push the interaction finish, retrying failed deliveries.
+///
+/// Any 2xx response is a delivery.  The outcome describes the last attempt.
+/// Finish URIs that are not public https URIs are not tried at all.
+pub async fn push(uri: &str, finish: &InteractFinish, policy: &PushPolicy) -> PushOutcome {
+    let client = match public_target(uri).await.and_then(|target| target.client(PUSH_TIMEOUT)) {
+        Ok(client) => client,
+        Err(err) => {
+            error!("Not pushing to {}: {:?}", uri, err);
+            return PushOutcome {
+                error: Some("finish URI is not a public https URI".to_owned()),
+                finished_at: unix_time(),
+                ..Default::default()
+            };
+        }
+    };
+    send(&client, uri, finish, policy).await
+}
+
+async fn send(client: &reqwest::Client, uri: &str, finish: &InteractFinish, policy: &PushPolicy) -> PushOutcome {
+    let mut outcome = PushOutcome::default();
+    let mut backoff = policy.backoff;
+    for attempt in 1..=policy.attempts {
+        outcome.attempts = attempt;
+        match client.post(uri).json(finish).send().await {
+            Ok(response) if response.status().is_success() => {
+                trace!("Pushed interaction finish to {}", uri);
+                outcome.delivered = true;
+                outcome.status = Some(response.status().as_u16());
+                outcome.error = None;
+                break;
+            }
+            Ok(response) => {
+                error!("Push to {} failed with {}", uri, response.status());
+                outcome.status = Some(response.status().as_u16());
+                outcome.error = Some(format!("client instance responded with {}", response.status()));
+            }
+            Err(err) => {
+                error!("Push to {} failed: {}", uri, err);
+                outcome.status = None;
+                outcome.error = Some(err.to_string());
+            }
+        }
+        if attempt < policy.attempts {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    outcome.finished_at = unix_time();
+    outcome
+}
+
+/// Record a push outcome, without undoing any continuation the client
+/// instance made after it was pushed the interaction finish.
+async fn record(service: &Service, tx_id: &str, outcome: PushOutcome) -> Result<(), GnapError> {
+    let tx = service
+        .modify_transaction(tx_id, |tx| {
+            if let Some(interaction) = tx.interaction.as_mut() {
+                interaction.push = Some(outcome.clone());
+            }
+            Ok(())
+        })
+        .await?;
+    if tx.is_none() {
+        error!("Transaction {} expired before its push outcome was recorded", tx_id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    /// Receive one HTTP request per status on a local port, and answer each
+    /// with the status.  The request bodies are sent to the receiver.
+    fn receiver(statuses: &[&str]) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/push", listener.local_addr().unwrap());
+        let statuses: Vec<String> = statuses.iter().map(|status| status.to_string()).collect();
+        let (sender, bodies) = channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                sender.send(read_body(&mut stream)).unwrap();
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (uri, bodies)
+    }
+
+    fn read_body(stream: &mut impl Read) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let read = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse().ok())
+                    .unwrap_or(0);
+                if body.len() >= length || read == 0 {
+                    return body.to_owned();
+                }
+            }
+            if read == 0 {
+                return String::new();
+            }
+        }
+    }
+
+    fn finish() -> InteractFinish {
+        InteractFinish {
+            hash: "abc".to_owned(),
+            interact_ref: "4IFWWIKYBC2PQ6U56NL1".to_owned(),
+        }
+    }
+
+    fn policy(attempts: u32) -> PushPolicy {
+        PushPolicy {
+            attempts,
+            backoff: Duration::from_millis(10),
+        }
+    }
+
+    fn local_client() -> reqwest::Client {
+        outbound::client(PUSH_TIMEOUT).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn push_with_retry() {
+        let (uri, bodies) = receiver(&["503 Service Unavailable", "200 OK"]);
+        let outcome = send(&local_client(), &uri, &finish(), &policy(3)).await;
+        assert!(outcome.delivered);
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.status, Some(200));
+        assert_eq!(outcome.error, None);
+
+        for _ in 0..2 {
+            let body: InteractFinish = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+            assert_eq!(body, finish());
+        }
+    }
+
+    #[actix_web::test]
+    async fn push_failure() {
+        let (uri, _bodies) = receiver(&["500 Internal Server Error", "404 Not Found"]);
+        let outcome = send(&local_client(), &uri, &finish(), &policy(2)).await;
+        assert!(!outcome.delivered);
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.status, Some(404));
+        assert!(outcome.error.is_some());
+    }
+
+    #[actix_web::test]
+    async fn push_redirect_refused() {
+        let (uri, _bodies) = receiver(&["307 Temporary Redirect\r\nlocation: http://169.254.169.254/"]);
+        let outcome = send(&local_client(), &uri, &finish(), &policy(1)).await;
+        assert!(!outcome.delivered);
+        assert_eq!(outcome.status, Some(307));
+    }
+
+    #[actix_web::test]
+    async fn push_to_local_address_refused() {
+        let (uri, bodies) = receiver(&["200 OK"]);
+        let outcome = push(&uri, &finish(), &policy(1)).await;
+        assert!(!outcome.delivered);
+        assert_eq!(outcome.attempts, 0);
+        assert!(outcome.error.is_some());
+
+        let https_uri = uri.replacen("http:", "https:", 1);
+        let outcome = push(&https_uri, &finish(), &policy(1)).await;
+        assert!(!outcome.delivered);
+        assert_eq!(outcome.attempts, 0);
+        assert!(bodies.try_recv().is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! User code interaction.
+//!
+//! Client instances that cannot send the resource owner to a URI can show
+//! them a short code instead.  The resource owner enters the code at a
+//! stable URI on the AS, and is taken on to the interaction pages.
+//!
+//! Codes are made to be read and typed by people: they only use consonants,
+//! and ignore case and dashes.  Each code can be used once, and expires.
+//! Entered codes are counted, and a client that enters too many is turned
+//! away for a while.
+//!
+use crate::keys::crypto_error;
+use dao::service::Service;
+use errors::GnapError;
+use gnap_as::get_as_host;
+use log::{error, trace};
+use model::{grant::UserCode, transaction::GnapTransaction};
+use openssl::rand::rand_bytes;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+
+/// Characters user codes are made of.  Vowels are left out, so codes do
+/// not spell words, along with characters that are easily confused.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+/// Default lifetime of a user code, in seconds.
+const DEFAULT_USER_CODE_LIFETIME: u64 = 600;
+/// Number of codes a client can enter in the guess window.
+const MAX_USER_CODE_GUESSES: u64 = 5;
+/// How long entered codes are counted for, in seconds.
+const USER_CODE_GUESS_WINDOW: u64 = 300;
+const USER_CODE_ATTEMPTS: &str = "user_codes";
+
+/// The outcome of entering a user code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redemption {
+    /// The code was found.  Holds the interaction ID.
+    Interaction(String),
+    /// The code is unknown, expired or already used.
+    Unknown,
+    /// The client has entered too many codes.
+    Throttled,
+}
+
+/// Get how long, in seconds, a user code can be used for from ENV.
+pub fn user_code_lifetime() -> u64 {
+    env::var("GNAP_USER_CODE_LIFETIME")
+        .ok()
+        .and_then(|lifetime| lifetime.parse().ok())
+        .unwrap_or(DEFAULT_USER_CODE_LIFETIME)
+}
+
+/// Get how many reverse proxies the AS runs behind from ENV.  Each one is
+/// trusted to append the address it was reached from to
+/// `X-Forwarded-For`.
+pub fn trusted_proxies() -> usize {
+    env::var("GNAP_TRUSTED_PROXIES")
+        .ok()
+        .and_then(|proxies| proxies.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The address user code guesses are counted against.
+///
+/// Behind `proxies` trusted reverse proxies, this is the address the
+/// outermost proxy was reached from.  Entries further left in
+/// `X-Forwarded-For` are sent by the client, and are not trusted.  Without
+/// proxies, or when the header is missing, it is the peer address.
+pub fn guess_address(peer: Option<IpAddr>, forwarded_for: Option<&str>, proxies: usize) -> String {
+    let forwarded = match (proxies, forwarded_for) {
+        (0, _) | (_, None) => None,
+        (proxies, Some(forwarded_for)) => {
+            let hops: Vec<&str> = forwarded_for.split(',').map(|hop| hop.trim()).collect();
+            hops.len()
+                .checked_sub(proxies)
+                .and_then(|index| parse_address(hops[index]))
+        }
+    };
+    forwarded
+        .or(peer)
+        .map(|ip| ip.to_string())
+        .unwrap_or_default()
+}
+
+fn parse_address(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The page where the resource owner enters a user code.
+pub const USER_CODE_PATH: &str = "/gnap/device";
+
+/// The stable URI handed to the client instance with each user code.
+pub fn user_code_uri() -> String {
+    format!("{}{}", get_as_host(), USER_CODE_PATH)
+}
+
+/// Give a transaction a user code.
+///
+/// The code is saved on the transaction interaction, which the caller
+/// saves.
+pub async fn issue(service: &Service, tx: &mut GnapTransaction) -> Result<UserCode, GnapError> {
+    let interaction = tx.interaction.as_mut().ok_or(GnapError::GeneralError)?;
+    let mut code = generate_code()?;
+    // A code already in use by another transaction is left alone, and
+    // another code is generated until a free one is found.
+    while !service.add_user_code(&code, &tx.tx_id, user_code_lifetime()).await? {
+        trace!("User code already in use, generating another");
+        code = generate_code()?;
+    }
+    interaction.user_code = Some(code.clone());
+    Ok(UserCode {
+        code: display_code(&code),
+        uri: user_code_uri(),
+    })
+}
+
+/// Find the interaction for a user code entered by the resource owner.
+///
+/// `client` identifies who entered the code, for throttling guesses.  The
+/// code is counted before it is looked up, so parallel guesses are counted
+/// too.
+pub async fn redeem(service: &Service, client: &str, code: &str) -> Result<Redemption, GnapError> {
+    let guesses = service
+        .count_attempt(USER_CODE_ATTEMPTS, client, USER_CODE_GUESS_WINDOW)
+        .await?;
+    if guesses > MAX_USER_CODE_GUESSES {
+        error!("Too many user codes from {}", client);
+        return Ok(Redemption::Throttled);
+    }
+    let code = normalize_code(code);
+    let tx = match code.len() {
+        USER_CODE_LENGTH => service.take_user_code(&code).await?,
+        _ => None,
+    };
+    match tx.filter(|tx| tx.is_pending()).and_then(|tx| tx.interaction) {
+        Some(interaction) => Ok(Redemption::Interaction(interaction.id)),
+        None => {
+            error!("Wrong user code from {}", client);
+            Ok(Redemption::Unknown)
+        }
+    }
+}
+
+/// Generate a random user code.
+pub fn generate_code() -> Result<String, GnapError> {
+    // Only bytes below the largest multiple of the alphabet size are used,
+    // so every character is equally likely.
+    let limit = 256 - 256 % USER_CODE_ALPHABET.len();
+    let mut code = String::with_capacity(USER_CODE_LENGTH);
+    let mut random = [0u8; 16];
+    while code.len() < USER_CODE_LENGTH {
+        rand_bytes(&mut random).map_err(crypto_error)?;
+        for byte in random.iter().map(|byte| *byte as usize).filter(|byte| *byte < limit) {
+            if code.len() == USER_CODE_LENGTH {
+                break;
+            }
+            code.push(USER_CODE_ALPHABET[byte % USER_CODE_ALPHABET.len()] as char);
+        }
+    }
+    Ok(code)
+}
+
+/// Normalize a user code as entered by the resource owner.
+///
+/// Case is ignored, and anything that is not a code character, such as
+/// dashes and spaces, is dropped.
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .map(|c| c.to_ascii_uppercase())
+        .filter(|c| c.is_ascii() && USER_CODE_ALPHABET.contains(&(*c as u8)))
+        .collect()
+}
+
+/// Format a user code for display, in two dashed halves.
+pub fn display_code(code: &str) -> String {
+    let (first, second) = code.split_at(code.len() / 2);
+    format!("{}-{}", first, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_codes() {
+        let code = generate_code().unwrap();
+        assert_eq!(code.len(), USER_CODE_LENGTH);
+        assert!(code.bytes().all(|c| USER_CODE_ALPHABET.contains(&c)));
+        assert_ne!(generate_code().unwrap(), code);
+
+        let display = display_code(&code);
+        assert_eq!(display.len(), USER_CODE_LENGTH + 1);
+        assert_eq!(&display[4..5], "-");
+        assert_eq!(normalize_code(&display), code);
+        assert_eq!(normalize_code(&format!(" {} ", display.to_lowercase())), code);
+        assert_eq!(normalize_code("wdjb-mjht"), "WDJBMJHT");
+    }
+
+    #[test]
+    fn guess_addresses() {
+        let peer = Some("10.0.0.2".parse().unwrap());
+        assert_eq!(guess_address(peer, None, 0), "10.0.0.2");
+        assert_eq!(guess_address(peer, Some("203.0.113.7"), 0), "10.0.0.2");
+        assert_eq!(guess_address(peer, None, 1), "10.0.0.2");
+        assert_eq!(guess_address(None, None, 0), "");
+
+        assert_eq!(guess_address(peer, Some("203.0.113.7"), 1), "203.0.113.7");
+        // Entries the client sent itself are ignored.
+        assert_eq!(guess_address(peer, Some("198.51.100.1, 203.0.113.7"), 1), "203.0.113.7");
+        assert_eq!(guess_address(peer, Some("198.51.100.1, 203.0.113.7, 10.0.0.9"), 2), "203.0.113.7");
+        assert_eq!(guess_address(peer, Some("[2001:db8::1]:4711"), 1), "2001:db8::1");
+
+        assert_eq!(guess_address(peer, Some("203.0.113.7"), 2), "10.0.0.2");
+        assert_eq!(guess_address(peer, Some("unknown"), 1), "10.0.0.2");
+    }
+}
@@ -0,0 +1,163 @@
+//! Keys of registered clients.
+//!
+//! A registered client key either carries its own JWK, or is found by key ID
+//! in the client key set.  The key set is held by the AS, or fetched from the
+//! client `jwks_uri`.  Fetched key sets are cached, and fetched again when a
+//! request names a key that is not in the cached set, at most once every
+//! [JWKS_REFETCH_INTERVAL] seconds per client.
+//!
+use crate::outbound;
+use crate::proof::{invalid_client, ProofRequest};
+use dao::service::Service;
+use errors::GnapError;
+use log::{error, trace};
+use model::{
+    client::GnapClient,
+    key::{ClientKey, Jwk, Jwks},
+};
+use std::time::Duration;
+
+/// Seconds before a client key set can be fetched again.
+const JWKS_REFETCH_INTERVAL: u64 = 60;
+/// How long to wait for a key set.
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Get the key a request from a registered client must be proven with.
+pub async fn client_key(
+    service: &Service,
+    client: &GnapClient,
+    proof: &ProofRequest<'_>,
+) -> Result<Option<ClientKey>, GnapError> {
+    let key = match &client.key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    if key.jwk.is_some() || (client.jwks.is_none() && client.jwks_uri.is_none()) {
+        return Ok(Some(key.clone()));
+    }
+
+    let kid = proof.key_id(key.proof);
+    match registered_jwk(service, client, kid.as_deref()).await? {
+        Some(jwk) => Ok(Some(ClientKey {
+            jwk: Some(jwk),
+            ..key.clone()
+        })),
+        None => {
+            error!("Client {} has no key {:?}", client.client_id, kid);
+            Err(invalid_client())
+        }
+    }
+}
+
+/// Find a key in the client key set.
+async fn registered_jwk(
+    service: &Service,
+    client: &GnapClient,
+    kid: Option<&str>,
+) -> Result<Option<Jwk>, GnapError> {
+    if let Some(jwk) = client.jwks.as_ref().and_then(|jwks| jwks.find(kid)) {
+        return Ok(Some(jwk.clone()));
+    }
+    let jwks_uri = match &client.jwks_uri {
+        Some(jwks_uri) => jwks_uri,
+        None => return Ok(None),
+    };
+    if let Some(jwks) = service.get_client_jwks(&client.client_id).await? {
+        if let Some(jwk) = jwks.find(kid) {
+            return Ok(Some(jwk.clone()));
+        }
+        trace!("Key {:?} is not in the cached key set, fetching it again", kid);
+    }
+    if !service.allow_jwks_refetch(&client.client_id, JWKS_REFETCH_INTERVAL).await? {
+        error!("Key set for client {} was fetched too recently", client.client_id);
+        return Ok(None);
+    }
+    let jwks = fetch_jwks(jwks_uri).await?;
+    service.set_client_jwks(&client.client_id, &jwks).await?;
+    Ok(jwks.find(kid).cloned())
+}
+
+/// Fetch a key set from a `jwks_uri`.  Only https URIs on public addresses
+/// are fetched.
+pub async fn fetch_jwks(jwks_uri: &str) -> Result<Jwks, GnapError> {
+    let target = outbound::public_target(jwks_uri).await.map_err(|_| invalid_client())?;
+    get_jwks(&target.client(JWKS_TIMEOUT)?, target.uri.as_str()).await
+}
+
+async fn get_jwks(client: &reqwest::Client, jwks_uri: &str) -> Result<Jwks, GnapError> {
+    trace!("Fetching key set from {}", jwks_uri);
+    let response = client
+        .get(jwks_uri)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| {
+            error!("Could not fetch {}: {}", jwks_uri, err);
+            invalid_client()
+        })?;
+    response.json::<Jwks>().await.map_err(|err| {
+        error!("Malformed key set at {}: {}", jwks_uri, err);
+        invalid_client()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serve a single HTTP response on a local port, and return the URI.
+    fn stand_in(status: &str, body: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/jwks", listener.local_addr().unwrap());
+        let response = format!(
+            "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        uri
+    }
+
+    /// The stand-ins are local, so they are reached without the public
+    /// address checks.
+    fn local_client() -> reqwest::Client {
+        outbound::client(JWKS_TIMEOUT).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn fetch_key_set() {
+        let uri = stand_in(
+            "200 OK",
+            r#"{"keys": [{"kty": "OKP", "kid": "k1", "crv": "Ed25519", "x": "abc"}]}"#,
+        );
+        let jwks = get_jwks(&local_client(), &uri).await.expect("key set not fetched");
+        assert_eq!(jwks.find(Some("k1")).and_then(|jwk| jwk.x.as_deref()), Some("abc"));
+    }
+
+    #[actix_web::test]
+    async fn fetch_failures() {
+        let uri = stand_in("404 Not Found", "");
+        assert!(get_jwks(&local_client(), &uri).await.is_err());
+
+        let uri = stand_in("200 OK", r#"{"not": "a key set"}"#);
+        assert!(get_jwks(&local_client(), &uri).await.is_err());
+
+        // Redirects are not followed.
+        let uri = stand_in("302 Found\r\nlocation: http://127.0.0.1:1/jwks", "");
+        assert!(get_jwks(&local_client(), &uri).await.is_err());
+
+        // Key sets are not fetched from the local network.
+        let uri = stand_in("200 OK", r#"{"keys": []}"#);
+        assert!(fetch_jwks(&uri).await.is_err());
+        assert!(fetch_jwks(&uri.replace("http:", "https:")).await.is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! Conversion between JWKs and crypto library keys.
+//!
+use super::crypto_error;
+use errors::GnapError;
+use log::error;
+use model::key::Jwk;
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    nid::Nid,
+    pkey::{Id, PKey, Public},
+    rsa::Rsa,
+};
+
+/// Get the public key described by a JWK.
+pub fn public_key(jwk: &Jwk) -> Result<PKey<Public>, GnapError> {
+    match jwk.kty.as_str() {
+        "RSA" => {
+            let n = member(&jwk.n, "n")?;
+            let e = member(&jwk.e, "e")?;
+            let rsa = Rsa::from_public_components(
+                BigNum::from_slice(&n).map_err(crypto_error)?,
+                BigNum::from_slice(&e).map_err(crypto_error)?,
+            )
+            .map_err(crypto_error)?;
+            PKey::from_rsa(rsa).map_err(crypto_error)
+        }
+        "EC" => {
+            let nid = match jwk.crv.as_deref() {
+                Some("P-256") => Nid::X9_62_PRIME256V1,
+                Some("P-384") => Nid::SECP384R1,
+                crv => return Err(unsupported(&format!("EC curve {:?}", crv))),
+            };
+            let group = EcGroup::from_curve_name(nid).map_err(crypto_error)?;
+            let x = BigNum::from_slice(&member(&jwk.x, "x")?).map_err(crypto_error)?;
+            let y = BigNum::from_slice(&member(&jwk.y, "y")?).map_err(crypto_error)?;
+            let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y).map_err(crypto_error)?;
+            PKey::from_ec_key(ec).map_err(crypto_error)
+        }
+        "OKP" => match jwk.crv.as_deref() {
+            Some("Ed25519") => {
+                let x = member(&jwk.x, "x")?;
+                PKey::public_key_from_raw_bytes(&x, Id::ED25519).map_err(crypto_error)
+            }
+            crv => Err(unsupported(&format!("OKP curve {:?}", crv))),
+        },
+        kty => Err(unsupported(&format!("key type {}", kty))),
+    }
+}
+
+/// Describe a public key as a JWK.
+pub fn public_jwk<T: openssl::pkey::HasPublic>(pkey: &PKey<T>) -> Result<Jwk, GnapError> {
+    let mut jwk = Jwk {
+        kty: String::new(),
+        kid: None,
+        alg: None,
+        key_use: None,
+        crv: None,
+        x: None,
+        y: None,
+        n: None,
+        e: None,
+    };
+    match pkey.id() {
+        Id::RSA => {
+            let rsa = pkey.rsa().map_err(crypto_error)?;
+            jwk.kty = "RSA".to_owned();
+            jwk.n = Some(encode(&rsa.n().to_vec()));
+            jwk.e = Some(encode(&rsa.e().to_vec()));
+        }
+        Id::EC => {
+            let ec = pkey.ec_key().map_err(crypto_error)?;
+            let (crv, size) = match ec.group().curve_name() {
+                Some(Nid::X9_62_PRIME256V1) => ("P-256", 32),
+                Some(Nid::SECP384R1) => ("P-384", 48),
+                _ => return Err(unsupported("EC curve")),
+            };
+            let mut x = BigNum::new().map_err(crypto_error)?;
+            let mut y = BigNum::new().map_err(crypto_error)?;
+            let mut ctx = BigNumContext::new().map_err(crypto_error)?;
+            ec.public_key()
+                .affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx)
+                .map_err(crypto_error)?;
+            jwk.kty = "EC".to_owned();
+            jwk.crv = Some(crv.to_owned());
+            jwk.x = Some(encode(&x.to_vec_padded(size).map_err(crypto_error)?));
+            jwk.y = Some(encode(&y.to_vec_padded(size).map_err(crypto_error)?));
+        }
+        Id::ED25519 => {
+            jwk.kty = "OKP".to_owned();
+            jwk.crv = Some("Ed25519".to_owned());
+            jwk.x = Some(encode(&pkey.raw_public_key().map_err(crypto_error)?));
+        }
+        _ => return Err(unsupported("key type")),
+    }
+    Ok(jwk)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn member(value: &Option<String>, name: &str) -> Result<Vec<u8>, GnapError> {
+    let value = value.as_ref().ok_or_else(|| {
+        error!("JWK is missing {}", name);
+        GnapError::CryptoError(format!("JWK is missing {}", name))
+    })?;
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD).map_err(crypto_error)
+}
+
+fn unsupported(what: &str) -> GnapError {
+    error!("Unsupported JWK {}", what);
+    GnapError::CryptoError(format!("unsupported JWK {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let rsa = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let ec = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let ed = PKey::generate_ed25519().unwrap();
+
+        for pkey in [rsa, ec, ed].iter() {
+            let jwk = public_jwk(pkey).expect("no jwk");
+            let public = public_key(&jwk).expect("no key");
+            assert!(public.public_eq(pkey));
+        }
+    }
+
+    #[test]
+    fn unsupported_keys() {
+        let jwk: Jwk = serde_json::from_str(r#"{"kty": "oct", "k": "abc"}"#).unwrap();
+        assert!(public_key(&jwk).is_err());
+        let jwk: Jwk = serde_json::from_str(r#"{"kty": "EC", "crv": "P-521", "x": "a", "y": "b"}"#).unwrap();
+        assert!(public_key(&jwk).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! AS managed signing keys.
+//!
+use actix_web::rt::time::sleep;
+use dao::service::Service;
+use errors::GnapError;
+use log::{error, trace};
+use crate::token::token_lifetime;
+use model::{
+    key::{Jwks, KeyAlgorithm, SigningKey},
+    unix_time,
+};
+use openssl::{
+    ec::{EcGroup, EcKey},
+    nid::Nid,
+    pkey::PKey,
+    memcmp,
+    rand::rand_bytes,
+    rsa::Rsa,
+    sha::sha256,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub mod client;
+pub mod jwk;
+
+/// Default age of a signing key when it is rotated, in seconds.
+const DEFAULT_KEY_ROTATION_AGE: u64 = 30 * 24 * 3600;
+/// How long an AS instance may hold the lock to replace a signing key, in
+/// seconds.
+const KEY_LOCK_TTL: u64 = 10;
+/// How long to wait for another AS instance to replace a signing key.
+const KEY_LOCK_WAIT: Duration = Duration::from_millis(100);
+/// How many times to wait before giving up.
+const KEY_LOCK_ATTEMPTS: u32 = 100;
+/// Marks a private key that is encrypted at rest.
+const SEALED_PREFIX: &str = "sealed:";
+
+/// Get the algorithm used to sign JWT access tokens from ENV.
+///
+/// Defaults to RS256.  Only asymmetric JWS algorithms are supported, since
+/// RSs must be able to verify tokens with the AS public key.
+pub fn signing_alg() -> KeyAlgorithm {
+    let alg = env::var("GNAP_TOKEN_SIGNING_ALG")
+        .ok()
+        .and_then(|alg| KeyAlgorithm::from_str(&alg).ok())
+        .unwrap_or(KeyAlgorithm::RS256);
+    if alg.jwt_algorithm().is_none() {
+        error!("{} cannot be used to sign JWTs, using RS256", alg);
+        return KeyAlgorithm::RS256;
+    }
+    alg
+}
+
+/// Get how long, in seconds, a signing key is used before it is rotated
+/// from ENV.
+pub fn key_rotation_age() -> u64 {
+    env::var("GNAP_KEY_ROTATION_AGE")
+        .ok()
+        .and_then(|age| age.parse().ok())
+        .unwrap_or(DEFAULT_KEY_ROTATION_AGE)
+}
+
+/// Get the active AS signing key for an algorithm.
+///
+/// A key is generated and saved the first time an algorithm is used, and
+/// replaced once it reaches the rotation age.  Only one AS instance at a
+/// time generates or replaces the key for an algorithm, so there is only
+/// ever one active key.  The others wait for it.
+pub async fn get_signing_key(service: &Service, alg: KeyAlgorithm) -> Result<SigningKey, GnapError> {
+    if let Some(key) = current_key(service, alg).await? {
+        return Ok(key);
+    }
+
+    let lock = format!("signing_keys:{}", alg);
+    let holder = create_secret()?;
+    for _ in 0..KEY_LOCK_ATTEMPTS {
+        if service.acquire_lock(&lock, &holder, KEY_LOCK_TTL).await? {
+            let result = replace_signing_key(service, alg).await;
+            service.release_lock(&lock, &holder).await?;
+            return result;
+        }
+        sleep(KEY_LOCK_WAIT).await;
+        if let Some(key) = current_key(service, alg).await? {
+            return Ok(key);
+        }
+    }
+    error!("Timed out waiting for the {} signing key", alg);
+    Err(GnapError::GeneralError)
+}
+
+/// The active signing key for an algorithm, unless it is missing or due
+/// for rotation.
+async fn current_key(service: &Service, alg: KeyAlgorithm) -> Result<Option<SigningKey>, GnapError> {
+    match service.get_signing_key(alg).await? {
+        Some(key) if !key.is_due_for_rotation(key_rotation_age(), unix_time()) => open_key(key).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Generate the signing key for an algorithm, or rotate it if it is due.
+/// Must be called with the signing key lock held.
+async fn replace_signing_key(service: &Service, alg: KeyAlgorithm) -> Result<SigningKey, GnapError> {
+    match service.get_signing_key(alg).await? {
+        Some(key) if key.is_due_for_rotation(key_rotation_age(), unix_time()) => {
+            rotate_signing_key(service, key).await
+        }
+        Some(key) => open_key(key),
+        None => {
+            trace!("No signing key for {}, generating one", alg);
+            let key = generate_key(alg)?;
+            service.add_signing_key(&seal_key(&key)?).await?;
+            Ok(key)
+        }
+    }
+}
+
+/// Replace an active signing key with a new one.
+///
+/// The old key keeps being published until every token it signed has
+/// expired, and is then retired.
+async fn rotate_signing_key(service: &Service, mut key: SigningKey) -> Result<SigningKey, GnapError> {
+    trace!("Rotating signing key {}", key.kid);
+    let new_key = generate_key(key.alg)?;
+    service.add_signing_key(&seal_key(&new_key)?).await?;
+    key.start_retiring(unix_time() + token_lifetime());
+    service.update_signing_key(&key).await?;
+    Ok(new_key)
+}
+
+/// Get every AS key that has not been retired, with its private key.
+pub async fn signing_keys(service: &Service) -> Result<Vec<SigningKey>, GnapError> {
+    service.get_signing_keys().await?.into_iter().map(open_key).collect()
+}
+
+/// Get the key that encrypts private keys at rest from ENV.
+///
+/// `GNAP_KEY_ENCRYPTION_KEY` is a base64url encoded 32 byte AES key.  Without
+/// it, private keys are saved as they are.
+fn key_encryption_key() -> Result<Option<Vec<u8>>, GnapError> {
+    let encoded = match env::var("GNAP_KEY_ENCRYPTION_KEY") {
+        Ok(encoded) if !encoded.is_empty() => encoded,
+        _ => return Ok(None),
+    };
+    let kek = base64::decode_config(encoded.trim(), base64::URL_SAFE_NO_PAD).map_err(crypto_error)?;
+    if kek.len() != 32 {
+        error!("GNAP_KEY_ENCRYPTION_KEY must be 32 bytes");
+        return Err(GnapError::CryptoError("GNAP_KEY_ENCRYPTION_KEY must be 32 bytes".to_owned()));
+    }
+    Ok(Some(kek))
+}
+
+/// Encrypt the private key of a key before it is saved.
+fn seal_key(key: &SigningKey) -> Result<SigningKey, GnapError> {
+    let mut sealed = key.clone();
+    if let Some(kek) = key_encryption_key()? {
+        sealed.private_key = seal(&key.private_key, &kek)?;
+    }
+    Ok(sealed)
+}
+
+/// Decrypt the private key of a saved key.
+fn open_key(mut key: SigningKey) -> Result<SigningKey, GnapError> {
+    if key.private_key.starts_with(SEALED_PREFIX) {
+        let kek = key_encryption_key()?.ok_or_else(|| {
+            error!("Signing key {} is encrypted, but GNAP_KEY_ENCRYPTION_KEY is not set", &key.kid);
+            GnapError::CryptoError("GNAP_KEY_ENCRYPTION_KEY is not set".to_owned())
+        })?;
+        key.private_key = open(&key.private_key, &kek)?;
+    }
+    Ok(key)
+}
+
+/// AES-256-GCM encrypt a private key, as the prefix followed by the
+/// base64url encoded nonce, ciphertext and tag.
+fn seal(private_key: &str, kek: &[u8]) -> Result<String, GnapError> {
+    let mut nonce = [0u8; 12];
+    rand_bytes(&mut nonce).map_err(crypto_error)?;
+    let mut tag = [0u8; 16];
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), kek, Some(&nonce), b"", private_key.as_bytes(), &mut tag)
+        .map_err(crypto_error)?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    sealed.extend_from_slice(&tag);
+    Ok(format!("{}{}", SEALED_PREFIX, base64::encode_config(sealed, base64::URL_SAFE_NO_PAD)))
+}
+
+fn open(sealed: &str, kek: &[u8]) -> Result<String, GnapError> {
+    let sealed = base64::decode_config(&sealed[SEALED_PREFIX.len()..], base64::URL_SAFE_NO_PAD)
+        .map_err(crypto_error)?;
+    if sealed.len() < 28 {
+        return Err(GnapError::CryptoError("sealed key is too short".to_owned()));
+    }
+    let (nonce, rest) = sealed.split_at(12);
+    let (ciphertext, tag) = rest.split_at(rest.len() - 16);
+    let private_key = decrypt_aead(Cipher::aes_256_gcm(), kek, Some(nonce), b"", ciphertext, tag)
+        .map_err(crypto_error)?;
+    String::from_utf8(private_key).map_err(crypto_error)
+}
+
+/// Get the AS public keys, for verifying the tokens it signed.
+///
+/// Retiring keys whose tokens have all expired are retired along the way.
+pub async fn published_keys(service: &Service) -> Result<Jwks, GnapError> {
+    let now = unix_time();
+    let mut keys = Vec::new();
+    for mut key in service.get_signing_keys().await? {
+        if key.retire_if_due(now) {
+            trace!("Retiring signing key {}", key.kid);
+            service.update_signing_key(&key).await?;
+        }
+        keys.push(key);
+    }
+    jwks(&keys)
+}
+
+/// Describe the published keys as a JWK Set.
+pub fn jwks(keys: &[SigningKey]) -> Result<Jwks, GnapError> {
+    let mut jwks = Jwks::default();
+    for key in keys.iter().filter(|key| key.is_published()) {
+        let public_key = match &key.public_key {
+            Some(public_key) => public_key,
+            None => continue,
+        };
+        let pkey = PKey::public_key_from_pem(public_key.as_bytes()).map_err(crypto_error)?;
+        let mut jwk = jwk::public_jwk(&pkey)?;
+        jwk.kid = Some(key.kid.clone());
+        jwk.alg = Some(key.alg.to_string());
+        jwk.key_use = Some("sig".to_owned());
+        jwks.keys.push(jwk);
+    }
+    Ok(jwks)
+}
+
+/// Generate a new key for an algorithm.
+pub fn generate_key(alg: KeyAlgorithm) -> Result<SigningKey, GnapError> {
+    let (private_key, public_key) = match alg {
+        KeyAlgorithm::RS256
+        | KeyAlgorithm::RS384
+        | KeyAlgorithm::RS512
+        | KeyAlgorithm::PS256
+        | KeyAlgorithm::PS384
+        | KeyAlgorithm::PS512 => {
+            let rsa = Rsa::generate(2048).map_err(crypto_error)?;
+            let private_key = rsa.private_key_to_pem().map_err(crypto_error)?;
+            let public_key = PKey::from_rsa(rsa)
+                .and_then(|pkey| pkey.public_key_to_pem())
+                .map_err(crypto_error)?;
+            (private_key, Some(public_key))
+        }
+        KeyAlgorithm::ES256 | KeyAlgorithm::ES384 => {
+            let nid = match alg {
+                KeyAlgorithm::ES256 => Nid::X9_62_PRIME256V1,
+                _ => Nid::SECP384R1,
+            };
+            let group = EcGroup::from_curve_name(nid).map_err(crypto_error)?;
+            let pkey = EcKey::generate(&group)
+                .and_then(PKey::from_ec_key)
+                .map_err(crypto_error)?;
+            let private_key = pkey.private_key_to_pem_pkcs8().map_err(crypto_error)?;
+            let public_key = pkey.public_key_to_pem().map_err(crypto_error)?;
+            (private_key, Some(public_key))
+        }
+        KeyAlgorithm::EdDSA => {
+            let pkey = PKey::generate_ed25519().map_err(crypto_error)?;
+            let private_key = pkey.private_key_to_pem_pkcs8().map_err(crypto_error)?;
+            let public_key = pkey.public_key_to_pem().map_err(crypto_error)?;
+            (private_key, Some(public_key))
+        }
+        KeyAlgorithm::V4Local => {
+            let mut secret = [0u8; 32];
+            rand_bytes(&mut secret).map_err(crypto_error)?;
+            let secret = base64::encode_config(secret, base64::URL_SAFE_NO_PAD);
+            (secret.into_bytes(), None)
+        }
+    };
+
+    Ok(SigningKey::new(
+        alg,
+        String::from_utf8_lossy(&private_key).into_owned(),
+        public_key.map(|public_key| String::from_utf8_lossy(&public_key).into_owned()),
+    ))
+}
+
+/// Create a random secret, base64url encoded.
+pub fn create_secret() -> Result<String, GnapError> {
+    let mut secret = [0u8; 32];
+    rand_bytes(&mut secret).map_err(crypto_error)?;
+    Ok(base64::encode_config(secret, base64::URL_SAFE_NO_PAD))
+}
+
+/// Hash a secret created with [create_secret].
+///
+/// The secrets are random, so a plain hash is enough to protect them at rest.
+pub fn hash_secret(secret: &str) -> String {
+    base64::encode_config(sha256(secret.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Check a secret against its saved hash.
+pub fn verify_secret(secret: &str, secret_hash: &str) -> bool {
+    let hash = hash_secret(secret);
+    hash.len() == secret_hash.len() && memcmp::eq(hash.as_bytes(), secret_hash.as_bytes())
+}
+
+/// Convert a crypto library error into a GnapError.
+pub fn crypto_error<E: std::fmt::Display>(err: E) -> GnapError {
+    error!("{}", err);
+    GnapError::CryptoError(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn published_jwks() {
+        let active = generate_key(KeyAlgorithm::ES256).unwrap();
+        let mut retiring = generate_key(KeyAlgorithm::EdDSA).unwrap();
+        retiring.start_retiring(unix_time() + 60);
+        let mut retired = generate_key(KeyAlgorithm::RS256).unwrap();
+        retired.start_retiring(unix_time());
+        assert!(retired.retire_if_due(unix_time()));
+        let local = generate_key(KeyAlgorithm::V4Local).unwrap();
+
+        let jwks = jwks(&[active.clone(), retiring.clone(), retired, local]).unwrap();
+        assert_eq!(jwks.keys.len(), 2);
+        let jwk = &jwks.keys[0];
+        assert_eq!(jwk.kid, Some(active.kid.clone()));
+        assert_eq!(jwk.alg, Some("ES256".to_owned()));
+        assert_eq!(jwk.key_use, Some("sig".to_owned()));
+        assert_eq!(jwk.crv, Some("P-256".to_owned()));
+        assert_eq!(jwks.keys[1].kid, Some(retiring.kid));
+
+        // The published key is the one tokens are signed with.
+        let published = jwk::public_key(jwk).unwrap();
+        let pkey = PKey::public_key_from_pem(active.public_key.unwrap().as_bytes()).unwrap();
+        assert!(published.public_eq(&pkey));
+    }
+
+    #[test]
+    fn sealed_private_key() {
+        let kek = [9u8; 32];
+        let key = generate_key(KeyAlgorithm::EdDSA).unwrap();
+        let sealed = seal(&key.private_key, &kek).unwrap();
+        assert!(sealed.starts_with(SEALED_PREFIX));
+        assert!(!sealed.contains("PRIVATE KEY"));
+        assert_eq!(open(&sealed, &kek).unwrap(), key.private_key);
+        assert!(open(&sealed, &[8u8; 32]).is_err());
+
+        // Keys saved before encryption was turned on are read as they are.
+        assert_eq!(open_key(key.clone()).unwrap().private_key, key.private_key);
+    }
+}
@@ -1,5 +1,11 @@
-use actix_web::web;
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream, web};
+use log::trace;
+use openssl::{
+    hash::MessageDigest,
+    ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode},
+};
+use std::any::Any;
 use std::env;
 use std::net::SocketAddr;
 
@@ -42,6 +48,14 @@ pub fn get_ip_addresses() -> (SocketAddr, SocketAddr, String) {
     (api_address, tls_address, ip)
 }
 
+/// Get the externally visible base URL of the AS from ENV
+///
+/// This is used to build the URIs handed out to clients, such as the
+/// continuation URI.  Defaults to the local HTTP listener.
+pub fn get_as_host() -> String {
+    env::var("GNAP_AS_HOST").unwrap_or_else(|_| "http://localhost:8000".to_owned())
+}
+
 /*
 
 To create a self-signed temporary cert for testing, copy&paste the following:
@@ -61,6 +75,12 @@ To create a self-signed temporary cert for testing, copy&paste the following:
 */
 
 /// SSL builder for HttpServer
+///
+/// Client certificates are requested, but not required, so that client
+/// instances can prove their keys with mTLS.  If `TLS_CLIENT_CA` is set,
+/// presented certificates must chain to a CA in that PEM file.  Otherwise any
+/// certificate is accepted, including self-signed ones, since the client
+/// instance key proof only relies on the client holding the certificate key.
 pub fn tls_builder() -> SslAcceptorBuilder {
     // load ssl keys
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
@@ -70,5 +90,53 @@ pub fn tls_builder() -> SslAcceptorBuilder {
     builder
         .set_certificate_chain_file(".keystore/cert.pem")
         .unwrap();
+    match env::var("TLS_CLIENT_CA") {
+        Ok(ca_file) => {
+            builder.set_ca_file(ca_file).expect("TLS_CLIENT_CA is invalid");
+            builder.set_verify(SslVerifyMode::PEER);
+        }
+        Err(_) => builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true),
+    }
     builder
 }
+
+/// The certificate a client presented on a TLS connection.
+///
+/// Added to the connection data by [on_connect], and available to handlers
+/// with `HttpRequest::conn_data`.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    /// DER encoded certificate.
+    pub der: Vec<u8>,
+    /// base64url encoded SHA-256 thumbprint of the certificate.
+    pub thumbprint: String,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: Vec<u8>) -> Result<Self, openssl::error::ErrorStack> {
+        let digest = openssl::hash::hash(MessageDigest::sha256(), &der)?;
+        Ok(Self {
+            der,
+            thumbprint: base64::encode_config(digest, base64::URL_SAFE_NO_PAD),
+        })
+    }
+}
+
+/// Connection callback for HttpServer
+///
+/// Records the client certificate, if one was presented on a TLS connection.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let stream = match connection.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(stream) => stream,
+        None => return,
+    };
+    let certificate = stream
+        .ssl()
+        .peer_certificate()
+        .and_then(|cert| cert.to_der().ok())
+        .and_then(|der| ClientCertificate::from_der(der).ok());
+    if let Some(certificate) = certificate {
+        trace!("Client certificate presented: {}", certificate.thumbprint);
+        data.insert(certificate);
+    }
+}
@@ -4,10 +4,18 @@ use dotenv::dotenv;
 use log::info;
 use pretty_env_logger;
 
-use gnap_as::{app_state, get_ip_addresses, tls_builder};
+use gnap_as::{app_state, get_ip_addresses, on_connect, tls_builder};
+mod account;
+mod admin;
 mod grant;
 mod handlers;
+mod interact;
+mod keys;
+mod outbound;
+mod proof;
+mod resource_server;
 mod routes;
+mod token;
 
 /// Crate main.
 /// The main service needs to be async, in order to leverage async services.
@@ -40,12 +48,17 @@ async fn main() -> std::io::Result<()> {
             .configure(routes::db::routes)
             .configure(routes::well_known::routes)
             .configure(routes::transaction::routes)
+            .configure(routes::introspection::routes)
+            .configure(routes::token::routes)
+            .configure(routes::keys::routes)
+            .configure(routes::interact::routes)
             // enable logger - always register actix-web Logger middleware last
             .wrap(middleware::Logger::default())
     };
 
     // Start http server with the app
     HttpServer::new(app)
+        .on_connect(on_connect)
         .bind(api_address)?
         .bind_openssl(tls_address, tls_builder())?
         .run()
@@ -0,0 +1,205 @@
+//! Requests the AS makes to URIs chosen by clients.
+//!
+//! Clients name the URIs the AS fetches their key sets from and pushes
+//! interaction finishes to, so these requests must not reach into the
+//! network the AS runs in.  Only https URIs are accepted, and their host must
+//! only resolve to public addresses.  The request is pinned to the addresses
+//! that were checked, and redirects are not followed.
+//!
+use actix_web::web;
+use errors::GnapError;
+use log::error;
+use reqwest::{redirect::Policy, Client};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use url::{Host, Url};
+
+/// A checked URI, and the public addresses its host resolved to.
+pub struct Target {
+    pub uri: Url,
+    host: String,
+    addrs: Vec<SocketAddr>,
+}
+
+impl Target {
+    /// A client that only connects to the checked addresses.
+    pub fn client(&self, timeout: Duration) -> Result<Client, GnapError> {
+        build(Client::builder().resolve_to_addrs(&self.host, &self.addrs), timeout)
+    }
+}
+
+/// Check a URI before the AS makes a request to it.
+pub async fn public_target(uri: &str) -> Result<Target, GnapError> {
+    let uri = match Url::parse(uri) {
+        Ok(uri) if is_https_uri(&uri) => uri,
+        _ => {
+            error!("{} is not an https URI", uri);
+            return Err(GnapError::BadData);
+        }
+    };
+    let host = match uri.host() {
+        Some(Host::Domain(domain)) => domain.to_owned(),
+        Some(Host::Ipv4(ip)) => ip.to_string(),
+        Some(Host::Ipv6(ip)) => ip.to_string(),
+        None => return Err(GnapError::BadData),
+    };
+    let port = uri.port_or_known_default().unwrap_or(443);
+    let lookup = host.clone();
+    let addrs: Vec<SocketAddr> = web::block(move || (lookup.as_str(), port).to_socket_addrs())
+        .await
+        .map_err(|_| GnapError::GeneralError)?
+        .map_err(|err| {
+            error!("Could not resolve {}: {}", &host, err);
+            GnapError::BadData
+        })?
+        .collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+        error!("{} does not resolve to public addresses only", &host);
+        return Err(GnapError::BadData);
+    }
+    Ok(Target { uri, host, addrs })
+}
+
+/// Can the AS make requests to the URI?  The host is checked when the
+/// request is made.
+pub fn is_https_uri(uri: &Url) -> bool {
+    uri.scheme() == "https" && uri.host().is_some()
+}
+
+/// Is the URI an https URI that does not name a local host?  Host names
+/// are only resolved, and their addresses checked, when the request is
+/// made.
+pub fn is_public_uri(uri: &Url) -> bool {
+    if !is_https_uri(uri) {
+        return false;
+    }
+    match uri.host() {
+        Some(Host::Ipv4(ip)) => is_public_ip(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => is_public_ip(IpAddr::V6(ip)),
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        None => false,
+    }
+}
+
+/// A client that gives up after `timeout` and does not follow redirects,
+/// for reaching local stand-ins in tests without the address checks.
+#[cfg(test)]
+pub fn client(timeout: Duration) -> Result<Client, GnapError> {
+    build(Client::builder(), timeout)
+}
+
+fn build(builder: reqwest::ClientBuilder, timeout: Duration) -> Result<Client, GnapError> {
+    builder
+        .timeout(timeout)
+        .redirect(Policy::none())
+        .build()
+        .map_err(|err| {
+            error!("Could not build HTTP client: {}", err);
+            GnapError::GeneralError
+        })
+}
+
+/// Is the address reachable on the public internet?
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (RFC 6598)
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking (RFC 2544)
+        || (a == 198 && (b == 18 || b == 19))
+        // Reserved
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local
+        || (first & 0xfe00) == 0xfc00
+        // Link local
+        || (first & 0xffc0) == 0xfe80
+        // Documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn public_uris() {
+        for uri in ["https://client.example.net/push", "https://93.184.216.34/push"] {
+            assert!(is_public_uri(&Url::parse(uri).unwrap()), "{}", uri);
+        }
+        for uri in [
+            "http://client.example.net/push",
+            "https://127.0.0.1/push",
+            "https://[::1]/push",
+            "https://10.0.0.1/push",
+            "https://localhost:8000/push",
+            "https://api.localhost/push",
+            "file:///etc/passwd",
+        ] {
+            assert!(!is_public_uri(&Url::parse(uri).unwrap()), "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn private_targets() {
+        assert!(public_target("http://93.184.216.34/jwks").await.is_err());
+        assert!(public_target("https://127.0.0.1/jwks").await.is_err());
+        assert!(public_target("https://[::1]/jwks").await.is_err());
+        assert!(public_target("https://169.254.169.254/latest").await.is_err());
+        assert!(public_target("not a uri").await.is_err());
+
+        let target = public_target("https://93.184.216.34/jwks").await.expect("public address refused");
+        assert_eq!(target.addrs, vec!["93.184.216.34:443".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,203 @@
+//! Attached JWS (jws) key proofing.
+//!
+//! Client instances that cannot set custom headers send the request body as
+//! a compact JWS, with the `application/jose` content type.  The protected
+//! header binds the signature to the request as it does for detached JWS.
+//! Requests without a body fall back to a detached JWS over an empty
+//! payload.
+//!
+use super::{
+    jwsd::{self, decode, split_jws, verify_jws},
+    Proof,
+};
+use crate::handlers::error_response;
+use actix_web::{
+    dev::Payload, error::InternalError, web::Bytes, FromRequest, HttpMessage, HttpRequest,
+};
+use errors::{GnapError, GnapErrorCode};
+use futures::future::LocalBoxFuture;
+use log::error;
+use model::key::ClientKey;
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+
+const JOSE_CONTENT_TYPE: &str = "application/jose";
+const JWS_TYPE: &str = "gnap-binding-jws";
+
+/// Verify the JWS body of a request.
+pub fn verify(
+    req: &HttpRequest,
+    body: &[u8],
+    key: &ClientKey,
+    access_token: Option<&str>,
+) -> Result<Proof, GnapError> {
+    if body.is_empty() {
+        return jwsd::verify(req, body, key, access_token);
+    }
+    let (encoded_header, payload, signature) = split_jws(jws_body(body)?)?;
+    verify_jws(req, key, access_token, JWS_TYPE, encoded_header, |_| Ok(payload.to_owned()), signature)
+}
+
+/// The `kid` of the JWS on a request, if it names one.
+pub fn key_id(req: &HttpRequest, body: &[u8]) -> Option<String> {
+    if body.is_empty() {
+        return jwsd::key_id(req);
+    }
+    let (encoded_header, _, _) = split_jws(jws_body(body).ok()?).ok()?;
+    jwsd::header_key_id(encoded_header)
+}
+
+fn jws_body(body: &[u8]) -> Result<&str, GnapError> {
+    std::str::from_utf8(body).map_err(|_| {
+        error!("JWS body is not text");
+        super::invalid_client()
+    })
+}
+
+/// A JSON request body, sent either as is or as the payload of a JWS.
+///
+/// The body is unwrapped and deserialized, and the raw body is kept so that
+/// the key proof can be verified once the client instance key is known.
+pub struct SignedJson<T> {
+    value: T,
+    body: Bytes,
+}
+
+impl<T> SignedJson<T> {
+    /// The body as it was sent.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+impl<T> Deref for SignedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for SignedJson<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let is_jws = req.content_type() == JOSE_CONTENT_TYPE;
+        let bytes = Bytes::from_request(req, payload);
+        Box::pin(async move {
+            let body = bytes.await?;
+            let value = parse_body(&body, is_jws).map_err(|err| {
+                let response = error_response(err);
+                InternalError::from_response("Malformed request body", response)
+            })?;
+            Ok(SignedJson { value, body })
+        })
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8], is_jws: bool) -> Result<T, GnapError> {
+    let invalid_request = || GnapError::ProtocolError(GnapErrorCode::InvalidRequest);
+    // An empty body reads as null, for requests whose body is optional.
+    let json = if body.is_empty() {
+        b"null".to_vec()
+    } else if is_jws {
+        let (_, payload, _) = split_jws(jws_body(body)?).map_err(|_| invalid_request())?;
+        decode(payload).map_err(|_| invalid_request())?
+    } else {
+        body.to_vec()
+    };
+    serde_json::from_slice(&json).map_err(|err| {
+        error!("Malformed request body: {:?}", err);
+        invalid_request()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keys::jwk::public_jwk,
+        proof::{jose_algorithm, jwsd::encode, sign},
+    };
+    use actix_web::{http::header::CONTENT_TYPE, test::TestRequest};
+    use model::{grant::GrantRequest, key::KeyProofMethod, unix_time};
+    use openssl::pkey::{PKey, Private};
+    use serde_json::json;
+
+    const BODY: &str = r#"{"access_token": {"access": ["foo"]}, "client": "7e057b0c-17e8-4ab4-9260-2b33f32b2cce"}"#;
+
+    fn client_key(key: &PKey<Private>) -> ClientKey {
+        ClientKey {
+            proof: KeyProofMethod::Jws,
+            jwk: Some(public_jwk(key).unwrap()),
+            cert: None,
+            cert_s256: None,
+        }
+    }
+
+    fn jws(key: &PKey<Private>, typ: &str, payload: &str) -> String {
+        let header = json!({
+            "alg": "EdDSA",
+            "typ": typ,
+            "htm": "POST",
+            "uri": "http://as.example/gnap/tx",
+            "created": unix_time(),
+        });
+        let signing_input = format!("{}.{}", encode(header.to_string().as_bytes()), encode(payload.as_bytes()));
+        let signature = sign(key, jose_algorithm("EdDSA").unwrap(), signing_input.as_bytes());
+        format!("{}.{}", signing_input, encode(&signature))
+    }
+
+    fn request(body: &str) -> TestRequest {
+        TestRequest::post()
+            .uri("/gnap/tx")
+            .insert_header(("host", "as.example"))
+            .insert_header((CONTENT_TYPE, JOSE_CONTENT_TYPE))
+            .set_payload(body.to_owned())
+    }
+
+    #[actix_web::test]
+    async fn extract_jws_body() {
+        let key = PKey::generate_ed25519().unwrap();
+        let body = jws(&key, JWS_TYPE, BODY);
+        let (req, mut payload) = request(&body).to_http_parts();
+
+        let signed = SignedJson::<GrantRequest>::from_request(&req, &mut payload)
+            .await
+            .expect("not extracted");
+        assert_eq!(signed.access_token.len(), 1);
+        assert_eq!(signed.body(), body.as_bytes());
+        assert!(verify(&req, signed.body(), &client_key(&key), None).is_ok());
+        assert!(verify(&req, signed.body(), &client_key(&PKey::generate_ed25519().unwrap()), None).is_err());
+    }
+
+    #[actix_web::test]
+    async fn extract_json_body() {
+        let (req, mut payload) = TestRequest::post().set_payload(BODY).to_http_parts();
+        let signed = SignedJson::<GrantRequest>::from_request(&req, &mut payload)
+            .await
+            .expect("not extracted");
+        assert_eq!(signed.body(), BODY.as_bytes());
+
+        let (req, mut payload) = request("not.a.jws").to_http_parts();
+        assert!(SignedJson::<GrantRequest>::from_request(&req, &mut payload).await.is_err());
+
+        // Optional bodies can be left out.
+        let (req, mut payload) = TestRequest::post().to_http_parts();
+        let signed = SignedJson::<Option<GrantRequest>>::from_request(&req, &mut payload)
+            .await
+            .expect("not extracted");
+        assert!(signed.is_none());
+        let (req, mut payload) = TestRequest::post().to_http_parts();
+        assert!(SignedJson::<GrantRequest>::from_request(&req, &mut payload).await.is_err());
+    }
+
+    #[test]
+    fn jws_type() {
+        let key = PKey::generate_ed25519().unwrap();
+        let body = jws(&key, "gnap-binding-jwsd", BODY);
+        let req = request(&body).to_http_request();
+        assert!(verify(&req, body.as_bytes(), &client_key(&key), None).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//! Detached JWS (jwsd) key proofing.
+//!
+//! The client instance signs a JWS over the hash of the request body, and
+//! sends it in the `Detached-JWS` header with the payload left out.  The
+//! protected header binds the signature to the request method, URI and
+//! creation time, and to the access token through its hash.
+//!
+use super::{
+    check_created, invalid_client, jose_algorithm, target_uri, verify_signature, Proof,
+    SignatureAlgorithm,
+};
+use crate::keys::jwk::public_key;
+use actix_web::HttpRequest;
+use errors::GnapError;
+use log::error;
+use model::{key::ClientKey, unix_time};
+use openssl::sha::sha256;
+use serde::Deserialize;
+
+const DETACHED_JWS: &str = "detached-jws";
+const JWSD_TYPE: &str = "gnap-binding-jwsd";
+
+/// The protected header of a JWS proof.
+#[derive(Deserialize, Debug)]
+struct ProofHeader {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    typ: Option<String>,
+    htm: String,
+    uri: String,
+    created: u64,
+    #[serde(default)]
+    ath: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+/// Verify the detached JWS on a request.
+pub fn verify(
+    req: &HttpRequest,
+    body: &[u8],
+    key: &ClientKey,
+    access_token: Option<&str>,
+) -> Result<Proof, GnapError> {
+    let jws = req
+        .headers()
+        .get(DETACHED_JWS)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| {
+            error!("Request has no Detached-JWS header");
+            invalid_client()
+        })?;
+    let (encoded_header, payload, signature) = split_jws(jws)?;
+    if !payload.is_empty() {
+        error!("Detached JWS has an attached payload");
+        return Err(invalid_client());
+    }
+
+    // The payload is the hash of the body, with the hash the signature
+    // algorithm uses.  Requests without a body have an empty payload.
+    let hashed_payload = |alg: SignatureAlgorithm| -> Result<String, GnapError> {
+        if body.is_empty() {
+            Ok(String::new())
+        } else {
+            Ok(encode(&alg.digest().hash(body)?))
+        }
+    };
+    verify_jws(req, key, access_token, JWSD_TYPE, encoded_header, hashed_payload, signature)
+}
+
+/// The `kid` of the detached JWS on a request, if it names one.
+pub fn key_id(req: &HttpRequest) -> Option<String> {
+    let jws = req.headers().get(DETACHED_JWS)?.to_str().ok()?;
+    let (encoded_header, _, _) = split_jws(jws).ok()?;
+    header_key_id(encoded_header)
+}
+
+/// The `kid` in a JWS protected header.
+pub fn header_key_id(encoded_header: &str) -> Option<String> {
+    let header: serde_json::Value = serde_json::from_slice(&decode(encoded_header).ok()?).ok()?;
+    header.get("kid")?.as_str().map(|kid| kid.to_owned())
+}
+
+/// Verify a compact JWS proof of a request.
+///
+/// The payload is produced from the algorithm in the protected header, so
+/// that detached payloads can be computed with the right hash.
+pub fn verify_jws<F>(
+    req: &HttpRequest,
+    key: &ClientKey,
+    access_token: Option<&str>,
+    typ: &str,
+    encoded_header: &str,
+    payload: F,
+    signature: &str,
+) -> Result<Proof, GnapError>
+where
+    F: FnOnce(SignatureAlgorithm) -> Result<String, GnapError>,
+{
+    let jwk = key.jwk.as_ref().ok_or_else(|| {
+        error!("Client instance key has no JWK");
+        invalid_client()
+    })?;
+    let header: ProofHeader = serde_json::from_slice(&decode(encoded_header)?).map_err(|_| {
+        error!("Malformed JWS protected header");
+        invalid_client()
+    })?;
+    check_header(&header, typ, req, access_token, unix_time())?;
+
+    let alg = jose_algorithm(&header.alg)?;
+    if let Some(key_alg) = &jwk.alg {
+        if key_alg != &header.alg {
+            error!("Key is for {}, not {}", key_alg, &header.alg);
+            return Err(invalid_client());
+        }
+    }
+    if let (Some(kid), Some(key_id)) = (&header.kid, &jwk.kid) {
+        if kid != key_id {
+            error!("JWS is for key {}", kid);
+            return Err(invalid_client());
+        }
+    }
+
+    let signing_input = format!("{}.{}", encoded_header, payload(alg)?);
+    let public_key = public_key(jwk).map_err(|_| invalid_client())?;
+    let signature = decode(signature)?;
+    if !verify_signature(&public_key, alg, signing_input.as_bytes(), &signature)? {
+        error!("JWS verification failed");
+        return Err(invalid_client());
+    }
+    Ok(Proof::new(header.created, header.nonce.as_deref(), signing_input.as_bytes()))
+}
+
+fn check_header(
+    header: &ProofHeader,
+    typ: &str,
+    req: &HttpRequest,
+    access_token: Option<&str>,
+    now: u64,
+) -> Result<(), GnapError> {
+    if header.typ.as_deref() != Some(typ) {
+        error!("JWS has type {:?}", &header.typ);
+        return Err(invalid_client());
+    }
+    if header.htm != req.method().as_str() {
+        error!("JWS is for method {}", &header.htm);
+        return Err(invalid_client());
+    }
+    if header.uri != target_uri(req) {
+        error!("JWS is for URI {}", &header.uri);
+        return Err(invalid_client());
+    }
+    check_created(header.created, now)?;
+    if let Some(access_token) = access_token {
+        if header.ath.as_deref() != Some(access_token_hash(access_token).as_str()) {
+            error!("JWS does not match the access token");
+            return Err(invalid_client());
+        }
+    }
+    Ok(())
+}
+
+/// The `ath` value for an access token.
+pub fn access_token_hash(access_token: &str) -> String {
+    encode(&sha256(access_token.as_bytes()))
+}
+
+/// Split a compact JWS into its header, payload and signature.
+pub fn split_jws(jws: &str) -> Result<(&str, &str, &str), GnapError> {
+    let mut parts = jws.trim().split('.');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(payload), Some(signature), None) => Ok((header, payload, signature)),
+        _ => {
+            error!("Malformed compact JWS");
+            Err(invalid_client())
+        }
+    }
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>, GnapError> {
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD).map_err(|_| {
+        error!("Malformed base64url value");
+        invalid_client()
+    })
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keys::jwk::public_jwk, proof::sign};
+    use actix_web::test::TestRequest;
+    use model::key::KeyProofMethod;
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::{PKey, Private},
+    };
+    use serde_json::json;
+
+    const BODY: &[u8] = br#"{"access_token": {"access": ["foo"]}}"#;
+    const URI: &str = "http://as.example/gnap/tx";
+
+    fn client_key(key: &PKey<Private>) -> ClientKey {
+        ClientKey {
+            proof: KeyProofMethod::Jwsd,
+            jwk: Some(public_jwk(key).unwrap()),
+            cert: None,
+            cert_s256: None,
+        }
+    }
+
+    fn detached_jws(key: &PKey<Private>, header: serde_json::Value, body: &[u8]) -> String {
+        let alg = jose_algorithm(header["alg"].as_str().unwrap()).unwrap();
+        let header = encode(header.to_string().as_bytes());
+        let payload = if body.is_empty() {
+            String::new()
+        } else {
+            encode(&alg.digest().hash(body).unwrap())
+        };
+        let signature = sign(key, alg, format!("{}.{}", header, payload).as_bytes());
+        format!("{}..{}", header, encode(&signature))
+    }
+
+    fn request(jws: String) -> HttpRequest {
+        TestRequest::post()
+            .uri("/gnap/tx")
+            .insert_header(("host", "as.example"))
+            .insert_header((DETACHED_JWS, jws))
+            .to_http_request()
+    }
+
+    fn header(alg: &str) -> serde_json::Value {
+        json!({
+            "alg": alg,
+            "kid": "k1",
+            "typ": JWSD_TYPE,
+            "htm": "POST",
+            "uri": URI,
+            "created": unix_time(),
+        })
+    }
+
+    #[test]
+    fn signed_body() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let ed = PKey::generate_ed25519().unwrap();
+
+        for (key, alg) in [(&ec, "ES256"), (&ed, "EdDSA")].iter() {
+            let req = request(detached_jws(key, header(alg), BODY));
+            assert!(verify(&req, BODY, &client_key(key), None).is_ok());
+            assert!(verify(&req, b"{}", &client_key(key), None).is_err());
+        }
+    }
+
+    #[test]
+    fn access_token_binding() {
+        let key = PKey::generate_ed25519().unwrap();
+        let mut with_ath = header("EdDSA");
+        with_ath["ath"] = json!(access_token_hash("abc"));
+
+        let req = request(detached_jws(&key, with_ath, b""));
+        assert!(verify(&req, b"", &client_key(&key), Some("abc")).is_ok());
+        assert!(verify(&req, b"", &client_key(&key), Some("other")).is_err());
+
+        let req = request(detached_jws(&key, header("EdDSA"), b""));
+        assert!(verify(&req, b"", &client_key(&key), Some("abc")).is_err());
+    }
+
+    #[test]
+    fn header_checks() {
+        let key = PKey::generate_ed25519().unwrap();
+
+        let mut wrong_uri = header("EdDSA");
+        wrong_uri["uri"] = json!("http://as.example/gnap/other");
+        let mut old = header("EdDSA");
+        old["created"] = json!(unix_time() - 3600);
+        let mut untyped = header("EdDSA");
+        untyped.as_object_mut().unwrap().remove("typ");
+
+        for header in [wrong_uri, old, untyped].iter() {
+            let req = request(detached_jws(&key, header.clone(), BODY));
+            assert!(verify(&req, BODY, &client_key(&key), None).is_err());
+        }
+    }
+
+    #[test]
+    fn unsupported_algorithms() {
+        let key = PKey::generate_ed25519().unwrap();
+        for alg in ["HS256", "none"].iter() {
+            let mut unsupported = header("EdDSA");
+            unsupported["alg"] = json!(alg);
+            let jws = format!("{}..", encode(unsupported.to_string().as_bytes()));
+            assert!(verify(&request(jws), BODY, &client_key(&key), None).is_err());
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! Client instance key proofing.
+//!
+//! Requests from a client instance must prove possession of the client
+//! instance key, using the proofing method the key was registered with.
+//!
+//! Signed proofs are only accepted once.  Every verified proof, and its
+//! nonce if it has one, is recorded for as long as the proof would be
+//! accepted, so that a replayed request is rejected.  Proofs are recorded by
+//! what was signed rather than by the signature, since an ECDSA signature
+//! can be altered into another valid signature over the same input.
+//!
+use crate::keys::crypto_error;
+use actix_web::HttpRequest;
+use dao::service::Service;
+use errors::{GnapError, GnapErrorCode};
+use gnap_as::ClientCertificate;
+use log::error;
+use model::key::{ClientKey, KeyProofMethod};
+use openssl::{
+    bn::BigNum,
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    pkey::{Id, PKey, Public},
+    rsa::Padding,
+    sha::sha256,
+    sign::{RsaPssSaltlen, Verifier},
+};
+use std::env;
+
+pub mod httpsig;
+pub mod jws;
+pub mod jwsd;
+pub mod mtls;
+
+/// Default age, in seconds, up to which a proof is accepted.
+const DEFAULT_PROOF_WINDOW: u64 = 300;
+/// Default allowance, in seconds, for client clocks that run ahead.
+const DEFAULT_CLOCK_SKEW: u64 = 30;
+
+/// Get how long, in seconds, after its creation a proof is accepted from
+/// ENV.
+pub fn proof_window() -> u64 {
+    env::var("GNAP_PROOF_WINDOW")
+        .ok()
+        .and_then(|window| window.parse().ok())
+        .unwrap_or(DEFAULT_PROOF_WINDOW)
+}
+
+/// Get how far, in seconds, a proof creation time may be ahead of the AS
+/// clock from ENV.
+pub fn clock_skew() -> u64 {
+    env::var("GNAP_PROOF_CLOCK_SKEW")
+        .ok()
+        .and_then(|skew| skew.parse().ok())
+        .unwrap_or(DEFAULT_CLOCK_SKEW)
+}
+
+/// A verified signed proof, as recorded to detect replays.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proof {
+    /// Seconds since the epoch
+    pub created: u64,
+    pub nonce: Option<String>,
+    /// base64url encoded SHA-256 hash of the signed input, such as the
+    /// signature base or the JWS signing input.
+    pub signature_id: String,
+}
+
+impl Proof {
+    pub fn new(created: u64, nonce: Option<&str>, signed: &[u8]) -> Self {
+        Self {
+            created,
+            nonce: nonce.map(|nonce| nonce.to_owned()),
+            signature_id: base64::encode_config(sha256(signed), base64::URL_SAFE_NO_PAD),
+        }
+    }
+}
+
+/// A request to be checked against a client instance key.
+pub struct ProofRequest<'a> {
+    req: &'a HttpRequest,
+    body: &'a [u8],
+}
+
+impl<'a> ProofRequest<'a> {
+    pub fn new(req: &'a HttpRequest, body: &'a [u8]) -> Self {
+        Self { req, body }
+    }
+
+    /// Verify that the request was made by the holder of the key, and that
+    /// the proof has not been seen before.
+    ///
+    /// `access_token` is the GNAP access token the request is authorized
+    /// with, if any.  The proof must cover it.
+    pub async fn verify(
+        &self,
+        service: &Service,
+        key: Option<&ClientKey>,
+        access_token: Option<&str>,
+    ) -> Result<(), GnapError> {
+        let proof = match self.check(key, access_token)? {
+            Some(proof) => proof,
+            // mTLS proofs are made by the TLS handshake.
+            None => return Ok(()),
+        };
+        // Nonces are recorded per key.  The key was checked above.
+        let key_id = key.and_then(|key| key.thumbprint()).unwrap_or_default();
+        let ttl = proof_window() + clock_skew();
+        if !service
+            .record_proof(&key_id, &proof.signature_id, proof.nonce.as_deref(), proof.created, ttl)
+            .await?
+        {
+            error!("Key proof {} was replayed", &proof.signature_id);
+            return Err(invalid_client());
+        }
+        Ok(())
+    }
+
+    /// Check the key proof on the request, without recording it.
+    pub fn check(&self, key: Option<&ClientKey>, access_token: Option<&str>) -> Result<Option<Proof>, GnapError> {
+        let key = match key {
+            Some(key) => key,
+            None => {
+                error!("No client instance key to verify the request with");
+                return Err(invalid_client());
+            }
+        };
+        match key.proof {
+            KeyProofMethod::Httpsig => httpsig::verify(self.req, self.body, key, access_token).map(Some),
+            KeyProofMethod::Jwsd => jwsd::verify(self.req, self.body, key, access_token).map(Some),
+            KeyProofMethod::Jws => jws::verify(self.req, self.body, key, access_token).map(Some),
+            KeyProofMethod::Mtls => mtls::verify(self.req, key).map(|_| None),
+        }
+    }
+
+    /// The ID of the key the request claims to be proven with, if the
+    /// proofing method names one.
+    pub fn key_id(&self, method: KeyProofMethod) -> Option<String> {
+        match method {
+            KeyProofMethod::Httpsig => httpsig::key_id(self.req),
+            KeyProofMethod::Jwsd => jwsd::key_id(self.req),
+            KeyProofMethod::Jws => jws::key_id(self.req, self.body),
+            KeyProofMethod::Mtls => None,
+        }
+    }
+
+    /// The client instance key as proven by the request.
+    ///
+    /// For mTLS, the key is pinned to the certificate presented on the
+    /// connection, so that issued tokens can be bound to it.
+    pub fn proven_key(&self, key: Option<&ClientKey>) -> Option<ClientKey> {
+        let mut key = key.cloned()?;
+        if key.proof == KeyProofMethod::Mtls {
+            if let Some(certificate) = self.req.conn_data::<ClientCertificate>() {
+                key.cert_s256 = Some(certificate.thumbprint.clone());
+            }
+        }
+        Some(key)
+    }
+}
+
+/// The error returned for any failed key proof.
+pub fn invalid_client() -> GnapError {
+    GnapError::ProtocolError(GnapErrorCode::InvalidClient)
+}
+
+/// Check a proof creation time against the AS clock.
+///
+/// Proofs are accepted for the proof window after they are created, and
+/// may be created up to the clock skew allowance in the future.
+pub fn check_created(created: u64, now: u64) -> Result<(), GnapError> {
+    if created + proof_window() < now || created > now + clock_skew() {
+        error!("Proof created at {} is outside the allowed window", created);
+        return Err(invalid_client());
+    }
+    Ok(())
+}
+
+/// The full URI the client instance made the request to.
+pub fn target_uri(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    let path = match req.uri().path_and_query() {
+        Some(path) => path.as_str(),
+        None => req.uri().path(),
+    };
+    format!("{}://{}{}", info.scheme(), info.host(), path)
+}
+
+/// Map a JWS algorithm name.  Symmetric algorithms and `none` are not
+/// accepted.
+pub fn jose_algorithm(alg: &str) -> Result<SignatureAlgorithm, GnapError> {
+    match alg {
+        "RS256" => Ok(SignatureAlgorithm::RsaPkcs1(Digest::Sha256)),
+        "RS384" => Ok(SignatureAlgorithm::RsaPkcs1(Digest::Sha384)),
+        "RS512" => Ok(SignatureAlgorithm::RsaPkcs1(Digest::Sha512)),
+        "PS256" => Ok(SignatureAlgorithm::RsaPss(Digest::Sha256)),
+        "PS384" => Ok(SignatureAlgorithm::RsaPss(Digest::Sha384)),
+        "PS512" => Ok(SignatureAlgorithm::RsaPss(Digest::Sha512)),
+        "ES256" => Ok(SignatureAlgorithm::Ecdsa(Digest::Sha256)),
+        "ES384" => Ok(SignatureAlgorithm::Ecdsa(Digest::Sha384)),
+        "EdDSA" => Ok(SignatureAlgorithm::Ed25519),
+        _ => {
+            error!("Unsupported JWS algorithm {}", alg);
+            Err(invalid_client())
+        }
+    }
+}
+
+/// Digests used by signature algorithms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Digest {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Digest {
+    pub fn hash(self, data: &[u8]) -> Result<Vec<u8>, GnapError> {
+        openssl::hash::hash(self.message_digest(), data)
+            .map(|digest| digest.to_vec())
+            .map_err(crypto_error)
+    }
+
+    fn message_digest(self) -> MessageDigest {
+        match self {
+            Digest::Sha256 => MessageDigest::sha256(),
+            Digest::Sha384 => MessageDigest::sha384(),
+            Digest::Sha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+/// Signature algorithms accepted in key proofs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    RsaPkcs1(Digest),
+    RsaPss(Digest),
+    /// ECDSA, with the signature as the concatenated `r` and `s` values.
+    Ecdsa(Digest),
+    Ed25519,
+}
+
+impl SignatureAlgorithm {
+    /// The digest the algorithm signs with.  Ed25519 uses SHA-512
+    /// internally.
+    pub fn digest(self) -> Digest {
+        match self {
+            SignatureAlgorithm::RsaPkcs1(digest)
+            | SignatureAlgorithm::RsaPss(digest)
+            | SignatureAlgorithm::Ecdsa(digest) => digest,
+            SignatureAlgorithm::Ed25519 => Digest::Sha512,
+        }
+    }
+}
+
+/// Verify a signature made with the private half of `key`.
+///
+/// Fails if the key cannot be used with the algorithm.
+pub fn verify_signature(
+    key: &PKey<Public>,
+    alg: SignatureAlgorithm,
+    data: &[u8],
+    signature: &[u8],
+) -> Result<bool, GnapError> {
+    match (alg, key.id()) {
+        (SignatureAlgorithm::RsaPkcs1(digest), Id::RSA) => {
+            let mut verifier = Verifier::new(digest.message_digest(), key).map_err(crypto_error)?;
+            verifier.set_rsa_padding(Padding::PKCS1).map_err(crypto_error)?;
+            verifier.verify_oneshot(signature, data).map_err(crypto_error)
+        }
+        (SignatureAlgorithm::RsaPss(digest), Id::RSA) => {
+            let mut verifier = Verifier::new(digest.message_digest(), key).map_err(crypto_error)?;
+            verifier.set_rsa_padding(Padding::PKCS1_PSS).map_err(crypto_error)?;
+            verifier
+                .set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)
+                .map_err(crypto_error)?;
+            verifier
+                .set_rsa_mgf1_md(digest.message_digest())
+                .map_err(crypto_error)?;
+            verifier.verify_oneshot(signature, data).map_err(crypto_error)
+        }
+        (SignatureAlgorithm::Ecdsa(digest), Id::EC) => {
+            // The curve must match the digest size, as it does in the JOSE
+            // and HTTP signature algorithm definitions.
+            let size = match digest {
+                Digest::Sha256 => 32,
+                Digest::Sha384 => 48,
+                Digest::Sha512 => 66,
+            };
+            let degree = key.ec_key().map_err(crypto_error)?.group().degree() as usize;
+            if degree.div_ceil(8) != size || signature.len() != size * 2 {
+                return Ok(false);
+            }
+            let der = EcdsaSig::from_private_components(
+                BigNum::from_slice(&signature[..size]).map_err(crypto_error)?,
+                BigNum::from_slice(&signature[size..]).map_err(crypto_error)?,
+            )
+            .and_then(|sig| sig.to_der())
+            .map_err(crypto_error)?;
+            let mut verifier = Verifier::new(digest.message_digest(), key).map_err(crypto_error)?;
+            verifier.verify_oneshot(&der, data).map_err(crypto_error)
+        }
+        (SignatureAlgorithm::Ed25519, Id::ED25519) => {
+            let mut verifier = Verifier::new_without_digest(key).map_err(crypto_error)?;
+            verifier.verify_oneshot(signature, data).map_err(crypto_error)
+        }
+        (alg, _) => {
+            error!("Key cannot be used with {:?}", alg);
+            Err(invalid_client())
+        }
+    }
+}
+
+/// Sign with the algorithm, producing signatures in the form
+/// [verify_signature] expects.
+#[cfg(test)]
+pub fn sign(key: &PKey<openssl::pkey::Private>, alg: SignatureAlgorithm, data: &[u8]) -> Vec<u8> {
+    use openssl::sign::Signer;
+
+    match alg {
+        SignatureAlgorithm::RsaPkcs1(digest) => {
+            let mut signer = Signer::new(digest.message_digest(), key).unwrap();
+            signer.sign_oneshot_to_vec(data).unwrap()
+        }
+        SignatureAlgorithm::RsaPss(digest) => {
+            let mut signer = Signer::new(digest.message_digest(), key).unwrap();
+            signer.set_rsa_padding(Padding::PKCS1_PSS).unwrap();
+            signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH).unwrap();
+            signer.set_rsa_mgf1_md(digest.message_digest()).unwrap();
+            signer.sign_oneshot_to_vec(data).unwrap()
+        }
+        SignatureAlgorithm::Ecdsa(digest) => {
+            let hash = openssl::hash::hash(digest.message_digest(), data).unwrap();
+            let sig = EcdsaSig::sign(&hash, &key.ec_key().unwrap()).unwrap();
+            let size = (key.ec_key().unwrap().group().degree() as i32 + 7) / 8;
+            let mut raw = sig.r().to_vec_padded(size).unwrap();
+            raw.extend(sig.s().to_vec_padded(size).unwrap());
+            raw
+        }
+        SignatureAlgorithm::Ed25519 => {
+            let mut signer = Signer::new_without_digest(key).unwrap();
+            signer.sign_oneshot_to_vec(data).unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        rsa::Rsa,
+    };
+
+    fn public(key: &PKey<openssl::pkey::Private>) -> PKey<Public> {
+        PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn signature_algorithms() {
+        let rsa = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let ed = PKey::generate_ed25519().unwrap();
+
+        let cases = [
+            (&rsa, SignatureAlgorithm::RsaPkcs1(Digest::Sha256)),
+            (&rsa, SignatureAlgorithm::RsaPss(Digest::Sha512)),
+            (&ec, SignatureAlgorithm::Ecdsa(Digest::Sha256)),
+            (&ed, SignatureAlgorithm::Ed25519),
+        ];
+        for (key, alg) in cases.iter() {
+            let signature = sign(key, *alg, b"data");
+            assert!(verify_signature(&public(key), *alg, b"data", &signature).unwrap());
+            assert!(!verify_signature(&public(key), *alg, b"other", &signature).unwrap());
+        }
+    }
+
+    #[test]
+    fn created_window() {
+        let now = 1_000_000;
+        assert!(check_created(now, now).is_ok());
+        assert!(check_created(now - proof_window(), now).is_ok());
+        assert!(check_created(now - proof_window() - 1, now).is_err());
+        assert!(check_created(now + clock_skew(), now).is_ok());
+        assert!(check_created(now + clock_skew() + 1, now).is_err());
+    }
+
+    #[test]
+    fn proof_identity() {
+        let proof = Proof::new(1, Some("n1"), b"signed");
+        assert_eq!(proof, Proof::new(1, Some("n1"), b"signed"));
+        assert_ne!(proof.signature_id, Proof::new(1, Some("n1"), b"other").signature_id);
+        assert_eq!(proof.nonce, Some("n1".to_owned()));
+    }
+
+    #[test]
+    fn key_algorithm_mismatch() {
+        let ed = PKey::generate_ed25519().unwrap();
+        let signature = sign(&ed, SignatureAlgorithm::Ed25519, b"data");
+        let alg = SignatureAlgorithm::RsaPkcs1(Digest::Sha256);
+        assert!(verify_signature(&public(&ed), alg, b"data", &signature).is_err());
+
+        // P-256 keys only sign with SHA-256.
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let signature = sign(&ec, SignatureAlgorithm::Ecdsa(Digest::Sha256), b"data");
+        let alg = SignatureAlgorithm::Ecdsa(Digest::Sha384);
+        assert!(!verify_signature(&public(&ec), alg, b"data", &signature).unwrap());
+    }
+}
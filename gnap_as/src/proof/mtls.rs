@@ -0,0 +1,137 @@
+//! Mutual TLS (mtls) key proofing.
+//!
+//! The client instance proves its key by presenting a certificate for it on
+//! the TLS connection.  The TLS handshake has already shown that the client
+//! holds the certificate key, so all that is left is to match the
+//! certificate to the client instance key.
+//!
+use super::invalid_client;
+use crate::keys::{crypto_error, jwk::public_key};
+use actix_web::HttpRequest;
+use errors::GnapError;
+use gnap_as::ClientCertificate;
+use log::error;
+use model::key::ClientKey;
+use openssl::x509::X509;
+
+/// Verify the certificate presented on the request connection.
+pub fn verify(req: &HttpRequest, key: &ClientKey) -> Result<(), GnapError> {
+    verify_certificate(req.conn_data::<ClientCertificate>(), key)
+}
+
+/// Match a presented certificate to the client instance key.
+///
+/// The key may name the certificate by its thumbprint, carry the whole
+/// certificate, or carry the certificate public key as a JWK.
+pub fn verify_certificate(certificate: Option<&ClientCertificate>, key: &ClientKey) -> Result<(), GnapError> {
+    let certificate = match certificate {
+        Some(certificate) => certificate,
+        None => {
+            error!("No client certificate was presented");
+            return Err(invalid_client());
+        }
+    };
+
+    let matched = if let Some(thumbprint) = &key.cert_s256 {
+        *thumbprint == certificate.thumbprint
+    } else if let Some(cert) = &key.cert {
+        let der = base64::decode(cert).map_err(|_| invalid_client())?;
+        der == certificate.der
+    } else if let Some(jwk) = &key.jwk {
+        let presented = X509::from_der(&certificate.der)
+            .and_then(|cert| cert.public_key())
+            .map_err(crypto_error)?;
+        let expected = public_key(jwk)?;
+        presented.public_eq(&expected)
+    } else {
+        error!("Client instance key has no certificate to match");
+        false
+    };
+
+    if !matched {
+        error!("Client certificate {} does not match the client instance key", certificate.thumbprint);
+        return Err(invalid_client());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::jwk::public_jwk;
+    use model::key::KeyProofMethod;
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        x509::X509NameBuilder,
+    };
+
+    fn certificate(key: &PKey<Private>) -> ClientCertificate {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "client").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(key, MessageDigest::null()).unwrap();
+        ClientCertificate::from_der(builder.build().to_der().unwrap()).unwrap()
+    }
+
+    fn client_key() -> ClientKey {
+        ClientKey {
+            proof: KeyProofMethod::Mtls,
+            jwk: None,
+            cert: None,
+            cert_s256: None,
+        }
+    }
+
+    #[test]
+    fn matches_certificate() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let cert = certificate(&pkey);
+
+        let key = ClientKey {
+            cert_s256: Some(cert.thumbprint.clone()),
+            ..client_key()
+        };
+        assert!(verify_certificate(Some(&cert), &key).is_ok());
+
+        let key = ClientKey {
+            cert: Some(base64::encode(&cert.der)),
+            ..client_key()
+        };
+        assert!(verify_certificate(Some(&cert), &key).is_ok());
+
+        let key = ClientKey {
+            jwk: Some(public_jwk(&pkey).unwrap()),
+            ..client_key()
+        };
+        assert!(verify_certificate(Some(&cert), &key).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_certificate() {
+        let cert = certificate(&PKey::generate_ed25519().unwrap());
+        let other = PKey::generate_ed25519().unwrap();
+
+        let key = ClientKey {
+            cert_s256: Some(certificate(&other).thumbprint),
+            ..client_key()
+        };
+        assert!(verify_certificate(Some(&cert), &key).is_err());
+
+        let key = ClientKey {
+            jwk: Some(public_jwk(&other).unwrap()),
+            ..client_key()
+        };
+        assert!(verify_certificate(Some(&cert), &key).is_err());
+
+        assert!(verify_certificate(Some(&cert), &client_key()).is_err());
+        assert!(verify_certificate(None, &client_key()).is_err());
+    }
+}
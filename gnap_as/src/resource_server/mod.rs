@@ -0,0 +1,109 @@
+//! Resource server registration and authentication.
+//!
+//! RSs call the AS, for instance to introspect access tokens, with HTTP
+//! Basic authentication using the ID and secret they were given when they
+//! were registered.
+//!
+use crate::keys::{create_secret, hash_secret, verify_secret};
+use actix_web::{http::header, HttpRequest};
+use dao::service::Service;
+use errors::{GnapError, GnapErrorCode};
+use log::{error, trace};
+use model::resource::{ResourceServer, ResourceServerCredentials, ResourceServerRequest};
+use uuid::Uuid;
+
+/// Register a new RS and create its credentials.
+pub async fn register(
+    service: &Service,
+    request: ResourceServerRequest,
+) -> Result<ResourceServerCredentials, GnapError> {
+    let secret = create_secret()?;
+    let rs = ResourceServer {
+        rs_id: Uuid::new_v4(),
+        name: request.name,
+        secret_hash: hash_secret(&secret),
+        locations: request.locations,
+        resource_types: request.resource_types,
+    };
+    service.add_resource_server(&rs).await?;
+    trace!("Registered resource server {}", &rs.rs_id);
+
+    Ok(ResourceServerCredentials {
+        rs_id: rs.rs_id,
+        name: rs.name,
+        secret,
+    })
+}
+
+/// Authenticate the RS making a request.
+pub async fn authenticate(service: &Service, req: &HttpRequest) -> Result<ResourceServer, GnapError> {
+    let unauthorized = GnapError::ProtocolError(GnapErrorCode::InvalidClient);
+    let (rs_id, secret) = match basic_credentials(req) {
+        Some(credentials) => credentials,
+        None => {
+            error!("Resource server request without credentials");
+            return Err(unauthorized);
+        }
+    };
+    let rs_id = Uuid::parse_str(&rs_id).map_err(|_| {
+        error!("Malformed resource server ID: {}", &rs_id);
+        GnapError::ProtocolError(GnapErrorCode::InvalidClient)
+    })?;
+
+    match service.get_resource_server(&rs_id).await? {
+        Some(rs) if verify_secret(&secret, &rs.secret_hash) => Ok(rs),
+        _ => {
+            error!("Resource server {} failed to authenticate", &rs_id);
+            Err(unauthorized)
+        }
+    }
+}
+
+/// Get the ID and secret from a Basic authorization header.
+fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.split_once(' ')?;
+    if scheme != "Basic" {
+        return None;
+    }
+    let credentials = String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()?;
+    let (id, secret) = credentials.split_once(':')?;
+    Some((id.to_owned(), secret.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn basic_auth() {
+        let value = format!("Basic {}", base64::encode("rs1:s3cret"));
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, value))
+            .to_http_request();
+        assert_eq!(
+            basic_credentials(&req),
+            Some(("rs1".to_owned(), "s3cret".to_owned()))
+        );
+
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "GNAP abc123"))
+            .to_http_request();
+        assert_eq!(basic_credentials(&req), None);
+    }
+
+    #[test]
+    fn secret_verification() {
+        let secret = create_secret().unwrap();
+        let rs = ResourceServer {
+            rs_id: Uuid::new_v4(),
+            name: "rs".to_owned(),
+            secret_hash: hash_secret(&secret),
+            locations: Vec::new(),
+            resource_types: Vec::new(),
+        };
+        assert!(verify_secret(&secret, &rs.secret_hash));
+        assert!(!verify_secret("guess", &rs.secret_hash));
+    }
+}
@@ -30,6 +30,11 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/db")
             .service(web::resource("/client/{id}").route(web::get().to(handlers::db::get_client)))
-            .service(web::resource("/client").route(web::put().to(handlers::db::add_client))),
+            .service(web::resource("/client").route(web::put().to(handlers::db::add_client)))
+            .service(web::resource("/account").route(web::put().to(handlers::db::add_account)))
+            .service(
+                web::resource("/resource_server")
+                    .route(web::put().to(handlers::db::add_resource_server)),
+            ),
     );
 }
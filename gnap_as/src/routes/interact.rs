@@ -0,0 +1,23 @@
+use crate::handlers;
+use actix_web::web;
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/gnap/interact/{interaction_id}")
+            .route(web::get().to(handlers::interact::interaction_page)),
+    )
+    .service(
+        web::resource("/gnap/interact/{interaction_id}/login")
+            .route(web::post().to(handlers::interact::interaction_login)),
+    )
+    .service(
+        web::resource("/gnap/interact/{interaction_id}/consent")
+            .route(web::post().to(handlers::interact::interaction_consent)),
+    )
+    .service(
+        web::resource("/gnap/device")
+            .route(web::get().to(handlers::interact::user_code_page))
+            .route(web::post().to(handlers::interact::user_code_entry)),
+    )
+    .service(web::resource("/gnap/app").route(web::get().to(handlers::interact::app_interaction)));
+}
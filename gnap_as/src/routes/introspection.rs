@@ -0,0 +1,9 @@
+use crate::handlers;
+use actix_web::web;
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/gnap/introspect")
+            .route(web::post().to(handlers::introspection::introspect_token)),
+    );
+}
@@ -0,0 +1,6 @@
+use crate::handlers;
+use actix_web::web;
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/gnap/jwks").route(web::get().to(handlers::keys::jwks)));
+}
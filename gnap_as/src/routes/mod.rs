@@ -1,5 +1,9 @@
 pub mod transaction;
 pub mod well_known;
 pub mod db;
+pub mod introspection;
+pub mod token;
+pub mod keys;
+pub mod interact;
 //mod with_service;
 //pub mod rejection;
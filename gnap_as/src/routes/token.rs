@@ -0,0 +1,10 @@
+use crate::handlers;
+use actix_web::web;
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/gnap/token/{token_id}")
+            .route(web::post().to(handlers::token::rotate))
+            .route(web::delete().to(handlers::token::revoke)),
+    );
+}
@@ -1,12 +1,16 @@
 use crate::handlers;
 use actix_web::{http, web};
 
+// Other route modules also serve paths under /gnap, so resources are
+// registered with their full path rather than in a shared scope.
 pub fn routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
-        web::scope("/gnap").service(
-            web::resource("/tx")
-                .route(web::post().to(handlers::transaction::grant_request))
-                .route(web::method(http::Method::OPTIONS).to(handlers::transaction::grant_options)),
-        ),
+        web::resource("/gnap/tx")
+            .route(web::post().to(handlers::transaction::grant_request))
+            .route(web::method(http::Method::OPTIONS).to(handlers::transaction::grant_options)),
+    )
+    .service(
+        web::resource("/gnap/tx/{tx_id}")
+            .route(web::post().to(handlers::transaction::continue_request)),
     );
-}
\ No newline at end of file
+}
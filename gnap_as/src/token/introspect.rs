@@ -0,0 +1,208 @@
+//! Token introspection for resource servers.
+//!
+use super::paseto;
+use crate::keys::signing_keys;
+use dao::service::Service;
+use errors::GnapError;
+use gnap_as::get_as_host;
+use log::trace;
+use model::{
+    grant::AccessRequest,
+    resource::ResourceServer,
+    token::{GnapAccessToken, IntrospectionRequest, IntrospectionResponse},
+};
+
+/// Introspect a token value presented to an RS.
+///
+/// Tokens are looked up by value, so this works for every token format.
+/// Only tokens carrying access for the RS are reported to it.
+/// PASETO values must also verify, or decrypt, with an AS key, and name the
+/// token they were found as.
+pub async fn introspect(
+    service: &Service,
+    rs: &ResourceServer,
+    request: &IntrospectionRequest,
+) -> Result<IntrospectionResponse, GnapError> {
+    let issuer = get_as_host();
+    let mut token = service.get_access_token_by_value(&request.access_token).await?;
+    if let Some(found) = &token {
+        if paseto::is_paseto(&request.access_token) {
+            let keys = signing_keys(service).await?;
+            match paseto::decode_token(&request.access_token, &issuer, &keys) {
+                Ok(claims) if claims.jti == found.token_id => {}
+                _ => {
+                    trace!("Introspected PASETO for {} does not verify", &found.token_id);
+                    token = None;
+                }
+            }
+        }
+    }
+    Ok(introspection_response(token.as_ref(), request, rs, &issuer))
+}
+
+/// Unknown and expired tokens are inactive, as are tokens that do not carry
+/// all of the access the RS asked about, tokens with no access for the RS,
+/// and bound tokens presented with a different proofing method than their
+/// key's.  Retired values of durable tokens are active until they expire.
+/// The RS is only told about the access it serves.
+fn introspection_response(
+    token: Option<&GnapAccessToken>,
+    request: &IntrospectionRequest,
+    rs: &ResourceServer,
+    issuer: &str,
+) -> IntrospectionResponse {
+    let (token, expires_at) = match token
+        .and_then(|token| Some((token, token.value_expires_at(&request.access_token)?)))
+    {
+        Some(found) => found,
+        None => {
+            trace!("Introspected token is unknown or expired");
+            return IntrospectionResponse::inactive();
+        }
+    };
+    if let Some(access) = &request.access {
+        if !access.iter().all(|access| token.access.contains(access)) {
+            trace!("Token {} does not carry the requested access", &token.token_id);
+            return IntrospectionResponse::inactive();
+        }
+    }
+    if let (Some(proof), Some(key)) = (&request.proof, &token.key) {
+        if *proof != key.proof {
+            trace!("Token {} is bound to a key proven with {:?}", &token.token_id, key.proof);
+            return IntrospectionResponse::inactive();
+        }
+    }
+    let access: Vec<AccessRequest> = token.access.iter().filter(|access| rs.serves(access)).cloned().collect();
+    if access.is_empty() {
+        trace!("Token {} was not issued for RS {}", &token.token_id, &rs.rs_id);
+        return IntrospectionResponse::inactive();
+    }
+    let mut response = token.to_introspection(issuer, expires_at);
+    response.access = Some(access);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::grant::AccessTokenFlag;
+    use uuid::Uuid;
+    use model::key::{ClientKey, KeyProofMethod};
+
+    const ISSUER: &str = "https://as.example";
+
+    fn rs(resource_types: &[&str], locations: &[&str]) -> ResourceServer {
+        ResourceServer {
+            rs_id: Uuid::new_v4(),
+            name: "rs".to_owned(),
+            secret_hash: String::new(),
+            locations: locations.iter().map(|location| location.to_string()).collect(),
+            resource_types: resource_types.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    fn foo_rs() -> ResourceServer {
+        rs(&["foo"], &[])
+    }
+
+    fn request(value: &str, access: Option<Vec<AccessRequest>>) -> IntrospectionRequest {
+        IntrospectionRequest {
+            access_token: value.to_owned(),
+            proof: None,
+            access,
+        }
+    }
+
+    #[test]
+    fn active_token() {
+        let foo = AccessRequest::Reference("foo".to_owned());
+        let token = GnapAccessToken::new("tx", vec![foo.clone()], 60);
+
+        let response = introspection_response(Some(&token), &request(&token.value, None), &foo_rs(), ISSUER);
+        assert!(response.active);
+        assert_eq!(response.access, Some(vec![foo.clone()]));
+        assert_eq!(response.exp, Some(token.expires_at));
+
+        let response = introspection_response(Some(&token), &request(&token.value, Some(vec![foo])), &foo_rs(), ISSUER);
+        assert!(response.active);
+    }
+
+    #[test]
+    fn inactive_token() {
+        let foo = AccessRequest::Reference("foo".to_owned());
+        let mut token = GnapAccessToken::new("tx", vec![foo], 60);
+
+        assert!(!introspection_response(None, &request(&token.value, None), &foo_rs(), ISSUER).active);
+
+        let bar = AccessRequest::Reference("bar".to_owned());
+        let response = introspection_response(Some(&token), &request(&token.value, Some(vec![bar])), &foo_rs(), ISSUER);
+        assert!(!response.active);
+        assert!(response.access.is_none());
+
+        token.expires_at = token.issued_at - 1;
+        assert!(!introspection_response(Some(&token), &request(&token.value, None), &foo_rs(), ISSUER).active);
+    }
+
+    #[test]
+    fn rotated_values() {
+        let foo = AccessRequest::Reference("foo".to_owned());
+        let mut token = GnapAccessToken::new("tx", vec![foo.clone()], 60);
+        let old = token.clone();
+        token.rotate();
+        assert!(!introspection_response(Some(&token), &request(&old.value, None), &foo_rs(), ISSUER).active);
+
+        let mut token = GnapAccessToken::new("tx", vec![foo], 60);
+        token.flags.push(AccessTokenFlag::Durable);
+        let old = token.clone();
+        token.rotate();
+        let response = introspection_response(Some(&token), &request(&old.value, None), &foo_rs(), ISSUER);
+        assert!(response.active);
+        assert_eq!(response.exp, Some(old.expires_at));
+    }
+
+    #[test]
+    fn bound_token() {
+        let mut token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        token.key = Some(ClientKey {
+            proof: KeyProofMethod::Httpsig,
+            jwk: None,
+            cert: None,
+            cert_s256: None,
+        });
+
+        let mut request = request(&token.value, None);
+        request.proof = Some(KeyProofMethod::Httpsig);
+        let response = introspection_response(Some(&token), &request, &foo_rs(), ISSUER);
+        assert!(response.active);
+        assert_eq!(response.key, token.key);
+
+        request.proof = Some(KeyProofMethod::Mtls);
+        assert!(!introspection_response(Some(&token), &request, &foo_rs(), ISSUER).active);
+    }
+
+    #[test]
+    fn other_resource_server() {
+        let foo = AccessRequest::Reference("foo".to_owned());
+        let photos = AccessRequest::Value {
+            resource_type: "photo-api".to_owned(),
+            actions: None,
+            locations: Some(vec!["https://photos.example".to_owned()]),
+            data_types: None,
+        };
+        let token = GnapAccessToken::new("tx", vec![foo.clone(), photos.clone()], 60);
+        let request = request(&token.value, None);
+
+        let response = introspection_response(Some(&token), &request, &foo_rs(), ISSUER);
+        assert!(response.active);
+        assert_eq!(response.access, Some(vec![foo]));
+
+        let response =
+            introspection_response(Some(&token), &request, &rs(&[], &["https://photos.example"]), ISSUER);
+        assert!(response.active);
+        assert_eq!(response.access, Some(vec![photos]));
+
+        // The resource type alone does not match access at a location.
+        assert!(!introspection_response(Some(&token), &request, &rs(&["photo-api"], &[]), ISSUER).active);
+        assert!(!introspection_response(Some(&token), &request, &rs(&["bar"], &[]), ISSUER).active);
+    }
+}
@@ -0,0 +1,185 @@
+//! JWT access token format.
+//!
+//! JWT access tokens carry the [AccessTokenClaims] for the token, signed
+//! with an AS managed key.  Other JWTs the AS hands out are signed the same
+//! way, and told apart by their `typ` header.
+//!
+use crate::keys::crypto_error;
+use errors::GnapError;
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use log::error;
+use model::{
+    key::SigningKey,
+    token::{AccessTokenClaims, GnapAccessToken},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// JWT `typ` header for access tokens (RFC 9068).
+const ACCESS_TOKEN_TYP: &str = "at+jwt";
+
+/// Encode an issued token as a signed JWT.
+pub fn encode_token(
+    token: &GnapAccessToken,
+    issuer: &str,
+    key: &SigningKey,
+) -> Result<String, GnapError> {
+    let claims = AccessTokenClaims::new(token, issuer);
+    encode_jwt(&claims, ACCESS_TOKEN_TYP, key)
+}
+
+/// Sign claims as a JWT of type `typ`.
+pub fn encode_jwt<T: Serialize>(claims: &T, typ: &str, key: &SigningKey) -> Result<String, GnapError> {
+    let alg = jwt_algorithm(key)?;
+    let mut header = Header::new(alg);
+    header.typ = Some(typ.to_owned());
+    header.kid = Some(key.kid.clone());
+    encode(&header, claims, &encoding_key(alg, key)?).map_err(crypto_error)
+}
+
+/// Verify a JWT of type `typ`, signed by `issuer` with one of `keys`, and
+/// get its claims.  The JWT must not have expired.
+pub fn decode_jwt<T: DeserializeOwned>(
+    value: &str,
+    typ: &str,
+    issuer: &str,
+    keys: &[SigningKey],
+) -> Result<T, GnapError> {
+    let header = decode_header(value).map_err(crypto_error)?;
+    if header.typ.as_deref() != Some(typ) {
+        error!("JWT is not a {}", typ);
+        return Err(GnapError::CryptoError(format!("JWT is not a {}", typ)));
+    }
+    let key = keys
+        .iter()
+        .find(|key| Some(&key.kid) == header.kid.as_ref())
+        .ok_or_else(|| GnapError::CryptoError("JWT is not signed with a known key".to_owned()))?;
+    let alg = jwt_algorithm(key)?;
+    let validation = Validation {
+        iss: Some(issuer.to_owned()),
+        algorithms: vec![alg],
+        ..Validation::default()
+    };
+    let data = decode::<T>(value, &decoding_key(alg, key)?, &validation).map_err(crypto_error)?;
+    Ok(data.claims)
+}
+
+fn jwt_algorithm(key: &SigningKey) -> Result<Algorithm, GnapError> {
+    match key.alg.jwt_algorithm() {
+        Some(alg) => Ok(alg),
+        None => {
+            error!("Key {} cannot be used to sign JWTs", &key.kid);
+            Err(GnapError::CryptoError(format!("{} is not a JWT algorithm", key.alg)))
+        }
+    }
+}
+
+fn encoding_key(alg: Algorithm, key: &SigningKey) -> Result<EncodingKey, GnapError> {
+    let pem = key.private_key.as_bytes();
+    match alg {
+        Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(pem),
+        _ => EncodingKey::from_rsa_pem(pem),
+    }
+    .map_err(crypto_error)
+}
+
+fn decoding_key(alg: Algorithm, key: &SigningKey) -> Result<DecodingKey<'_>, GnapError> {
+    let pem = match &key.public_key {
+        Some(public_key) => public_key.as_bytes(),
+        None => {
+            error!("Key {} has no public key", &key.kid);
+            return Err(GnapError::CryptoError(format!("{} has no public key", key.kid)));
+        }
+    };
+    match alg {
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+        _ => DecodingKey::from_rsa_pem(pem),
+    }
+    .map_err(crypto_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::generate_key;
+    use model::{
+        grant::AccessRequest,
+        key::{ClientKey, KeyAlgorithm, KeyProofMethod},
+    };
+
+    const ISSUER: &str = "https://as.example";
+
+    fn decode_token(value: &str, issuer: &str, key: &SigningKey) -> Result<AccessTokenClaims, GnapError> {
+        decode_jwt(value, ACCESS_TOKEN_TYP, issuer, std::slice::from_ref(key))
+    }
+
+    fn round_trip(alg: KeyAlgorithm) {
+        let key = generate_key(alg).expect("key generation failed");
+        let access = vec![AccessRequest::Reference("foo".to_owned())];
+        let token = GnapAccessToken::new("tx", access.clone(), 60);
+
+        let value = encode_token(&token, ISSUER, &key).expect("encode failed");
+        let header = jsonwebtoken::decode_header(&value).unwrap();
+        assert_eq!(header.kid, Some(key.kid.clone()));
+        assert_eq!(header.typ, Some(ACCESS_TOKEN_TYP.to_owned()));
+
+        let claims = decode_token(&value, ISSUER, &key).expect("decode failed");
+        assert_eq!(claims.jti, token.token_id);
+        assert_eq!(claims.access, access);
+        assert_eq!(claims.exp, token.expires_at);
+    }
+
+    #[test]
+    fn rs256_round_trip() {
+        round_trip(KeyAlgorithm::RS256);
+    }
+
+    #[test]
+    fn es256_round_trip() {
+        round_trip(KeyAlgorithm::ES256);
+    }
+
+    #[test]
+    fn bound_token_confirmation() {
+        let key = generate_key(KeyAlgorithm::ES256).unwrap();
+        let mut token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        let value = encode_token(&token, ISSUER, &key).unwrap();
+        assert!(decode_token(&value, ISSUER, &key).unwrap().cnf.is_none());
+
+        token.key = Some(ClientKey {
+            proof: KeyProofMethod::Mtls,
+            jwk: None,
+            cert: None,
+            cert_s256: Some("thumbprint".to_owned()),
+        });
+        let value = encode_token(&token, ISSUER, &key).unwrap();
+        let cnf = decode_token(&value, ISSUER, &key).unwrap().cnf.expect("token not bound");
+        assert_eq!(cnf.x5t_s256, Some("thumbprint".to_owned()));
+    }
+
+    #[test]
+    fn not_a_jwt_key() {
+        let key = generate_key(KeyAlgorithm::EdDSA).unwrap();
+        let token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        assert!(encode_token(&token, ISSUER, &key).is_err());
+    }
+
+    #[test]
+    fn wrong_issuer() {
+        let key = generate_key(KeyAlgorithm::ES256).unwrap();
+        let token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        let value = encode_token(&token, ISSUER, &key).unwrap();
+        assert!(decode_token(&value, "https://other.example", &key).is_err());
+    }
+
+    #[test]
+    fn wrong_type() {
+        let key = generate_key(KeyAlgorithm::ES256).unwrap();
+        let token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        let value = encode_token(&token, ISSUER, &key).unwrap();
+        assert!(decode_jwt::<AccessTokenClaims>(&value, "other+jwt", ISSUER, std::slice::from_ref(&key)).is_err());
+        let other = generate_key(KeyAlgorithm::ES256).unwrap();
+        assert!(decode_jwt::<AccessTokenClaims>(&value, ACCESS_TOKEN_TYP, ISSUER, &[other]).is_err());
+    }
+}
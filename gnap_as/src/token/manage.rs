@@ -0,0 +1,80 @@
+//! Access token management.
+//!
+//! Each issued token has a management URI.  The client instance rotates the
+//! token by POSTing to it, and revokes it with a DELETE.  Management requests
+//! are authorized with the current value of the token being managed, and
+//! signed with the key of the client instance the token was issued to.
+//!
+use super::{encode_value, token_response};
+use crate::proof::ProofRequest;
+use dao::service::Service;
+use errors::{GnapError, GnapErrorCode};
+use log::{error, trace};
+use model::{grant::AccessToken, token::GnapAccessToken};
+use openssl::memcmp;
+
+/// Rotate a token to a new value.  The old value is revoked, unless the
+/// token is durable.
+///
+/// The new value is only saved if the presented value is still current, so
+/// of several requests rotating the same value, only one succeeds.
+pub async fn rotate_token(
+    service: &Service,
+    token_id: &str,
+    presented: &str,
+    proof: &ProofRequest<'_>,
+) -> Result<AccessToken, GnapError> {
+    let mut token = match managed_token(service, token_id, presented, proof).await? {
+        Some(token) => token,
+        None => {
+            error!("Cannot rotate unknown token {}", token_id);
+            return Err(GnapError::ProtocolError(GnapErrorCode::InvalidRotation));
+        }
+    };
+
+    let revoked_value = token.rotate();
+    token.value = encode_value(service, &token).await?;
+    if !service.rotate_access_token(&token, presented, revoked_value.as_deref()).await? {
+        error!("Token {} was rotated or revoked while it was rotated", token_id);
+        return Err(GnapError::ProtocolError(GnapErrorCode::InvalidRotation));
+    }
+    trace!("Rotated access token {}", token_id);
+    Ok(token_response(&token))
+}
+
+/// Revoke a token.  Revoking a token that is already gone succeeds.
+pub async fn revoke_token(
+    service: &Service,
+    token_id: &str,
+    presented: &str,
+    proof: &ProofRequest<'_>,
+) -> Result<(), GnapError> {
+    if let Some(token) = managed_token(service, token_id, presented, proof).await? {
+        service.revoke_access_token(&token).await?;
+        trace!("Revoked access token {}", token_id);
+    }
+    Ok(())
+}
+
+/// Get the token being managed, if it still exists.  The presented value
+/// must be the current value of the token, and the request must be signed
+/// with the key of the client instance the token was issued to.
+async fn managed_token(
+    service: &Service,
+    token_id: &str,
+    presented: &str,
+    proof: &ProofRequest<'_>,
+) -> Result<Option<GnapAccessToken>, GnapError> {
+    let token = match service.get_access_token(token_id).await? {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let matches = token.value.len() == presented.len()
+        && memcmp::eq(token.value.as_bytes(), presented.as_bytes());
+    if !matches {
+        error!("Management request for {} presented the wrong token", token_id);
+        return Err(GnapError::ProtocolError(GnapErrorCode::InvalidClient));
+    }
+    proof.verify(service, token.client_key.as_ref(), Some(presented)).await?;
+    Ok(Some(token))
+}
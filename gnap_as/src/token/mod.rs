@@ -0,0 +1,408 @@
+//! Access token issuance.
+//!
+use crate::keys::{get_signing_key, signing_alg};
+use dao::service::Service;
+use errors::{GnapError, GnapErrorCode};
+use gnap_as::get_as_host;
+use log::{error, trace};
+use model::{
+    grant::{AccessRequest, AccessToken, AccessTokenFlag, AccessTokenRequest, AccessTokenResponse},
+    key::KeyAlgorithm,
+    token::{GnapAccessToken, TokenFormat},
+    transaction::GnapTransaction,
+};
+use std::collections::HashSet;
+use std::env;
+
+pub mod introspect;
+pub mod jwt;
+pub mod manage;
+pub mod paseto;
+
+/// Default lifetime of an issued access token, in seconds.
+const DEFAULT_TOKEN_LIFETIME: u64 = 3600;
+
+/// Get the access token lifetime from ENV.
+pub fn token_lifetime() -> u64 {
+    env::var("GNAP_TOKEN_LIFETIME")
+        .ok()
+        .and_then(|lifetime| lifetime.parse().ok())
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME)
+}
+
+/// Get the default format of issued access tokens from ENV.
+///
+/// Defaults to JWT.
+pub fn token_format() -> TokenFormat {
+    env::var("GNAP_TOKEN_FORMAT")
+        .ok()
+        .and_then(|format| format.parse().ok())
+        .unwrap_or(TokenFormat::Jwt)
+}
+
+/// Get the format of access tokens issued to the transaction's client.
+///
+/// Clients may be registered with their own format.  Otherwise the AS
+/// default is used.
+async fn client_token_format(service: &Service, tx: &GnapTransaction) -> Result<TokenFormat, GnapError> {
+    if let Some(client_id) = &tx.client_id {
+        if let Some(format) = service.get_client(client_id).await?.and_then(|client| client.token_format) {
+            return Ok(format);
+        }
+    }
+    Ok(token_format())
+}
+
+/// Check the access token requests of a grant request.
+///
+/// Every request must ask for some access, so that a token can be issued
+/// for it once the grant is approved.  A single access token request may
+/// omit its label.  When several tokens are requested, every request must
+/// be labelled and the labels must be unique, since the client relies on
+/// them to tell the tokens apart.
+pub fn validate_token_requests(requests: &[AccessTokenRequest]) -> Result<(), GnapError> {
+    for token_request in requests.iter() {
+        if token_request.access.is_empty() {
+            error!("Access token request has no access rights");
+            return Err(GnapError::ProtocolError(GnapErrorCode::InvalidRequest));
+        }
+        if let Some(key) = &token_request.key {
+            if is_bearer(token_request) {
+                error!("A bearer access token cannot be bound to a key");
+                return Err(GnapError::ProtocolError(GnapErrorCode::InvalidRequest));
+            }
+            if key.confirmation().is_none() {
+                error!("Access token request key has no key material");
+                return Err(GnapError::ProtocolError(GnapErrorCode::InvalidRequest));
+            }
+        }
+    }
+    if requests.len() < 2 {
+        return Ok(());
+    }
+
+    let mut labels = HashSet::new();
+    for token_request in requests.iter() {
+        match &token_request.label {
+            None => {
+                error!("Multiple access token request without a label");
+                return Err(GnapError::ProtocolError(GnapErrorCode::InvalidRequest));
+            }
+            Some(label) => {
+                if !labels.insert(label) {
+                    error!("Duplicate access token label: {}", label);
+                    return Err(GnapError::ProtocolError(GnapErrorCode::InvalidRequest));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Issue an access token for each access token request in an approved
+/// transaction.  Returns `None` if the grant requested no access tokens.
+pub async fn issue_tokens(
+    service: &Service,
+    tx: &GnapTransaction,
+) -> Result<Option<AccessTokenResponse>, GnapError> {
+    let request = match &tx.request {
+        Some(request) => request,
+        None => {
+            error!("Transaction {} has no grant request", &tx.tx_id);
+            return Err(GnapError::BadData);
+        }
+    };
+
+    let format = client_token_format(service, tx).await?;
+    let mut tokens = Vec::new();
+    for token_request in request.access_token.iter() {
+        for mut token in new_tokens(tx, token_request)? {
+            token.format = format;
+            token.value = encode_value(service, &token).await?;
+            service.add_access_token(&token).await?;
+            trace!("Issued access token {} for {}", &token.token_id, &tx.tx_id);
+            tokens.push(token_response(&token));
+        }
+    }
+
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(AccessTokenResponse::from(tokens)))
+}
+
+/// The management URI of an issued token.
+pub fn manage_uri(token_id: &str) -> String {
+    format!("{}/gnap/token/{}", get_as_host(), token_id)
+}
+
+/// The token as it is presented to the client instance, with its
+/// management URI.
+fn token_response(token: &GnapAccessToken) -> AccessToken {
+    let mut response = token.to_response();
+    response.manage = Some(manage_uri(&token.token_id));
+    response
+}
+
+/// Produce the token value in the token's format.
+async fn encode_value(service: &Service, token: &GnapAccessToken) -> Result<String, GnapError> {
+    match token.format {
+        TokenFormat::Opaque => Ok(token.value.clone()),
+        TokenFormat::Jwt => {
+            let key = get_signing_key(service, signing_alg()).await?;
+            jwt::encode_token(token, &get_as_host(), &key)
+        }
+        TokenFormat::Paseto => {
+            let key = get_signing_key(service, KeyAlgorithm::EdDSA).await?;
+            paseto::encode_public(token, &get_as_host(), &key)
+        }
+        TokenFormat::PasetoLocal => {
+            let key = get_signing_key(service, KeyAlgorithm::V4Local).await?;
+            paseto::encode_local(token, &get_as_host(), &key)
+        }
+    }
+}
+
+/// Build the token records for a single access token request.
+///
+/// If the client asked for the token to be split, the requested access is
+/// split by resource type, and each token is given an AS chosen label and
+/// the `split` flag.  Otherwise a single token is issued.
+fn new_tokens(
+    tx: &GnapTransaction,
+    token_request: &AccessTokenRequest,
+) -> Result<Vec<GnapAccessToken>, GnapError> {
+    let split = token_request
+        .flags
+        .as_ref()
+        .is_some_and(|flags| flags.contains(&AccessTokenFlag::Split));
+    let groups = split_access(&token_request.access);
+    if !split || groups.len() < 2 {
+        return Ok(vec![new_token(tx, token_request)?]);
+    }
+
+    let mut tokens = Vec::new();
+    for access in groups {
+        let mut token = new_token(tx, token_request)?;
+        token.access = access;
+        token.label = Some(token.token_id.clone());
+        token.flags.push(AccessTokenFlag::Split);
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Group requested access by resource type, in the order the types first
+/// appear.  Access requested by reference is kept apart, since the AS
+/// cannot tell which type it refers to.
+fn split_access(access: &[AccessRequest]) -> Vec<Vec<AccessRequest>> {
+    let mut groups: Vec<(&str, Vec<AccessRequest>)> = Vec::new();
+    for request in access.iter() {
+        let key = match request {
+            AccessRequest::Reference(reference) => reference.as_str(),
+            AccessRequest::Value { resource_type, .. } => resource_type.as_str(),
+        };
+        match groups.iter_mut().find(|(group, _)| *group == key) {
+            Some((_, requests)) => requests.push(request.clone()),
+            None => groups.push((key, vec![request.clone()])),
+        }
+    }
+    groups.into_iter().map(|(_, requests)| requests).collect()
+}
+
+/// Build the token record for a single access token request.
+///
+/// The token is granted the requested access as is.  Only the flags that
+/// describe the issued token are carried over from the request.
+fn new_token(
+    tx: &GnapTransaction,
+    token_request: &AccessTokenRequest,
+) -> Result<GnapAccessToken, GnapError> {
+    if token_request.access.is_empty() {
+        error!("Access token request has no access rights");
+        return Err(GnapError::ProtocolError(GnapErrorCode::InvalidRequest));
+    }
+
+    let mut token = GnapAccessToken::new(&tx.tx_id, token_request.access.clone(), token_lifetime());
+    token.client_id = tx.client_id;
+    token.account_id = tx.interaction.as_ref().and_then(|interaction| interaction.account_id);
+    token.client_key = tx.key.clone();
+    // Tokens are bound to the requested key, or else to the client instance
+    // key, unless they are bearer tokens.
+    if !is_bearer(token_request) {
+        token.key = token_request.key.clone().or_else(|| tx.key.clone());
+    }
+    token.label = token_request.label.clone();
+    if let Some(flags) = &token_request.flags {
+        token.flags = flags
+            .iter()
+            .filter(|flag| matches!(flag, AccessTokenFlag::Bearer | AccessTokenFlag::Durable))
+            .copied()
+            .collect();
+    }
+    Ok(token)
+}
+
+fn is_bearer(token_request: &AccessTokenRequest) -> bool {
+    token_request
+        .flags
+        .as_ref()
+        .is_some_and(|flags| flags.contains(&AccessTokenFlag::Bearer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::key::{ClientKey, KeyProofMethod};
+
+    #[test]
+    fn token_from_request() {
+        let tx = GnapTransaction::new(None);
+        let mut token_request = AccessTokenRequest::new();
+        token_request.access.push(AccessRequest::Reference("foo".to_owned()));
+        token_request.label = Some("my_label".to_owned());
+        token_request.flags = Some(vec![AccessTokenFlag::Bearer, AccessTokenFlag::Split]);
+
+        let token = new_token(&tx, &token_request).expect("token not issued");
+        assert_eq!(token.tx_id, tx.tx_id);
+        assert_eq!(token.label, Some("my_label".to_owned()));
+        assert_eq!(token.access, token_request.access);
+        assert_eq!(token.flags, vec![AccessTokenFlag::Bearer]);
+        assert_eq!(token.expires_at - token.issued_at, token_lifetime());
+    }
+
+    #[test]
+    fn resource_owner_account() {
+        let mut tx = GnapTransaction::new(None);
+        let token = new_token(&tx, &labelled(None)).unwrap();
+        assert!(token.account_id.is_none());
+
+        let account_id = uuid::Uuid::new_v4();
+        tx.start_interaction();
+        tx.interaction.as_mut().unwrap().account_id = Some(account_id);
+        let token = new_token(&tx, &labelled(None)).unwrap();
+        assert_eq!(token.account_id, Some(account_id));
+    }
+
+    fn labelled(label: Option<&str>) -> AccessTokenRequest {
+        let mut token_request = AccessTokenRequest::new();
+        token_request.access.push(AccessRequest::Reference("foo".to_owned()));
+        token_request.label = label.map(|label| label.to_owned());
+        token_request
+    }
+
+    #[test]
+    fn token_request_labels() {
+        assert!(validate_token_requests(&[labelled(None)]).is_ok());
+        assert!(validate_token_requests(&[labelled(Some("a")), labelled(Some("b"))]).is_ok());
+        assert!(validate_token_requests(&[labelled(Some("a")), labelled(None)]).is_err());
+        assert!(validate_token_requests(&[labelled(Some("a")), labelled(Some("a"))]).is_err());
+        assert!(validate_token_requests(&[AccessTokenRequest::new()]).is_err());
+    }
+
+    fn key(proof: KeyProofMethod) -> ClientKey {
+        ClientKey {
+            proof,
+            jwk: None,
+            cert: None,
+            cert_s256: Some("thumbprint".to_owned()),
+        }
+    }
+
+    #[test]
+    fn key_binding() {
+        let mut tx = GnapTransaction::new(None);
+        tx.key = Some(key(KeyProofMethod::Mtls));
+        let mut token_request = labelled(None);
+
+        let token = new_token(&tx, &token_request).unwrap();
+        assert_eq!(token.key, tx.key);
+
+        token_request.key = Some(key(KeyProofMethod::Mtls));
+        token_request.key.as_mut().unwrap().cert_s256 = Some("other".to_owned());
+        assert!(validate_token_requests(std::slice::from_ref(&token_request)).is_ok());
+        let token = new_token(&tx, &token_request).unwrap();
+        assert_eq!(token.key, token_request.key);
+        assert_eq!(token.client_key, tx.key);
+
+        token_request.flags = Some(vec![AccessTokenFlag::Bearer]);
+        assert!(validate_token_requests(std::slice::from_ref(&token_request)).is_err());
+        token_request.key = None;
+        assert!(new_token(&tx, &token_request).unwrap().key.is_none());
+
+        // A requested key must carry key material.
+        let mut token_request = labelled(None);
+        token_request.key = Some(ClientKey {
+            cert_s256: None,
+            ..key(KeyProofMethod::Httpsig)
+        });
+        assert!(validate_token_requests(&[token_request]).is_err());
+    }
+
+    fn resource(resource_type: &str, action: &str) -> AccessRequest {
+        AccessRequest::Value {
+            resource_type: resource_type.to_owned(),
+            actions: Some(vec![action.to_owned()]),
+            locations: None,
+            data_types: None,
+        }
+    }
+
+    #[test]
+    fn split_by_resource_type() {
+        let tx = GnapTransaction::new(None);
+        let mut token_request = labelled(Some("mine"));
+        token_request.access = vec![
+            resource("photo-api", "read"),
+            resource("calendar", "read"),
+            resource("photo-api", "write"),
+        ];
+        token_request.flags = Some(vec![AccessTokenFlag::Split]);
+
+        let tokens = new_tokens(&tx, &token_request).expect("tokens not issued");
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].access, vec![resource("photo-api", "read"), resource("photo-api", "write")]);
+        assert_eq!(tokens[1].access, vec![resource("calendar", "read")]);
+        for token in tokens.iter() {
+            assert!(token.has_flag(AccessTokenFlag::Split));
+            assert_ne!(token.label, Some("mine".to_owned()));
+        }
+        assert_ne!(tokens[0].label, tokens[1].label);
+    }
+
+    #[test]
+    fn no_split_unless_requested() {
+        let tx = GnapTransaction::new(None);
+        let mut token_request = labelled(Some("mine"));
+        token_request.access = vec![resource("photo-api", "read"), resource("calendar", "read")];
+
+        let tokens = new_tokens(&tx, &token_request).expect("tokens not issued");
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].label, Some("mine".to_owned()));
+        assert!(!tokens[0].has_flag(AccessTokenFlag::Split));
+
+        // Nothing to split, so the request is issued as is.
+        token_request.access = vec![resource("photo-api", "read")];
+        token_request.flags = Some(vec![AccessTokenFlag::Split]);
+        let tokens = new_tokens(&tx, &token_request).expect("tokens not issued");
+        assert_eq!(tokens.len(), 1);
+        assert!(!tokens[0].has_flag(AccessTokenFlag::Split));
+    }
+
+    #[test]
+    fn response_has_manage_uri() {
+        let token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        let response = token_response(&token);
+        assert_eq!(
+            response.manage,
+            Some(format!("{}/gnap/token/{}", get_as_host(), &token.token_id))
+        );
+    }
+
+    #[test]
+    fn no_access_rights() {
+        let tx = GnapTransaction::new(None);
+        let token_request = AccessTokenRequest::new();
+        assert!(new_token(&tx, &token_request).is_err());
+    }
+}
@@ -0,0 +1,476 @@
+//! PASETO access token format.
+//!
+//! Version 4 tokens carry the same [AccessTokenClaims] as JWT access tokens,
+//! with the times as RFC 3339 strings as PASETO requires.  `v4.public` tokens
+//! are signed with an AS Ed25519 key and can be verified by any RS that has
+//! the AS public key.  `v4.local` tokens are encrypted with an AS secret, so
+//! only the AS can read them and RSs must introspect them.
+//!
+//! Either kind is read back with [decode_token], which finds the AS key by
+//! the `kid` in the footer.
+//!
+use crate::keys::crypto_error;
+use blake2::{
+    digest::{
+        consts::{U32, U56},
+        Mac,
+    },
+    Blake2bMac,
+};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    XChaCha20,
+};
+use chrono::{DateTime, Utc};
+use errors::GnapError;
+use log::error;
+use model::{
+    key::{KeyAlgorithm, SigningKey},
+    token::{AccessTokenClaims, GnapAccessToken},
+    unix_time,
+};
+use openssl::{
+    pkey::{HasPublic, PKey, PKeyRef},
+    rand::rand_bytes,
+    sign::{Signer, Verifier},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const PUBLIC_HEADER: &str = "v4.public.";
+const LOCAL_HEADER: &str = "v4.local.";
+
+/// The token footer.  Identifies the AS key used for the token.
+#[derive(Serialize, Deserialize)]
+struct Footer {
+    kid: String,
+}
+
+/// Encode an issued token as a signed `v4.public` PASETO.
+pub fn encode_public(
+    token: &GnapAccessToken,
+    issuer: &str,
+    key: &SigningKey,
+) -> Result<String, GnapError> {
+    check_alg(key, KeyAlgorithm::EdDSA)?;
+    let pkey = PKey::private_key_from_pem(key.private_key.as_bytes()).map_err(crypto_error)?;
+    let message = payload(token, issuer)?;
+    let footer = footer(key)?;
+    let m2 = pae(&[PUBLIC_HEADER.as_bytes(), &message, &footer, b""]);
+    let signature = Signer::new_without_digest(&pkey)
+        .and_then(|mut signer| signer.sign_oneshot_to_vec(&m2))
+        .map_err(crypto_error)?;
+
+    let mut body = message;
+    body.extend_from_slice(&signature);
+    Ok(assemble(PUBLIC_HEADER, &body, &footer))
+}
+
+/// Encode an issued token as an encrypted `v4.local` PASETO.
+pub fn encode_local(
+    token: &GnapAccessToken,
+    issuer: &str,
+    key: &SigningKey,
+) -> Result<String, GnapError> {
+    check_alg(key, KeyAlgorithm::V4Local)?;
+    let secret =
+        base64::decode_config(&key.private_key, base64::URL_SAFE_NO_PAD).map_err(crypto_error)?;
+    let mut nonce = [0u8; 32];
+    rand_bytes(&mut nonce).map_err(crypto_error)?;
+    encrypt(&payload(token, issuer)?, &footer(key)?, &secret, &nonce)
+}
+
+/// Is the value a version 4 PASETO?
+pub fn is_paseto(value: &str) -> bool {
+    value.starts_with(PUBLIC_HEADER) || value.starts_with(LOCAL_HEADER)
+}
+
+/// Verify or decrypt a PASETO access token issued by `issuer` with one of
+/// `keys`, and get its claims.  The token must not have expired.
+pub fn decode_token(value: &str, issuer: &str, keys: &[SigningKey]) -> Result<AccessTokenClaims, GnapError> {
+    let (header, alg) = if value.starts_with(PUBLIC_HEADER) {
+        (PUBLIC_HEADER, KeyAlgorithm::EdDSA)
+    } else if value.starts_with(LOCAL_HEADER) {
+        (LOCAL_HEADER, KeyAlgorithm::V4Local)
+    } else {
+        return Err(GnapError::CryptoError("not a v4 PASETO".to_owned()));
+    };
+    let (_, footer) = split_token(value, header)?;
+    let footer: Footer = serde_json::from_slice(&footer)?;
+    let key = keys
+        .iter()
+        .find(|key| key.kid == footer.kid && key.alg == alg)
+        .ok_or_else(|| GnapError::CryptoError("PASETO is not from a known key".to_owned()))?;
+
+    let (message, _) = match alg {
+        KeyAlgorithm::EdDSA => {
+            let public_key = key
+                .public_key
+                .as_ref()
+                .ok_or_else(|| GnapError::CryptoError(format!("{} has no public key", key.kid)))?;
+            let pkey = PKey::public_key_from_pem(public_key.as_bytes()).map_err(crypto_error)?;
+            verify(value, &pkey)?
+        }
+        _ => {
+            let secret = base64::decode_config(&key.private_key, base64::URL_SAFE_NO_PAD)
+                .map_err(crypto_error)?;
+            decrypt(value, &secret)?
+        }
+    };
+    let claims = claims(&message)?;
+    if claims.iss != issuer {
+        error!("PASETO {} was issued by {}", &claims.jti, &claims.iss);
+        return Err(GnapError::CryptoError("PASETO has the wrong issuer".to_owned()));
+    }
+    if claims.exp <= unix_time() {
+        return Err(GnapError::CryptoError("PASETO has expired".to_owned()));
+    }
+    Ok(claims)
+}
+
+/// Verify a `v4.public` token, and get its message and footer.
+fn verify<T: HasPublic>(value: &str, public_key: &PKeyRef<T>) -> Result<(Vec<u8>, Vec<u8>), GnapError> {
+    let (body, footer) = split_token(value, PUBLIC_HEADER)?;
+    if body.len() < 64 {
+        return Err(GnapError::CryptoError("PASETO is too short".to_owned()));
+    }
+    let (message, signature) = body.split_at(body.len() - 64);
+    let m2 = pae(&[PUBLIC_HEADER.as_bytes(), message, &footer, b""]);
+    let verified = Verifier::new_without_digest(public_key)
+        .and_then(|mut verifier| verifier.verify_oneshot(signature, &m2))
+        .map_err(crypto_error)?;
+    if !verified {
+        return Err(GnapError::CryptoError("PASETO signature does not verify".to_owned()));
+    }
+    Ok((message.to_vec(), footer))
+}
+
+/// Decrypt a `v4.local` token, and get its message and footer.
+fn decrypt(value: &str, secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>), GnapError> {
+    let (body, footer) = split_token(value, LOCAL_HEADER)?;
+    if body.len() < 64 {
+        return Err(GnapError::CryptoError("PASETO is too short".to_owned()));
+    }
+    let (nonce, rest) = body.split_at(32);
+    let (ciphertext, tag) = rest.split_at(rest.len() - 32);
+    let keys = split_key(secret, nonce)?;
+
+    let pre_auth = pae(&[LOCAL_HEADER.as_bytes(), nonce, ciphertext, &footer, b""]);
+    let mut mac = <Blake2bMac<U32> as Mac>::new_from_slice(&keys.auth_key).map_err(crypto_error)?;
+    mac.update(&pre_auth);
+    mac.verify_slice(tag)
+        .map_err(|_| GnapError::CryptoError("PASETO tag does not verify".to_owned()))?;
+
+    let mut message = ciphertext.to_vec();
+    XChaCha20::new(
+        keys.encryption_key[..].into(),
+        keys.counter_nonce[..].into(),
+    )
+    .apply_keystream(&mut message);
+    Ok((message, footer))
+}
+
+/// Split a token into its decoded body and footer.
+fn split_token(value: &str, header: &str) -> Result<(Vec<u8>, Vec<u8>), GnapError> {
+    let rest = value
+        .strip_prefix(header)
+        .ok_or_else(|| GnapError::CryptoError(format!("PASETO is not {}", header)))?;
+    let mut parts = rest.split('.');
+    let body = parts.next().unwrap_or_default();
+    let footer = parts.next().unwrap_or_default();
+    if parts.next().is_some() {
+        return Err(GnapError::CryptoError("PASETO has too many parts".to_owned()));
+    }
+    Ok((
+        base64::decode_config(body, base64::URL_SAFE_NO_PAD).map_err(crypto_error)?,
+        base64::decode_config(footer, base64::URL_SAFE_NO_PAD).map_err(crypto_error)?,
+    ))
+}
+
+/// Read the claims from a PASETO payload.
+fn claims(message: &[u8]) -> Result<AccessTokenClaims, GnapError> {
+    let mut payload: Value = serde_json::from_slice(message)?;
+    if let Value::Object(map) = &mut payload {
+        for claim in ["iat", "exp"] {
+            let time = map
+                .get(claim)
+                .and_then(Value::as_str)
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .ok_or_else(|| GnapError::CryptoError(format!("PASETO has no valid {}", claim)))?;
+            map.insert(claim.to_owned(), Value::from(time.timestamp().max(0) as u64));
+        }
+    }
+    Ok(serde_json::from_value(payload)?)
+}
+
+/// The token claims as a PASETO payload.
+fn payload(token: &GnapAccessToken, issuer: &str) -> Result<Vec<u8>, GnapError> {
+    let claims = AccessTokenClaims::new(token, issuer);
+    let mut payload = serde_json::to_value(&claims)?;
+    if let Value::Object(map) = &mut payload {
+        map.insert("iat".to_owned(), Value::String(rfc3339(claims.iat)));
+        map.insert("exp".to_owned(), Value::String(rfc3339(claims.exp)));
+    }
+    Ok(serde_json::to_vec(&payload)?)
+}
+
+fn footer(key: &SigningKey) -> Result<Vec<u8>, GnapError> {
+    Ok(serde_json::to_vec(&Footer {
+        kid: key.kid.clone(),
+    })?)
+}
+
+fn check_alg(key: &SigningKey, alg: KeyAlgorithm) -> Result<(), GnapError> {
+    if key.alg != alg {
+        error!("Key {} is a {} key, not {}", &key.kid, key.alg, alg);
+        return Err(GnapError::CryptoError(format!("expected a {} key", alg)));
+    }
+    Ok(())
+}
+
+fn rfc3339(secs: u64) -> String {
+    DateTime::<Utc>::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339()
+}
+
+/// `v4.local` encryption, with a given nonce.
+fn encrypt(
+    message: &[u8],
+    footer: &[u8],
+    secret: &[u8],
+    nonce: &[u8; 32],
+) -> Result<String, GnapError> {
+    let keys = split_key(secret, nonce)?;
+
+    let mut ciphertext = message.to_vec();
+    XChaCha20::new(
+        keys.encryption_key[..].into(),
+        keys.counter_nonce[..].into(),
+    )
+    .apply_keystream(&mut ciphertext);
+
+    let pre_auth = pae(&[LOCAL_HEADER.as_bytes(), nonce, &ciphertext, footer, b""]);
+    let mut mac = <Blake2bMac<U32> as Mac>::new_from_slice(&keys.auth_key).map_err(crypto_error)?;
+    mac.update(&pre_auth);
+    let tag = mac.finalize().into_bytes();
+
+    let mut body = nonce.to_vec();
+    body.extend_from_slice(&ciphertext);
+    body.extend_from_slice(&tag);
+    Ok(assemble(LOCAL_HEADER, &body, footer))
+}
+
+/// Keys derived from the AS secret for a single `v4.local` token.
+struct LocalKeys {
+    encryption_key: Vec<u8>,
+    counter_nonce: Vec<u8>,
+    auth_key: Vec<u8>,
+}
+
+/// Derive the `v4.local` keys from the secret and the token nonce.
+fn split_key(secret: &[u8], nonce: &[u8]) -> Result<LocalKeys, GnapError> {
+    let mut mac = <Blake2bMac<U56> as Mac>::new_from_slice(secret).map_err(crypto_error)?;
+    mac.update(b"paseto-encryption-key");
+    mac.update(nonce);
+    let tmp = mac.finalize().into_bytes();
+
+    let mut mac = <Blake2bMac<U32> as Mac>::new_from_slice(secret).map_err(crypto_error)?;
+    mac.update(b"paseto-auth-key-for-aead");
+    mac.update(nonce);
+    let auth_key = mac.finalize().into_bytes();
+
+    Ok(LocalKeys {
+        encryption_key: tmp[..32].to_vec(),
+        counter_nonce: tmp[32..].to_vec(),
+        auth_key: auth_key.to_vec(),
+    })
+}
+
+fn assemble(header: &str, body: &[u8], footer: &[u8]) -> String {
+    let mut token = format!(
+        "{}{}",
+        header,
+        base64::encode_config(body, base64::URL_SAFE_NO_PAD)
+    );
+    if !footer.is_empty() {
+        token.push('.');
+        token.push_str(&base64::encode_config(footer, base64::URL_SAFE_NO_PAD));
+    }
+    token
+}
+
+/// Pre-Authentication Encoding.
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    let mut output = (pieces.len() as u64).to_le_bytes().to_vec();
+    for piece in pieces.iter() {
+        output.extend_from_slice(&((piece.len() as u64) & (u64::MAX >> 1)).to_le_bytes());
+        output.extend_from_slice(piece);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::generate_key;
+    use model::grant::AccessRequest;
+    use openssl::pkey::Id;
+
+    const ISSUER: &str = "https://as.example";
+
+    #[test]
+    fn pae_encoding() {
+        assert_eq!(pae(&[]), vec![0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            pae(&[b"test"]),
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, b't', b'e', b's', b't']
+        );
+    }
+
+    #[test]
+    fn public_round_trip() {
+        let key = generate_key(KeyAlgorithm::EdDSA).unwrap();
+        let token =
+            GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        let value = encode_public(&token, ISSUER, &key).expect("encode failed");
+        assert!(value.starts_with(PUBLIC_HEADER));
+
+        let public_key =
+            PKey::public_key_from_pem(key.public_key.as_ref().unwrap().as_bytes()).unwrap();
+        let (message, footer) = verify(&value, &public_key).expect("verify failed");
+        let claims: Value = serde_json::from_slice(&message).unwrap();
+        assert_eq!(claims["jti"], Value::String(token.token_id.clone()));
+        assert_eq!(claims["exp"], Value::String(rfc3339(token.expires_at)));
+        let footer: Footer = serde_json::from_slice(&footer).unwrap();
+        assert_eq!(footer.kid, key.kid);
+
+        let claims = decode_token(&value, ISSUER, std::slice::from_ref(&key)).expect("decode failed");
+        assert_eq!(claims.jti, token.token_id);
+        assert_eq!(claims.exp, token.expires_at);
+        assert!(decode_token(&value, "https://other.example", std::slice::from_ref(&key)).is_err());
+        let other = generate_key(KeyAlgorithm::EdDSA).unwrap();
+        assert!(decode_token(&value, ISSUER, &[other]).is_err());
+    }
+
+    #[test]
+    fn local_round_trip() {
+        let key = generate_key(KeyAlgorithm::V4Local).unwrap();
+        let token =
+            GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        let value = encode_local(&token, ISSUER, &key).expect("encode failed");
+        assert!(value.starts_with(LOCAL_HEADER));
+
+        let secret = base64::decode_config(&key.private_key, base64::URL_SAFE_NO_PAD).unwrap();
+        let (message, _) = decrypt(&value, &secret).expect("decrypt failed");
+        let claims: Value = serde_json::from_slice(&message).unwrap();
+        assert_eq!(claims["jti"], Value::String(token.token_id.clone()));
+        assert_eq!(claims["access"], serde_json::json!(["foo"]));
+
+        let claims = decode_token(&value, ISSUER, std::slice::from_ref(&key)).expect("decode failed");
+        assert_eq!(claims.jti, token.token_id);
+    }
+
+    #[test]
+    fn expired_token() {
+        let key = generate_key(KeyAlgorithm::V4Local).unwrap();
+        let mut token =
+            GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        token.expires_at = token.issued_at - 1;
+        let value = encode_local(&token, ISSUER, &key).unwrap();
+        assert!(decode_token(&value, ISSUER, &[key]).is_err());
+    }
+
+    #[test]
+    fn wrong_key_type() {
+        let key = generate_key(KeyAlgorithm::ES256).unwrap();
+        let token =
+            GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        assert!(encode_public(&token, ISSUER, &key).is_err());
+        assert!(encode_local(&token, ISSUER, &key).is_err());
+    }
+
+    const LOCAL_KEY: &str = "707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f";
+    const PUBLIC_SECRET_KEY: &str = "b4cbfb43df4ce210727d953e4a713307fa19bb7d9f85041438d9e11b942a3774";
+    const PUBLIC_KEY: &str = "1eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2";
+    const SIGNED_MESSAGE: &[u8] = br#"{"data":"this is a signed message","exp":"2022-01-01T00:00:00+00:00"}"#;
+    const KID_FOOTER: &[u8] = br#"{"kid":"zVhMiPBP9fRf2snEcT7gFTioeA9COcNy9DfgL1W60haN"}"#;
+
+    // PASETO test vector 4-E-1
+    const VECTOR_4_E_1: &str = "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvSwscFlAl1pk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XJ5hOb_4v9RmDkneN0S92dx0OW4pgy7omxgf3S8c3LlQg";
+    // PASETO test vector 4-E-2
+    const VECTOR_4_E_2: &str = "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvS2csCgglvpk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XIemu9chy3WVKvRBfg6t8wwYHK0ArLxxfZP73W_vfwt5A";
+    // PASETO test vector 4-S-1
+    const VECTOR_4_S_1: &str = "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9bg_XBBzds8lTZShVlwwKSgeKpLT3yukTw6JUz3W4h_ExsQV-P0V54zemZDcAxFaSeef1QlXEFtkqxT1ciiQEDA";
+    // PASETO test vector 4-S-2
+    const VECTOR_4_S_2: &str = "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9v3Jt8mx_TdM2ceTGoqwrh4yDFn0XsHvvV_D0DtwQxVrJEBMl0F2caAdgnpKlt4p7xBnx1HcO-SPo8FPp214HDw.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9";
+
+    #[test]
+    fn local_test_vectors() {
+        let key = hex(LOCAL_KEY);
+        let (message, footer) = decrypt(VECTOR_4_E_1, &key).expect("4-E-1 does not decrypt");
+        assert_eq!(
+            message,
+            br#"{"data":"this is a secret message","exp":"2022-01-01T00:00:00+00:00"}"#
+        );
+        assert!(footer.is_empty());
+        let (message, _) = decrypt(VECTOR_4_E_2, &key).expect("4-E-2 does not decrypt");
+        assert_eq!(
+            message,
+            br#"{"data":"this is a hidden message","exp":"2022-01-01T00:00:00+00:00"}"#
+        );
+
+        let mut wrong_key = key.clone();
+        wrong_key[0] ^= 1;
+        assert!(decrypt(VECTOR_4_E_1, &wrong_key).is_err());
+        assert_eq!(
+            encrypt(&decrypt(VECTOR_4_E_1, &key).unwrap().0, b"", &key, &[0u8; 32]).unwrap(),
+            VECTOR_4_E_1
+        );
+    }
+
+    #[test]
+    fn public_test_vectors() {
+        let public_key = PKey::public_key_from_raw_bytes(&hex(PUBLIC_KEY), Id::ED25519).unwrap();
+        let (message, footer) = verify(VECTOR_4_S_1, &public_key).expect("4-S-1 does not verify");
+        assert_eq!(message, SIGNED_MESSAGE);
+        assert!(footer.is_empty());
+        let (message, footer) = verify(VECTOR_4_S_2, &public_key).expect("4-S-2 does not verify");
+        assert_eq!(message, SIGNED_MESSAGE);
+        assert_eq!(footer, KID_FOOTER);
+
+        // A token is only valid with the footer it was signed with.
+        let (body, _) = VECTOR_4_S_2.rsplit_once('.').unwrap();
+        assert!(verify(body, &public_key).is_err());
+    }
+
+    #[test]
+    fn public_signing_vector() {
+        let pkey = PKey::private_key_from_raw_bytes(&hex(PUBLIC_SECRET_KEY), Id::ED25519).unwrap();
+        let m2 = pae(&[PUBLIC_HEADER.as_bytes(), SIGNED_MESSAGE, b"", b""]);
+        let signature = Signer::new_without_digest(&pkey)
+            .unwrap()
+            .sign_oneshot_to_vec(&m2)
+            .unwrap();
+        let mut body = SIGNED_MESSAGE.to_vec();
+        body.extend_from_slice(&signature);
+        assert_eq!(assemble(PUBLIC_HEADER, &body, b""), VECTOR_4_S_1);
+    }
+
+    #[test]
+    fn local_tampered() {
+        let secret = [7u8; 32];
+        let token = encrypt(b"{}", b"", &secret, &[1u8; 32]).unwrap();
+        let mut body =
+            base64::decode_config(&token[LOCAL_HEADER.len()..], base64::URL_SAFE_NO_PAD).unwrap();
+        body[33] ^= 1;
+        assert!(decrypt(&assemble(LOCAL_HEADER, &body, b""), &secret).is_err());
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
@@ -20,6 +20,7 @@ pub fn make_request() -> GrantRequest {
         label: Some("my_label".to_owned()),
         access: vec![ac_foo, ac_ref],
         flags: Some(vec![AccessTokenFlag::Bearer]),
+        key: None,
     };
 
     let access_tokens = vec![at];
@@ -30,6 +31,7 @@ pub fn make_request() -> GrantRequest {
             method: InteractFinishMethodType::Redirect,
             uri: "localhost:3000/login".to_owned(),
             nonce: generate_nonce(),
+            hash_method: None,
         }),
     };
 
@@ -79,6 +79,9 @@ pub struct Account {
     website: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     zoneinfo: Option<String>,
+    /// Hash of the password the resource owner signs in with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
 }
 
 impl CachePath for Account {
@@ -108,6 +111,7 @@ impl From<AccountRequest> for Account {
             tax_id: ar.tax_id,
             website: ar.website,
             zoneinfo: ar.zoneinfo,
+            password_hash: None,
         }
     }
 }
@@ -116,6 +120,18 @@ impl Account {
     pub fn create_id() -> Uuid {
         Uuid::new_v4()
     }
+
+    pub fn account_id(&self) -> Uuid {
+        self.account_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn preferred_username(&self) -> Option<&str> {
+        self.preferred_username.as_deref()
+    }
 }
 
 impl ToRedisArgs for &Account {
@@ -161,6 +177,10 @@ pub struct AccountRequest {
     website: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     zoneinfo: Option<String>,
+    /// The password the resource owner signs in with.  Only its hash is
+    /// saved with the account.
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
 }
 
 impl AccountRequest {
@@ -183,6 +203,7 @@ impl AccountRequest {
             tax_id: None,
             website: None,
             zoneinfo: None,
+            password: None,
         }
     }
 }
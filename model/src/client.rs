@@ -4,6 +4,9 @@
 //! for client/service interaction.
 //!
 use crate::oauth::{AcrValueType, ApplicationType, GrantType, ResponseType, SubjectType};
+use crate::grant::{AccessRequest, ClientInstance};
+use crate::key::{ClientKey, Jwks};
+use crate::token::TokenFormat;
 use redis::{RedisWrite, ToRedisArgs};
 use jsonwebtoken::Algorithm;
 use serde::{Deserialize, Serialize};
@@ -16,6 +19,14 @@ use super::CachePath;
 pub struct GnapClientRequest {
     pub redirect_uris: Vec<String>,
     pub client_name: String,
+    #[serde(default)]
+    pub key: Option<ClientKey>,
+    #[serde(default)]
+    pub jwks: Option<Jwks>,
+    #[serde(default)]
+    pub jwks_uri: Option<String>,
+    #[serde(default)]
+    pub pre_authorized: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -37,8 +48,13 @@ pub struct GnapClient {
     pub policy_uri: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tos_uri: Option<String>,
+    /// Location of the client key set.  It is fetched when the client key
+    /// has no JWK of its own, and the key is not in `jwks`.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub jwks_uri: Option<String>,
+    /// Client keys held by the AS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks: Option<Jwks>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub logo_uri: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -77,6 +93,25 @@ pub struct GnapClient {
     pub initiate_login_uri: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub request_uris: Option<Vec<String>>,
+    /// The client instance key.  Requests from the client instance must
+    /// prove possession of it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
+    /// Format of access tokens issued to this client.  Overrides the AS
+    /// default when set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_format: Option<TokenFormat>,
+    /// The software a client instance sent by value identified itself as.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class_id: Option<String>,
+    /// Resource types and access references the AS grants the client
+    /// without asking the resource owner.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_authorized: Option<Vec<String>>,
+    /// Thumbprint of the key of a client instance sent by value.  An
+    /// instance sent again with the same key is the same client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_thumbprint: Option<String>,
 }
 
 /// Client defined by OIDC
@@ -95,6 +130,7 @@ impl GnapClient {
             policy_uri: None,
             tos_uri: None,
             jwks_uri: None,
+            jwks: None,
             logo_uri: None,
             subjec_type: None,
             sector_identifier_uri: None,
@@ -114,9 +150,56 @@ impl GnapClient {
             default_acr_values: None,
             initiate_login_uri: None,
             request_uris: None,
+            key: None,
+            token_format: None,
+            class_id: None,
+            pre_authorized: None,
+            key_thumbprint: None,
         }
     }
 
+    /// Create a client for a client instance sent by value.
+    ///
+    /// The instance has nothing registered ahead of time, so the redirect
+    /// finish URI it presents, if any, becomes its registered redirect URI.
+    /// Display URIs are shown to the resource owner as links, so only http
+    /// and https URIs are kept.
+    pub fn from_instance(instance: ClientInstance, redirect_uri: Option<&str>) -> Self {
+        let (name, uri, logo_uri) = match instance.display {
+            Some(display) => (display.name, display.uri, display.logo_uri),
+            None => (String::new(), None, None),
+        };
+        let redirect_uris = redirect_uri.map(|uri| vec![uri.to_owned()]).unwrap_or_default();
+        let mut client = Self::new(redirect_uris, name);
+        client.client_uri = uri.filter(|uri| is_web_uri(uri));
+        client.logo_uri = logo_uri.filter(|uri| is_web_uri(uri));
+        client.key_thumbprint = instance.key.thumbprint();
+        client.key = Some(instance.key);
+        client.class_id = instance.class_id;
+        client
+    }
+
+    /// Is the URI one of the client's registered redirect URIs?
+    ///
+    /// URIs are compared exactly, without any normalization.
+    pub fn is_redirect_uri(&self, uri: &str) -> bool {
+        self.redirect_uris.iter().any(|redirect_uri| redirect_uri == uri)
+    }
+
+    /// Was the client registered with the access already authorized?
+    ///
+    /// Access requested by value is matched on its resource type, and
+    /// access requested by reference on the reference.
+    pub fn is_pre_authorized(&self, access: &AccessRequest) -> bool {
+        let name = match access {
+            AccessRequest::Reference(reference) => reference,
+            AccessRequest::Value { resource_type, .. } => resource_type,
+        };
+        self.pre_authorized
+            .as_ref()
+            .is_some_and(|pre_authorized| pre_authorized.contains(name))
+    }
+
     /// Validate a request body against openid-connect-registration-1_0
     pub fn validate_request(&self) -> Result<(), GnapError> {
         Ok(())
@@ -127,6 +210,15 @@ impl GnapClient {
     }
 }
 
+/// Is the URI an http or https URI, and so safe to link to from a page?
+pub fn is_web_uri(uri: &str) -> bool {
+    let scheme = match uri.split_once("://") {
+        Some((scheme, _)) => scheme,
+        None => return false,
+    };
+    scheme.eq_ignore_ascii_case("https") || scheme.eq_ignore_ascii_case("http")
+}
+
 impl CachePath for GnapClient {
     fn cache_path() -> &'static str {
         "gnap:clients"
@@ -142,3 +234,18 @@ impl ToRedisArgs for &GnapClient {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn web_uris() {
+        assert!(is_web_uri("https://client.example.net"));
+        assert!(is_web_uri("HTTP://client.example.net/logo.png"));
+        assert!(!is_web_uri("javascript:alert(1)"));
+        assert!(!is_web_uri("javascript://%0aalert(1)"));
+        assert!(!is_web_uri(" https://client.example.net"));
+        assert!(!is_web_uri("data:image/png;base64,AAAA"));
+        assert!(!is_web_uri("client.example.net"));
+    }
+}
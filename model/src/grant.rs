@@ -5,8 +5,10 @@
 //!
 use serde::{Deserialize, Serialize};
 use serde_utils::vec_or_one::deser_one_as_vec;
+use sha2::{Digest, Sha256, Sha512};
 use uuid::Uuid;
 use super::GnapID;
+use super::key::ClientKey;
 use errors::GnapError;
 use log::trace;
 
@@ -15,7 +17,7 @@ use log::trace;
 /// attributes or behavior to be attached to the access token by the
 /// AS.  This field is OPTIONAL.
 /// Flag values MUST NOT be included more than once.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum AccessTokenFlag {
     // This flag indicates whether the token is a bearer token,
@@ -82,6 +84,10 @@ pub struct AccessTokenRequest {
     pub label: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub flags: Option<Vec<AccessTokenFlag>>,
+    /// A key to bind the token to, rather than the client instance key.
+    /// Cannot be used with the `bearer` flag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
 }
 
 impl AccessTokenRequest {
@@ -89,7 +95,8 @@ impl AccessTokenRequest {
         AccessTokenRequest {
             label: None,
             access: Vec::<AccessRequest>::new(),
-            flags: None
+            flags: None,
+            key: None,
         }
     }
 }
@@ -140,11 +147,57 @@ pub enum InteractFinishMethodType {
     Redirect,
     Push,
 }
+/// Hash algorithms for the interaction finish hash.  Section 4.2.3
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HashMethod {
+    #[default]
+    #[serde(rename = "sha-256")]
+    Sha256,
+    #[serde(rename = "sha-512")]
+    Sha512,
+}
+
+impl HashMethod {
+    /// Hash the input, base64url encoded without padding.
+    pub fn hash(&self, input: &[u8]) -> String {
+        let digest = match self {
+            HashMethod::Sha256 => Sha256::digest(input).to_vec(),
+            HashMethod::Sha512 => Sha512::digest(input).to_vec(),
+        };
+        base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InteractFinishRequest {
     pub method: InteractFinishMethodType,
     pub uri: String,
     pub nonce: String,
+    // The hash calculation method for the interaction finish hash.
+    //  Defaults to sha-256.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_method: Option<HashMethod>,
+}
+
+impl InteractFinishRequest {
+    /// Calculate the interaction finish hash.  Section 4.2.3
+    ///
+    /// The hash covers the client instance's nonce, the AS nonce, the
+    /// interaction reference and the grant endpoint the client instance
+    /// started the request at, so the client instance can tell that the
+    /// interaction finished for its own request.
+    pub fn hash(&self, finish_nonce: &str, interact_ref: &str, grant_endpoint: &str) -> String {
+        let input = format!("{}\n{}\n{}\n{}", self.nonce, finish_nonce, interact_ref, grant_endpoint);
+        self.hash_method.unwrap_or_default().hash(input.as_bytes())
+    }
+}
+
+/// The result of a finished interaction, sent to the client instance with
+/// the finish method.  Section 4.2
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InteractFinish {
+    pub hash: String,
+    pub interact_ref: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -154,10 +207,32 @@ pub struct InteractRequest {
 }
 
 
+/// Information about a client instance, for display to the resource owner.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientDisplay {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    #[serde(alias = "logo", skip_serializing_if = "Option::is_none")]
+    pub logo_uri: Option<String>,
+}
+
+/// A client instance sent by value.  Section 2.3
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientInstance {
+    /// The key the client instance proves its requests with.
+    pub key: ClientKey,
+    /// Identifies the software the client instance is an instance of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display: Option<ClientDisplay>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged, rename_all = "lowercase")]
 pub enum GnapClientInstance {
-    Value {},
+    Value(Box<ClientInstance>),
     Ref(String)
 }
 
@@ -166,7 +241,6 @@ pub struct GrantRequest {
     #[serde(deserialize_with = "deser_one_as_vec")]
     pub access_token: Vec<AccessTokenRequest>,
     pub subject: Option<SubjectRequest>,
-    // We will only support client reference identifiers for now
     pub client: Option<GnapClientInstance>,
     // We will only support user ref ids for now
     pub user: Option<String>,
@@ -175,7 +249,7 @@ pub struct GrantRequest {
 
 // The client in the request can be either by refrence - which should be
 // a client_id string, or by value, which will contain a set of info, including
-// key data.
+// key data.  A client instance sent by value has no ID until the AS stores it.
 impl GnapID for GrantRequest {
 
     fn parse_id(&self) -> Result<Uuid, GnapError> {
@@ -198,9 +272,27 @@ impl GnapID for GrantRequest {
 }
 
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The access token a client instance presents when continuing a grant.
+///
+/// Continuation access tokens are always bound to the client instance that
+/// started the grant, so they carry neither a `key` nor any `flags`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ContinuationAccessToken {
+    pub value: String,
+}
+
+impl ContinuationAccessToken {
+    pub fn new() -> Self {
+        Self {
+            value: Uuid::new_v4().to_simple().to_string(),
+        }
+    }
+}
 
+impl Default for ContinuationAccessToken {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 
@@ -228,7 +320,7 @@ pub struct RequestContinuation {
     //  client instance MUST present the continuation access token in all
     //  requests to the continuation URI as described in Section 7.2.
     #[serde(skip_serializing_if = "Option::is_none")]
-    access_token: Option<ContinuationAccessToken>
+    pub access_token: Option<ContinuationAccessToken>
 }
 impl RequestContinuation {
     pub fn as_uri(uri: &str) -> Self {
@@ -238,6 +330,14 @@ impl RequestContinuation {
             access_token: None
         }
     }
+
+    pub fn with_wait(uri: &str, wait: u32) -> Self {
+        RequestContinuation {
+            uri: uri.to_owned(),
+            wait: Some(wait),
+            access_token: None
+        }
+    }
 }
 
 
@@ -255,6 +355,7 @@ pub struct AccessToken {
     //  provided in the associated token request (Section 2.1), if
     //  present.  If the token has been split by the AS, the value of the
     //  label field is chosen by the AS and the split flag is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
 
     // The management URI for this access token.
@@ -263,6 +364,7 @@ pub struct AccessToken {
     //  AS and is separate from the RS the client instance is requesting
     //  access to.  This URI MUST NOT include the access token value and
     //  SHOULD be different for each access token issued in a request.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub manage: Option<String>,
 
     // RECOMMENDED.  A description of the
@@ -270,6 +372,7 @@ pub struct AccessToken {
     //  If included, this MUST reflect the rights associated with the
     //  issued access token.  These rights MAY vary from what was
     //  requested by the client instance.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub access: Option<Vec<AccessRequest>>,
 
     //  OPTIONAL. The number of seconds in which the
@@ -277,6 +380,7 @@ pub struct AccessToken {
     //  token past this time.  An RS MUST NOT accept an access token past
     //  this time.  Note that the access token MAY be revoked by the AS or
     //  RS at any point prior to its expiration.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub expires_in: Option<u32>,
 
     // OPTIONAL.  The key that the token is bound to,
@@ -284,25 +388,85 @@ pub struct AccessToken {
     //  MUST be an object or string in a format described in Section 7.1.
     //  The client instance MUST be able to dereference or process the key
     //  information in order to be able to sign the request.
-    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
 
     // OPTIONAL.  A set of flags that represent
     //  attributes or behaviors of the access token issued by the AS.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub flags: Option<Vec<AccessTokenFlag>>,
 }
 
+/// The access token section of a grant response.
+///
+/// A single token is returned as an object, multiple tokens as an array.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AccessTokenResponse {
+    Single(Box<AccessToken>),
+    Multiple(Vec<AccessToken>),
+}
+
+impl From<Vec<AccessToken>> for AccessTokenResponse {
+    fn from(mut tokens: Vec<AccessToken>) -> Self {
+        if tokens.len() == 1 {
+            AccessTokenResponse::Single(Box::new(tokens.remove(0)))
+        } else {
+            AccessTokenResponse::Multiple(tokens)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InteractResponse {
-    #[serde(rename = "continue")]
-    pub tx_continue: RequestContinuation,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub redirect: Option<String>
+    pub redirect: Option<String>,
 
+    // A URI for the client instance to launch an application with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+
+    // A short code for the end user to enter at a stable URI on the AS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_code: Option<UserCode>,
+
+    // The AS nonce for the interaction finish hash.  Only returned when the
+    //  client instance asked to be told when interaction finishes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish: Option<String>,
+}
+
+/// A user code, and the URI the end user enters it at.  Section 3.3.3
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserCode {
+    pub code: String,
+    pub uri: String,
+}
+
+/// The body of a continuation request.  Section 5.1
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContinueRequest {
+    // The interaction reference the client instance was sent when
+    //  interaction finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interact_ref: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GrantResponse {
     pub instance_id: String,
+
+    // The access tokens issued for the grant, one for each access token
+    //  request.  Section 3.2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<AccessTokenResponse>,
+
+    // Indicates that the client instance can continue the request by making
+    //  one or more continuation requests.  Omitted once the grant is
+    //  complete.  Section 3.1
+    #[serde(rename = "continue", skip_serializing_if = "Option::is_none")]
+    pub tx_continue: Option<RequestContinuation>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub interact: Option<InteractResponse>
 }
@@ -315,6 +479,8 @@ impl GrantResponse {
     pub fn new() -> Self {
         Self {
             instance_id: Self::create_id(),
+            access_token: None,
+            tx_continue: None,
             interact: None
         }
     }
@@ -332,16 +498,86 @@ mod tests {
         let rc = RequestContinuation::as_uri(&uri.clone());
 
         let ic = InteractResponse {
-            tx_continue: rc,
-            redirect: Some(uri)
+            redirect: Some(uri),
+            app: None,
+            user_code: None,
+            finish: None,
         };
 
         let response = GrantResponse{
             instance_id: tx_id,
+            access_token: None,
+            tx_continue: Some(rc),
             interact: Some(ic)
         };
 
         println!("{}", serde_json::to_string(&response).expect("oops"));
         assert!(true);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn client_instance_forms() {
+        let request: GrantRequest = serde_json::from_str(r#"{
+            "access_token": {"access": ["foo"]},
+            "client": {
+                "key": {"proof": "httpsig", "jwk": {"kty": "OKP", "crv": "Ed25519", "x": "abc"}},
+                "class_id": "my_app",
+                "display": {"name": "My App", "uri": "https://app.example", "logo": "https://app.example/logo.png"}
+            }
+        }"#).expect("bad request");
+        match &request.client {
+            Some(GnapClientInstance::Value(instance)) => {
+                assert_eq!(instance.class_id, Some("my_app".to_owned()));
+                let display = instance.display.as_ref().expect("no display");
+                assert_eq!(display.logo_uri, Some("https://app.example/logo.png".to_owned()));
+            }
+            _ => panic!("client is not a value"),
+        }
+        assert!(request.parse_id().is_err());
+
+        let id = Uuid::new_v4();
+        let request: GrantRequest = serde_json::from_str(&format!(
+            r#"{{"access_token": {{"access": ["foo"]}}, "client": "{}"}}"#,
+            id
+        )).expect("bad request");
+        assert_eq!(request.parse_id().unwrap(), id);
+    }
+
+    #[test]
+    fn access_token_response_form() {
+        let mut token = AccessToken {
+            value: "abc".to_owned(),
+            label: Some("a".to_owned()),
+            manage: None,
+            access: None,
+            expires_in: None,
+            key: None,
+            flags: None,
+        };
+        let single = AccessTokenResponse::from(vec![token.clone()]);
+        assert!(serde_json::to_value(&single).unwrap().is_object());
+
+        token.label = Some("b".to_owned());
+        let multiple = AccessTokenResponse::from(vec![token.clone(), token]);
+        let value = serde_json::to_value(&multiple).unwrap();
+        assert_eq!(value.as_array().map(|tokens| tokens.len()), Some(2));
+        assert_eq!(value[1]["label"], "b");
+    }
+
+    #[test]
+    fn interaction_hash() {
+        let mut finish: InteractFinishRequest = serde_json::from_str(r#"{
+            "method": "redirect",
+            "uri": "https://client.example.net/return",
+            "nonce": "VJLO6A4CAYLBXHTR0KRO"
+        }"#).expect("bad finish request");
+        assert_eq!(finish.hash_method, None);
+        let hash = finish.hash("MBDOFXG4Y5CVJCX821LH", "4IFWWIKYBC2PQ6U56NL1", "https://server.example.com/tx");
+        assert_eq!(hash, "jdHcrti02HLCwGU3qhUZ3wZXt8IjrV_BtE3oUyOuKNk");
+
+        finish.hash_method = serde_json::from_str(r#""sha-512""#).unwrap();
+        assert_eq!(finish.hash_method, Some(HashMethod::Sha512));
+        let hash = finish.hash("MBDOFXG4Y5CVJCX821LH", "4IFWWIKYBC2PQ6U56NL1", "https://server.example.com/tx");
+        assert_eq!(hash, "4Tkhb_Mm6whcNVR9B5iJ_lLWQsBb8IVvhFLFrThw226Wg2Z-ohRfUoHduC1upVJdTHt2wyoUAX4sVAkZlXpl1g");
+    }
+}
@@ -0,0 +1,37 @@
+//! Resource owner interaction models.
+//!
+//! Forms posted by the resource owner's browser on the AS interaction pages.
+//!
+use serde::{Deserialize, Serialize};
+
+/// Resource owner sign in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+}
+
+/// The resource owner's decision on a grant request.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsentDecision {
+    Approve,
+    Deny,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConsentForm {
+    pub decision: ConsentDecision,
+}
+
+/// A user code entered by the resource owner.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserCodeForm {
+    pub code: String,
+}
+
+/// The query of an app interaction URI.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppHandleQuery {
+    pub handle: String,
+}
@@ -0,0 +1,416 @@
+//! Key models
+//!
+//! Key material managed by the AS, such as the keys used to sign access
+//! tokens.
+//!
+use jsonwebtoken::Algorithm;
+use redis::{RedisWrite, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use super::token::Confirmation;
+use super::{unix_time, CachePath};
+
+/// Algorithms the AS uses its keys with.
+///
+/// These are the JOSE algorithm names, plus the PASETO v4 purposes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    RS256,
+    RS384,
+    RS512,
+    PS256,
+    PS384,
+    PS512,
+    ES256,
+    ES384,
+    /// Ed25519 signatures, used for PASETO v4.public tokens.
+    EdDSA,
+    /// A symmetric key, used for PASETO v4.local tokens.
+    #[serde(rename = "v4.local")]
+    V4Local,
+}
+
+impl KeyAlgorithm {
+    /// The matching JWT signing algorithm, if there is one.
+    pub fn jwt_algorithm(&self) -> Option<Algorithm> {
+        match self {
+            KeyAlgorithm::RS256 => Some(Algorithm::RS256),
+            KeyAlgorithm::RS384 => Some(Algorithm::RS384),
+            KeyAlgorithm::RS512 => Some(Algorithm::RS512),
+            KeyAlgorithm::PS256 => Some(Algorithm::PS256),
+            KeyAlgorithm::PS384 => Some(Algorithm::PS384),
+            KeyAlgorithm::PS512 => Some(Algorithm::PS512),
+            KeyAlgorithm::ES256 => Some(Algorithm::ES256),
+            KeyAlgorithm::ES384 => Some(Algorithm::ES384),
+            KeyAlgorithm::EdDSA | KeyAlgorithm::V4Local => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alg = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", alg.as_str().unwrap_or_default())
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_owned())).map_err(|_| ())
+    }
+}
+
+/// Methods a client instance can use to prove possession of its key.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyProofMethod {
+    Httpsig,
+    Mtls,
+    Jwsd,
+    Jws,
+}
+
+/// A public key in JSON Web Key format (RFC 7517).
+///
+/// Only the members used by RSA, EC and OKP keys are modelled.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    pub key_use: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+}
+
+impl Jwk {
+    /// The JWK SHA-256 thumbprint (RFC 7638), base64url encoded.
+    ///
+    /// The thumbprint covers only the required members for the key type, in
+    /// lexicographic order.
+    pub fn thumbprint(&self) -> String {
+        let kty = Some(self.kty.clone());
+        let members: Vec<(&str, &Option<String>)> = match self.kty.as_str() {
+            "RSA" => vec![("e", &self.e), ("kty", &kty), ("n", &self.n)],
+            "EC" => vec![("crv", &self.crv), ("kty", &kty), ("x", &self.x), ("y", &self.y)],
+            _ => vec![("crv", &self.crv), ("kty", &kty), ("x", &self.x)],
+        };
+        let members: Vec<String> = members
+            .into_iter()
+            .map(|(name, value)| {
+                let value = serde_json::Value::from(value.clone().unwrap_or_default());
+                format!("\"{}\":{}", name, value)
+            })
+            .collect();
+        let json = format!("{{{}}}", members.join(","));
+        base64::encode_config(Sha256::digest(json.as_bytes()), base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// A client instance key, and the method the client instance uses to prove
+/// it holds the key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClientKey {
+    pub proof: KeyProofMethod,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwk: Option<Jwk>,
+    /// base64 encoded DER X.509 certificate, for keys proven with mTLS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
+    /// base64url encoded SHA-256 thumbprint of the certificate used with
+    /// mTLS.
+    #[serde(rename = "cert#S256", default, skip_serializing_if = "Option::is_none")]
+    pub cert_s256: Option<String>,
+}
+
+impl ClientKey {
+    /// Confirmation claims for a token bound to the key.
+    ///
+    /// Keys proven with mTLS are confirmed by their certificate thumbprint,
+    /// other keys by their JWK thumbprint.
+    pub fn confirmation(&self) -> Option<Confirmation> {
+        if self.proof == KeyProofMethod::Mtls {
+            let x5t_s256 = match (&self.cert_s256, &self.cert) {
+                (Some(thumbprint), _) => thumbprint.clone(),
+                (None, Some(cert)) => {
+                    let der = base64::decode(cert).ok()?;
+                    base64::encode_config(Sha256::digest(&der), base64::URL_SAFE_NO_PAD)
+                }
+                (None, None) => return None,
+            };
+            return Some(Confirmation {
+                jkt: None,
+                x5t_s256: Some(x5t_s256),
+            });
+        }
+        self.jwk.as_ref().map(|jwk| Confirmation {
+            jkt: Some(jwk.thumbprint()),
+            x5t_s256: None,
+        })
+    }
+}
+
+impl ClientKey {
+    /// The thumbprint that identifies the key: its certificate thumbprint
+    /// for keys proven with mTLS, its JWK thumbprint otherwise.
+    pub fn thumbprint(&self) -> Option<String> {
+        let cnf = self.confirmation()?;
+        cnf.x5t_s256.or(cnf.jkt)
+    }
+}
+
+/// A JWK Set, as published by the AS.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+impl Jwks {
+    /// Find the key with a key ID.  Without a key ID, a set with a single
+    /// key matches that key.
+    pub fn find(&self, kid: Option<&str>) -> Option<&Jwk> {
+        match kid {
+            Some(kid) => self.keys.iter().find(|key| key.kid.as_deref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+    }
+}
+
+impl ToRedisArgs for &Jwks {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize Jwks as string"))
+    }
+}
+
+/// Where an AS signing key is in its lifecycle.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyState {
+    /// The key signs new tokens.  There is one active key per algorithm.
+    #[default]
+    Active,
+    /// The key has been replaced, but tokens signed with it may still be
+    /// valid, so it is still published.
+    Retiring,
+    /// No unexpired token was signed with the key.  It is no longer
+    /// published.
+    Retired,
+}
+
+/// An AS signing key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SigningKey {
+    /// Key ID, used in the `kid` header of anything signed with the key.
+    pub kid: String,
+    pub alg: KeyAlgorithm,
+    /// PEM encoded private key.  For symmetric keys, the base64url encoded
+    /// secret.
+    pub private_key: String,
+    /// PEM encoded public key.  Symmetric keys have none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Seconds since the epoch
+    pub created_at: u64,
+    #[serde(default)]
+    pub state: KeyState,
+    /// When a retiring key can be retired.  Seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retire_at: Option<u64>,
+}
+
+impl SigningKey {
+    pub fn create_id() -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    pub fn new(alg: KeyAlgorithm, private_key: String, public_key: Option<String>) -> Self {
+        Self {
+            kid: Self::create_id(),
+            alg,
+            private_key,
+            public_key,
+            created_at: unix_time(),
+            state: KeyState::Active,
+            retire_at: None,
+        }
+    }
+
+    /// Whether an active key has been in use for at least `max_age` seconds.
+    pub fn is_due_for_rotation(&self, max_age: u64, now: u64) -> bool {
+        self.state == KeyState::Active && now >= self.created_at + max_age
+    }
+
+    /// Stop signing with the key.  It is kept until `retire_at`, when every
+    /// token signed with it has expired.
+    pub fn start_retiring(&mut self, retire_at: u64) {
+        self.state = KeyState::Retiring;
+        self.retire_at = Some(retire_at);
+    }
+
+    /// Move a retiring key to retired, if its time has come.  Returns true if
+    /// the state changed.
+    pub fn retire_if_due(&mut self, now: u64) -> bool {
+        match (self.state, self.retire_at) {
+            (KeyState::Retiring, Some(retire_at)) if now >= retire_at => {
+                self.state = KeyState::Retired;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the key is published for verifying tokens.
+    pub fn is_published(&self) -> bool {
+        self.state != KeyState::Retired && self.public_key.is_some()
+    }
+}
+
+impl CachePath for SigningKey {
+    fn cache_path() -> &'static str {
+        "gnap:signing_keys"
+    }
+}
+
+impl ToRedisArgs for &SigningKey {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize SigningKey as string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn algorithm_names() {
+        assert_eq!(KeyAlgorithm::V4Local.to_string(), "v4.local");
+        assert_eq!(KeyAlgorithm::from_str("EdDSA"), Ok(KeyAlgorithm::EdDSA));
+        assert_eq!(KeyAlgorithm::from_str("ES256").unwrap().jwt_algorithm(), Some(Algorithm::ES256));
+        assert!(KeyAlgorithm::from_str("HS256").is_err());
+    }
+
+    #[test]
+    fn key_lifecycle() {
+        let mut key = SigningKey::new(KeyAlgorithm::ES256, "private".to_owned(), Some("public".to_owned()));
+        let created_at = key.created_at;
+        assert!(key.is_published());
+        assert!(!key.is_due_for_rotation(60, created_at + 59));
+        assert!(key.is_due_for_rotation(60, created_at + 60));
+
+        key.start_retiring(created_at + 120);
+        assert_eq!(key.state, KeyState::Retiring);
+        assert!(!key.is_due_for_rotation(60, created_at + 60));
+        assert!(key.is_published());
+        assert!(!key.retire_if_due(created_at + 119));
+        assert!(key.retire_if_due(created_at + 120));
+        assert_eq!(key.state, KeyState::Retired);
+        assert!(!key.is_published());
+        assert!(!key.retire_if_due(created_at + 121));
+
+        // Keys stored before the lifecycle was tracked are active.
+        let json = r#"{"kid": "1", "alg": "ES256", "private_key": "private", "created_at": 0}"#;
+        let key: SigningKey = serde_json::from_str(json).unwrap();
+        assert_eq!(key.state, KeyState::Active);
+        assert!(!key.is_published());
+    }
+
+    #[test]
+    fn client_key() {
+        let key: ClientKey = serde_json::from_str(
+            r#"{"proof": "httpsig", "jwk": {"kty": "OKP", "crv": "Ed25519", "x": "abc", "use": "sig"}}"#,
+        )
+        .expect("bad key");
+        assert_eq!(key.proof, KeyProofMethod::Httpsig);
+        let jwk = key.jwk.expect("no jwk");
+        assert_eq!(jwk.key_use, Some("sig".to_owned()));
+        assert!(jwk.n.is_none());
+    }
+
+    #[test]
+    fn jwk_thumbprint() {
+        // RFC 7638 section 3.1
+        let jwk: Jwk = serde_json::from_str(r#"{
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29"
+        }"#).unwrap();
+        assert_eq!(jwk.thumbprint(), "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+    }
+
+    #[test]
+    fn find_jwk() {
+        let jwks: Jwks = serde_json::from_str(
+            r#"{"keys": [{"kty": "OKP", "kid": "a", "crv": "Ed25519", "x": "abc"}, {"kty": "OKP", "kid": "b", "crv": "Ed25519", "x": "def"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(jwks.find(Some("b")).and_then(|jwk| jwk.x.as_deref()), Some("def"));
+        assert!(jwks.find(Some("c")).is_none());
+        assert!(jwks.find(None).is_none());
+
+        let jwks = Jwks {
+            keys: jwks.keys[..1].to_vec(),
+        };
+        assert_eq!(jwks.find(None).and_then(|jwk| jwk.kid.as_deref()), Some("a"));
+    }
+
+    #[test]
+    fn key_confirmation() {
+        let jwk: Jwk = serde_json::from_str(r#"{"kty": "OKP", "crv": "Ed25519", "x": "abc"}"#).unwrap();
+        let key = ClientKey {
+            proof: KeyProofMethod::Httpsig,
+            jwk: Some(jwk.clone()),
+            cert: None,
+            cert_s256: None,
+        };
+        let cnf = key.confirmation().expect("no confirmation");
+        assert_eq!(cnf.jkt, Some(jwk.thumbprint()));
+        assert!(cnf.x5t_s256.is_none());
+        assert_eq!(key.thumbprint(), Some(jwk.thumbprint()));
+
+        let key = ClientKey {
+            proof: KeyProofMethod::Mtls,
+            cert_s256: Some("abc".to_owned()),
+            ..key
+        };
+        let cnf = key.confirmation().expect("no confirmation");
+        assert_eq!(cnf.x5t_s256, Some("abc".to_owned()));
+        assert!(cnf.jkt.is_none());
+        assert_eq!(key.thumbprint(), Some("abc".to_owned()));
+    }
+
+    #[test]
+    fn mtls_client_key() {
+        let key: ClientKey = serde_json::from_str(r#"{"proof": "mtls", "cert#S256": "abc"}"#).expect("bad key");
+        assert_eq!(key.proof, KeyProofMethod::Mtls);
+        assert_eq!(key.cert_s256, Some("abc".to_owned()));
+        assert!(key.jwk.is_none());
+        let json = serde_json::to_value(&key).unwrap();
+        assert!(json.get("cert").is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 use errors::GnapError;
 pub mod transaction;
@@ -8,6 +9,9 @@ pub mod oidc;
 pub mod gnap;
 pub mod resource;
 pub mod account;
+pub mod token;
+pub mod key;
+pub mod interact;
 
 /// CachePath ensures each model type that will be cached provides a
 /// consistent path to cache objects
@@ -21,9 +25,37 @@ pub trait GnapID {
     fn parse_id(&self) -> Result<Uuid, GnapError>;
 }
 
+/// Current time in seconds since the Unix epoch.  Used for issued and
+/// expiry times on cached models.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Compare a presented secret with the expected one in time that only
+/// depends on their length, so it cannot be guessed a byte at a time.
+pub fn secret_eq(presented: &str, expected: &str) -> bool {
+    let (presented, expected) = (presented.as_bytes(), expected.as_bytes());
+    let diff = presented
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    presented.len() == expected.len() && std::hint::black_box(diff) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use uuid::Uuid;
+    #[test]
+    fn secret_comparison() {
+        assert!(super::secret_eq("abc", "abc"));
+        assert!(!super::secret_eq("abc", "abd"));
+        assert!(!super::secret_eq("abc", "abcd"));
+        assert!(!super::secret_eq("", "a"));
+    }
+
     #[test]
     fn it_works() {
         let my_uuid = Uuid::new_v4();
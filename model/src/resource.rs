@@ -9,6 +9,8 @@
 
 use serde::{Deserialize, Serialize};
 use redis::{RedisWrite, ToRedisArgs};
+use super::CachePath;
+use super::grant::AccessRequest;
 use std::str::FromStr;
 use void::Void;
 use uuid::Uuid;
@@ -63,3 +65,70 @@ impl ToRedisArgs for &ResourceRequest {
         out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize ResourceRequest as string"))
     }
 }
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ResourceServerRequest {
+    pub name: String,
+    #[serde(default)]
+    pub locations: Vec<String>,
+    #[serde(default)]
+    pub resource_types: Vec<String>,
+}
+
+/// A resource server registered with the AS.
+///
+/// RSs authenticate to the AS with their ID and secret.  The AS only keeps a
+/// hash of the secret.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResourceServer {
+    pub rs_id: Uuid,
+    pub name: String,
+    pub secret_hash: String,
+    /// The RS locations, as they appear in the `locations` of requested
+    /// access.
+    #[serde(default)]
+    pub locations: Vec<String>,
+    /// Resource types and access references served by the RS.
+    #[serde(default)]
+    pub resource_types: Vec<String>,
+}
+
+impl ResourceServer {
+    /// Is the access for this RS?
+    ///
+    /// Access that names locations is for the RS at any of them.  Otherwise
+    /// it is matched on its resource type, or its reference.
+    pub fn serves(&self, access: &AccessRequest) -> bool {
+        match access {
+            AccessRequest::Value { locations: Some(locations), .. } if !locations.is_empty() => {
+                locations.iter().any(|location| self.locations.contains(location))
+            }
+            AccessRequest::Value { resource_type, .. } => self.resource_types.contains(resource_type),
+            AccessRequest::Reference(reference) => self.resource_types.contains(reference),
+        }
+    }
+}
+
+/// The credentials handed to an RS when it is registered.  This is the only
+/// time the secret is available.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResourceServerCredentials {
+    pub rs_id: Uuid,
+    pub name: String,
+    pub secret: String,
+}
+
+impl CachePath for ResourceServer {
+    fn cache_path() -> &'static str {
+        "gnap:resource_servers"
+    }
+}
+
+impl ToRedisArgs for &ResourceServer {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize ResourceServer as string"))
+    }
+}
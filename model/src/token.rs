@@ -0,0 +1,500 @@
+//! Issued access token models.
+//!
+//! The AS keeps a record of every access token it issues, so that tokens can
+//! later be introspected and managed.
+//!
+use redis::{RedisWrite, ToRedisArgs};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::grant::{AccessRequest, AccessToken, AccessTokenFlag};
+use super::key::{ClientKey, KeyProofMethod};
+use super::{unix_time, CachePath};
+
+/// Formats the AS can issue access token values in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenFormat {
+    /// A random reference value.  RSs must introspect the token.
+    #[default]
+    Opaque,
+    /// A signed JWT carrying the token claims.
+    Jwt,
+    /// A signed PASETO v4.public token carrying the token claims.
+    Paseto,
+    /// An encrypted PASETO v4.local token.  Only the AS can read the claims,
+    /// so RSs must introspect the token.
+    #[serde(rename = "paseto_local")]
+    PasetoLocal,
+}
+
+impl std::str::FromStr for TokenFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_owned())).map_err(|_| ())
+    }
+}
+
+/// The AS record of an issued access token.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GnapAccessToken {
+    /// AS assigned identifier.  Unlike the value, this is safe to use in
+    /// URIs and cache keys.
+    pub token_id: String,
+    /// The token value handed to the client instance.
+    pub value: String,
+    #[serde(default)]
+    pub format: TokenFormat,
+    /// The transaction the token was issued for.
+    pub tx_id: String,
+    /// The client instance the token was issued to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<Uuid>,
+    /// The resource owner who approved the token.  Tokens issued without
+    /// interaction have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// The rights granted to the token.
+    pub access: Vec<AccessRequest>,
+    pub flags: Vec<AccessTokenFlag>,
+    /// Seconds since the epoch
+    pub issued_at: u64,
+    /// Seconds since the epoch
+    pub expires_at: u64,
+    /// The key of the client instance the token was issued to.  Management
+    /// requests must prove possession of it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<ClientKey>,
+    /// The key the token is bound to.  RSs must require a proof of
+    /// possession of it.  Bearer tokens are bound to no key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
+    /// Values a durable token was rotated away from.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retired_values: Vec<RetiredValue>,
+}
+
+/// A previous value of a durable token.  It stays valid until the time the
+/// value was originally issued to expire.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RetiredValue {
+    pub value: String,
+    /// Seconds since the epoch
+    pub expires_at: u64,
+}
+
+impl GnapAccessToken {
+    pub fn create_id() -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    pub fn create_value() -> String {
+        Uuid::new_v4().to_simple().to_string()
+    }
+
+    /// Create a new token with an opaque value that expires in `lifetime`
+    /// seconds.
+    pub fn new(tx_id: &str, access: Vec<AccessRequest>, lifetime: u64) -> Self {
+        let now = unix_time();
+        Self {
+            token_id: Self::create_id(),
+            value: Self::create_value(),
+            format: TokenFormat::Opaque,
+            tx_id: tx_id.to_owned(),
+            client_id: None,
+            account_id: None,
+            label: None,
+            access,
+            flags: Vec::new(),
+            issued_at: now,
+            expires_at: now + lifetime,
+            client_key: None,
+            key: None,
+            retired_values: Vec::new(),
+        }
+    }
+
+    /// Replace the token value with a new opaque value.  The token keeps its
+    /// ID, and its lifetime starts over.
+    ///
+    /// The old value of a durable token is retired, and stays valid until it
+    /// expires.  Otherwise the old value is returned, so that it can be
+    /// revoked.
+    pub fn rotate(&mut self) -> Option<String> {
+        let lifetime = self.expires_at.saturating_sub(self.issued_at);
+        let now = unix_time();
+        let old_value = std::mem::replace(&mut self.value, Self::create_value());
+        let old_expires_at = self.expires_at;
+        self.issued_at = now;
+        self.expires_at = now + lifetime;
+
+        self.retired_values.retain(|retired| retired.expires_at > now);
+        if self.has_flag(AccessTokenFlag::Durable) {
+            self.retired_values.push(RetiredValue {
+                value: old_value,
+                expires_at: old_expires_at,
+            });
+            None
+        } else {
+            Some(old_value)
+        }
+    }
+
+    /// When a value of this token expires.  `None` if the value is not, or
+    /// is no longer, a value of this token.
+    pub fn value_expires_at(&self, value: &str) -> Option<u64> {
+        let expires_at = if value == self.value {
+            self.expires_at
+        } else {
+            self.retired_values
+                .iter()
+                .find(|retired| retired.value == value)?
+                .expires_at
+        };
+        if expires_at > unix_time() {
+            Some(expires_at)
+        } else {
+            None
+        }
+    }
+
+    /// Number of seconds until the token expires.
+    pub fn expires_in(&self) -> u64 {
+        self.expires_at.saturating_sub(unix_time())
+    }
+
+    pub fn has_flag(&self, flag: AccessTokenFlag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// The token as it is presented to the client instance in a grant
+    /// response.
+    pub fn to_response(&self) -> AccessToken {
+        AccessToken {
+            value: self.value.clone(),
+            label: self.label.clone(),
+            manage: None,
+            access: Some(self.access.clone()),
+            expires_in: Some(self.expires_in() as u32),
+            // The key is only returned if it is not the client instance key.
+            key: self.key.clone().filter(|key| Some(key) != self.client_key.as_ref()),
+            flags: if self.flags.is_empty() {
+                None
+            } else {
+                Some(self.flags.clone())
+            },
+        }
+    }
+}
+
+/// Key confirmation claims, for tokens that are bound to a key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Confirmation {
+    /// JWK SHA-256 thumbprint of the bound key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jkt: Option<String>,
+    /// SHA-256 thumbprint of the bound X.509 certificate.
+    #[serde(rename = "x5t#S256", skip_serializing_if = "Option::is_none")]
+    pub x5t_s256: Option<String>,
+}
+
+/// Claims carried by self-contained access token formats, such as JWT.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub aud: Vec<String>,
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    /// The ID of the token record.
+    pub jti: String,
+    /// The rights granted to the token.
+    pub access: Vec<AccessRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<AccessTokenFlag>,
+    /// Present when the token is bound to a key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+impl AccessTokenClaims {
+    /// Build the claims for an issued token.
+    ///
+    /// The audience is the set of RS locations in the granted access.  If
+    /// the access names no locations, the token is audienced to the AS.
+    pub fn new(token: &GnapAccessToken, issuer: &str) -> Self {
+        let mut aud: Vec<String> = Vec::new();
+        for access in token.access.iter() {
+            if let AccessRequest::Value { locations: Some(locations), .. } = access {
+                for location in locations.iter() {
+                    if !aud.contains(location) {
+                        aud.push(location.clone());
+                    }
+                }
+            }
+        }
+        if aud.is_empty() {
+            aud.push(issuer.to_owned());
+        }
+
+        // The subject is the resource owner who approved the token, or the
+        // client instance when no resource owner was involved.
+        let sub = match (&token.account_id, &token.client_id) {
+            (Some(account_id), _) => account_id.to_string(),
+            (None, Some(client_id)) => client_id.to_string(),
+            (None, None) => token.tx_id.clone(),
+        };
+
+        Self {
+            iss: issuer.to_owned(),
+            aud,
+            sub,
+            iat: token.issued_at,
+            exp: token.expires_at,
+            jti: token.token_id.clone(),
+            access: token.access.clone(),
+            client_id: token.client_id,
+            flags: token.flags.clone(),
+            cnf: token.key.as_ref().and_then(|key| key.confirmation()),
+        }
+    }
+}
+
+/// The AS response to an access token management request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokenManagementResponse {
+    pub access_token: AccessToken,
+}
+
+/// A token introspection request from an RS.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IntrospectionRequest {
+    /// The token value presented to the RS.
+    pub access_token: String,
+    /// The proofing method the client instance used with the RS.  A bound
+    /// token is only reported active if it matches the bound key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proof: Option<KeyProofMethod>,
+    /// Access the RS expects the token to carry.  The token is only reported
+    /// active if it carries all of it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access: Option<Vec<AccessRequest>>,
+}
+
+/// The AS response to a token introspection request.
+///
+/// Inactive tokens are reported with no other information.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access: Option<Vec<AccessRequest>>,
+    /// The key the token is bound to.  Omitted for bearer tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Vec<AccessTokenFlag>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    /// The client instance the token was issued to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    /// The resource owner who approved the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+}
+
+impl IntrospectionResponse {
+    pub fn inactive() -> Self {
+        Self::default()
+    }
+}
+
+impl GnapAccessToken {
+    /// The token as it is reported to an RS by introspection.
+    ///
+    /// `expires_at` is the expiry of the value the RS was presented with,
+    /// which differs from the token expiry for retired values.
+    pub fn to_introspection(&self, issuer: &str, expires_at: u64) -> IntrospectionResponse {
+        IntrospectionResponse {
+            active: true,
+            iss: Some(issuer.to_owned()),
+            access: Some(self.access.clone()),
+            key: self.key.clone(),
+            flags: Some(self.flags.clone()),
+            iat: Some(self.issued_at),
+            exp: Some(expires_at),
+            instance_id: self.client_id.map(|client_id| client_id.to_string()),
+            sub: self.account_id.map(|account_id| account_id.to_string()),
+        }
+    }
+}
+
+impl CachePath for GnapAccessToken {
+    fn cache_path() -> &'static str {
+        "gnap:tokens"
+    }
+}
+
+impl ToRedisArgs for &GnapAccessToken {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize GnapAccessToken as string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_response() {
+        let access = vec![AccessRequest::Reference("foo".to_owned())];
+        let mut token = GnapAccessToken::new("tx", access.clone(), 60);
+        token.label = Some("my_label".to_owned());
+        token.flags.push(AccessTokenFlag::Bearer);
+
+        let response = token.to_response();
+        assert_eq!(response.value, token.value);
+        assert_eq!(response.label, Some("my_label".to_owned()));
+        assert_eq!(response.access, Some(access));
+        assert!(response.expires_in.unwrap() <= 60);
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["flags"], serde_json::json!(["bearer"]));
+        assert!(json.get("manage").is_none());
+    }
+
+    #[test]
+    fn token_rotation() {
+        let mut token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        token.issued_at -= 30;
+        token.expires_at -= 30;
+        let old = token.clone();
+
+        assert_eq!(token.rotate(), Some(old.value.clone()));
+        assert_eq!(token.token_id, old.token_id);
+        assert_ne!(token.value, old.value);
+        assert_eq!(token.expires_at - token.issued_at, 60);
+        assert!(token.expires_at > old.expires_at);
+        assert!(token.retired_values.is_empty());
+        assert_eq!(token.value_expires_at(&old.value), None);
+        assert_eq!(token.value_expires_at(&token.value), Some(token.expires_at));
+    }
+
+    #[test]
+    fn durable_token_rotation() {
+        let mut token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        token.flags.push(AccessTokenFlag::Durable);
+        let first = token.clone();
+
+        assert_eq!(token.rotate(), None);
+        let second = token.clone();
+        assert_eq!(token.rotate(), None);
+
+        // Old values stay valid until they were due to expire.
+        assert_eq!(token.value_expires_at(&first.value), Some(first.expires_at));
+        assert_eq!(token.value_expires_at(&second.value), Some(second.expires_at));
+        assert_eq!(token.value_expires_at("unknown"), None);
+
+        // Expired values are dropped on the next rotation.
+        for retired in token.retired_values.iter_mut() {
+            retired.expires_at = unix_time() - 1;
+        }
+        assert_eq!(token.value_expires_at(&first.value), None);
+        token.rotate();
+        assert_eq!(token.retired_values.len(), 1);
+    }
+
+    #[test]
+    fn claims_audience() {
+        let access = vec![
+            AccessRequest::Reference("foo".to_owned()),
+            AccessRequest::Value {
+                resource_type: "photos".to_owned(),
+                actions: None,
+                locations: Some(vec!["https://rs.example".to_owned()]),
+                data_types: None,
+            },
+        ];
+        let token = GnapAccessToken::new("tx", access, 60);
+        let claims = AccessTokenClaims::new(&token, "https://as.example");
+        assert_eq!(claims.aud, vec!["https://rs.example".to_owned()]);
+        assert_eq!(claims.jti, token.token_id);
+        assert_eq!(claims.sub, "tx");
+
+        let token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        let claims = AccessTokenClaims::new(&token, "https://as.example");
+        assert_eq!(claims.aud, vec!["https://as.example".to_owned()]);
+        assert!(claims.cnf.is_none());
+    }
+
+    #[test]
+    fn resource_owner_subject() {
+        let mut token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        let client_id = Uuid::new_v4();
+        token.client_id = Some(client_id);
+        let claims = AccessTokenClaims::new(&token, "https://as.example");
+        assert_eq!(claims.sub, client_id.to_string());
+        assert!(token.to_introspection("https://as.example", token.expires_at).sub.is_none());
+
+        let account_id = Uuid::new_v4();
+        token.account_id = Some(account_id);
+        let claims = AccessTokenClaims::new(&token, "https://as.example");
+        assert_eq!(claims.sub, account_id.to_string());
+        assert_eq!(claims.client_id, Some(client_id));
+        let response = token.to_introspection("https://as.example", token.expires_at);
+        assert_eq!(response.sub, Some(account_id.to_string()));
+    }
+
+    #[test]
+    fn claims_certificate_binding() {
+        let mut token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        token.key = Some(ClientKey {
+            proof: KeyProofMethod::Mtls,
+            jwk: None,
+            cert: None,
+            cert_s256: Some("thumbprint".to_owned()),
+        });
+        let claims = AccessTokenClaims::new(&token, "https://as.example");
+        let cnf = claims.cnf.expect("token not bound");
+        assert_eq!(cnf.x5t_s256, Some("thumbprint".to_owned()));
+        assert!(cnf.jkt.is_none());
+
+        // Bearer tokens are bound to no key.
+        token.key = None;
+        assert!(AccessTokenClaims::new(&token, "https://as.example").cnf.is_none());
+    }
+
+    #[test]
+    fn bound_key_response() {
+        let client_key = ClientKey {
+            proof: KeyProofMethod::Httpsig,
+            jwk: None,
+            cert: None,
+            cert_s256: None,
+        };
+        let mut token = GnapAccessToken::new("tx", vec![AccessRequest::Reference("foo".to_owned())], 60);
+        token.client_key = Some(client_key.clone());
+        token.key = Some(client_key.clone());
+        assert!(token.to_response().key.is_none());
+        assert_eq!(token.to_introspection("https://as.example", token.expires_at).key, Some(client_key.clone()));
+
+        let requested = ClientKey {
+            proof: KeyProofMethod::Jwsd,
+            ..client_key
+        };
+        token.key = Some(requested.clone());
+        assert_eq!(token.to_response().key, Some(requested));
+    }
+}
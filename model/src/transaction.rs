@@ -4,9 +4,11 @@
 //!
 use serde::{Serialize, Deserialize};
 use redis::{RedisWrite, ToRedisArgs};
-use super::CachePath;
+use super::{secret_eq, unix_time, CachePath};
 use uuid::Uuid;
-use super::grant::GrantRequest;
+use super::GnapID;
+use super::grant::{ContinuationAccessToken, GrantRequest, InteractFinish, InteractFinishRequest};
+use super::key::ClientKey;
 
 //#[allow(proc_macro_derive_resolution_fallback)]
 
@@ -120,7 +122,61 @@ pub enum GnapTransactionState {
     Received,
     ClientVerified,
     ResourceOwnerVerified,
+    Approved,
+    Denied,
+    Finalized,
+}
 
+/// The resource owner interaction for a transaction.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GnapInteraction {
+    /// Identifies the interaction in the URIs the resource owner visits.
+    pub id: String,
+    /// The AS nonce for the interaction finish hash.  Only set when the
+    /// client instance asked to be told when interaction finishes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_nonce: Option<String>,
+    /// The interaction reference sent to the client instance when
+    /// interaction finishes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interact_ref: Option<String>,
+    /// The user code the resource owner can enter to find the interaction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_code: Option<String>,
+    /// The resource owner account, once the resource owner has signed in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<Uuid>,
+    /// Hash of the session secret held by the resource owner's browser.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// Set when the interaction URI was not handed to the client instance.
+    /// The interaction pages can then only be opened by the browser that
+    /// redeemed an app URI or user code for the interaction.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bind_browser: bool,
+    /// Hash of the secret held by the browser the interaction is bound to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub browser: Option<String>,
+    /// The outcome of pushing the interaction finish to the client instance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub push: Option<PushOutcome>,
+}
+
+/// The outcome of pushing the interaction finish to the client instance.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PushOutcome {
+    /// Number of deliveries attempted.
+    pub attempts: u32,
+    /// Did the client instance accept the interaction finish?
+    pub delivered: bool,
+    /// HTTP status of the last attempt, if the client instance responded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Why the last attempt failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// When the last attempt was made.
+    pub finished_at: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -128,6 +184,24 @@ pub struct GnapTransaction {
     pub tx_id: String,
     pub state: GnapTransactionState,
     pub request: Option<GrantRequest>,
+    /// The client instance that started the transaction.  Continuation
+    /// requests must come from the same instance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<Uuid>,
+    /// The current continuation access token.  This is replaced on every
+    /// successful continuation request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuation_token: Option<ContinuationAccessToken>,
+    /// When the client instance was last handed a continuation, in seconds
+    /// since the epoch.
+    #[serde(default)]
+    pub continued_at: u64,
+    /// The key of the client instance that started the transaction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<ClientKey>,
+    /// The resource owner interaction, if the transaction needs one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interaction: Option<GnapInteraction>,
 }
 
 impl GnapTransaction {
@@ -136,12 +210,110 @@ impl GnapTransaction {
     }
 
     pub fn new(request: Option<GrantRequest>) -> Self {
+        let client_id = request.as_ref().and_then(|r| r.parse_id().ok());
         Self{
             tx_id: Self::create_id(),
             state: GnapTransactionState::Received,
-            request: request
+            request: request,
+            client_id,
+            continuation_token: Some(ContinuationAccessToken::new()),
+            continued_at: unix_time(),
+            key: None,
+            interaction: None,
+        }
+    }
+
+    /// The client instance identifier returned to the client instance.
+    pub fn instance_id(&self) -> String {
+        match &self.client_id {
+            Some(client_id) => client_id.to_string(),
+            None => self.tx_id.clone(),
+        }
+    }
+
+    /// Check a presented continuation access token against the transaction.
+    pub fn is_continuation_token(&self, value: &str) -> bool {
+        match &self.continuation_token {
+            Some(token) => secret_eq(value, &token.value),
+            None => false,
         }
     }
+
+    /// Replace the continuation access token with a new one.
+    pub fn rotate_continuation_token(&mut self) {
+        self.continuation_token = Some(ContinuationAccessToken::new());
+        self.continued_at = unix_time();
+    }
+
+    /// Has the client instance come back before waiting `wait` seconds
+    /// since it was handed its continuation?
+    pub fn is_too_fast(&self, wait: u32) -> bool {
+        unix_time() < self.continued_at + u64::from(wait)
+    }
+
+    /// Start the resource owner interaction.
+    ///
+    /// An AS nonce is generated if the client instance sent a finish method.
+    pub fn start_interaction(&mut self) -> &GnapInteraction {
+        let finish_nonce = self.finish_request().map(|_| Self::create_nonce());
+        self.interaction.insert(GnapInteraction {
+            id: Self::create_nonce(),
+            finish_nonce,
+            ..Default::default()
+        })
+    }
+
+    /// Can the resource owner still approve or deny the transaction?
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            self.state,
+            GnapTransactionState::Start
+                | GnapTransactionState::Received
+                | GnapTransactionState::ClientVerified
+                | GnapTransactionState::ResourceOwnerVerified
+        )
+    }
+
+    /// Finish the resource owner interaction.
+    ///
+    /// If the client instance sent a finish method, an interaction reference
+    /// is generated, and returned with the interaction finish hash for
+    /// `grant_endpoint`.  The client instance must then present the
+    /// interaction reference to continue the transaction.
+    pub fn finish_interaction(&mut self, grant_endpoint: &str) -> Option<InteractFinish> {
+        let finish = self.finish_request()?.clone();
+        let interaction = self.interaction.as_mut()?;
+        let finish_nonce = interaction.finish_nonce.as_ref()?;
+        let interact_ref = Self::create_nonce();
+        let hash = finish.hash(finish_nonce, &interact_ref, grant_endpoint);
+        interaction.interact_ref = Some(interact_ref.clone());
+        Some(InteractFinish { hash, interact_ref })
+    }
+
+    /// Check the interaction reference presented with a continuation
+    /// request.
+    ///
+    /// Once interaction has finished with a finish method, the matching
+    /// reference is required.  Otherwise, none may be presented.
+    pub fn is_interact_ref(&self, value: Option<&str>) -> bool {
+        let interact_ref = self
+            .interaction
+            .as_ref()
+            .and_then(|interaction| interaction.interact_ref.as_deref());
+        interact_ref == value
+    }
+
+    /// How the client instance asked to be told that interaction finished.
+    pub fn finish_request(&self) -> Option<&InteractFinishRequest> {
+        self.request
+            .as_ref()
+            .and_then(|request| request.interact.as_ref())
+            .and_then(|interact| interact.finish.as_ref())
+    }
+
+    fn create_nonce() -> String {
+        Uuid::new_v4().to_simple().to_string()
+    }
 }
 
 impl CachePath for GnapTransaction {
@@ -158,3 +330,64 @@ impl CachePath for GnapTransaction {
         out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize GnapTransaction as string"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuation_token_rotation() {
+        let mut tx = GnapTransaction::new(None);
+        let first = tx.continuation_token.clone().expect("no continuation token");
+        assert!(tx.is_continuation_token(&first.value));
+
+        tx.rotate_continuation_token();
+        assert!(!tx.is_continuation_token(&first.value));
+        let second = tx.continuation_token.clone().expect("no continuation token");
+        assert!(tx.is_continuation_token(&second.value));
+    }
+
+    #[test]
+    fn continuation_wait() {
+        let mut tx = GnapTransaction::new(None);
+        assert!(tx.is_too_fast(5));
+        assert!(!tx.is_too_fast(0));
+
+        tx.continued_at = unix_time() - 5;
+        assert!(!tx.is_too_fast(5));
+        tx.rotate_continuation_token();
+        assert!(tx.is_too_fast(5));
+    }
+
+    #[test]
+    fn interaction_finish() {
+        let request: GrantRequest = serde_json::from_str(r#"{
+            "access_token": {"access": ["foo"]},
+            "interact": {
+                "start": ["redirect"],
+                "finish": {"method": "redirect", "uri": "https://client.example.net/return", "nonce": "abc"}
+            }
+        }"#).expect("bad request");
+        let mut tx = GnapTransaction::new(Some(request.clone()));
+        assert!(tx.finish_interaction("https://as.example/gnap/tx").is_none());
+
+        let interaction = tx.start_interaction();
+        assert!(!interaction.id.is_empty());
+        let finish_nonce = interaction.finish_nonce.clone().expect("no finish nonce");
+        assert!(tx.is_interact_ref(None));
+
+        let finish = tx.finish_interaction("https://as.example/gnap/tx").expect("no finish");
+        let expected = request.interact.unwrap().finish.unwrap()
+            .hash(&finish_nonce, &finish.interact_ref, "https://as.example/gnap/tx");
+        assert_eq!(finish.hash, expected);
+        assert!(tx.is_interact_ref(Some(&finish.interact_ref)));
+        assert!(!tx.is_interact_ref(None));
+        assert!(!tx.is_interact_ref(Some("other")));
+
+        // Without a finish method, there is nothing to hash.
+        let mut tx = GnapTransaction::new(None);
+        assert!(tx.start_interaction().finish_nonce.is_none());
+        assert!(tx.finish_interaction("https://as.example/gnap/tx").is_none());
+        assert!(tx.is_interact_ref(None));
+    }
+}
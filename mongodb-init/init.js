@@ -88,6 +88,8 @@ const accounts = [
         profile: 'https://johnswebsite.com',
         website: 'http://example.com',
         zoneinfo: 'Europe/Berlin',
+        // The password is `password`
+        password_hash: 'pbkdf2-sha256$100000$ZTvZt-ScBBrTzZagZg3ddQ$nF6cuvsCQDSKZajHLssy6x4FeurbaEuE5J3NPxpLRxk',
     },
 ]
 
@@ -95,7 +97,17 @@ conn = new Mongo();
 db = conn.getDB("gnap");
 db.service_config.insert(config);
 db.clients.insertMany(clients);
+// Client instances sent by value are saved once per key.
+db.clients.createIndex(
+    { key_thumbprint: 1 },
+    { unique: true, partialFilterExpression: { key_thumbprint: { $exists: true } } }
+);
 db.accounts.insertMany(accounts);
+// Resource owners sign in with their preferred username.
+db.accounts.createIndex(
+    { preferred_username: 1 },
+    { unique: true, partialFilterExpression: { preferred_username: { $exists: true } } }
+);
 
 
 